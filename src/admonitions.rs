@@ -0,0 +1,389 @@
+//! Rendering of the admonitions (GitHub alerts, `> **Note**` callouts and mdbook-admonish
+//! blocks), which the HTML-oriented preprocessors leave as blockquotes or code in the EPUB.
+
+use mdbook::book::{Book, BookItem};
+use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+use mdbook::utils::new_cmark_parser;
+use pulldown_cmark::{CodeBlockKind, Event, Tag, TagEnd};
+
+use crate::epub::escape_xml;
+
+/// A kind of admonition, styled by the `admonition-{name}` class.
+struct Kind {
+    name: &'static str,
+    /// Shown before the title
+    icon: char,
+    /// Border and icon color
+    color: &'static str,
+    /// The names the kind is written with (mdbook-admonish aliases)
+    directives: &'static [&'static str],
+}
+
+const KINDS: &[Kind] = &[
+    Kind {
+        name: "note",
+        icon: '✎',
+        color: "#448aff",
+        directives: &["note"],
+    },
+    Kind {
+        name: "abstract",
+        icon: '☰',
+        color: "#00b0ff",
+        directives: &["abstract", "summary", "tldr"],
+    },
+    Kind {
+        name: "info",
+        icon: 'ℹ',
+        color: "#00b8d4",
+        directives: &["info", "todo"],
+    },
+    Kind {
+        name: "tip",
+        icon: '★',
+        color: "#00bfa5",
+        directives: &["tip", "hint"],
+    },
+    Kind {
+        name: "important",
+        icon: '❗',
+        color: "#7c4dff",
+        directives: &["important"],
+    },
+    Kind {
+        name: "success",
+        icon: '✔',
+        color: "#00c853",
+        directives: &["success", "check", "done"],
+    },
+    Kind {
+        name: "question",
+        icon: '?',
+        color: "#64dd17",
+        directives: &["question", "help", "faq"],
+    },
+    Kind {
+        name: "warning",
+        icon: '⚠',
+        color: "#ff9100",
+        directives: &["warning", "attention"],
+    },
+    Kind {
+        name: "caution",
+        icon: '⚠',
+        color: "#ff5252",
+        directives: &["caution"],
+    },
+    Kind {
+        name: "failure",
+        icon: '✘',
+        color: "#ff5252",
+        directives: &["failure", "fail", "missing"],
+    },
+    Kind {
+        name: "danger",
+        icon: '⚡',
+        color: "#ff1744",
+        directives: &["danger", "error"],
+    },
+    Kind {
+        name: "bug",
+        icon: '✱',
+        color: "#f50057",
+        directives: &["bug"],
+    },
+    Kind {
+        name: "example",
+        icon: '▸',
+        color: "#7c4dff",
+        directives: &["example"],
+    },
+    Kind {
+        name: "quote",
+        icon: '❝',
+        color: "#9e9e9e",
+        directives: &["quote", "cite"],
+    },
+];
+
+/// Returns the kind written `directive`, whatever its case.
+fn kind(directive: &str) -> Option<&'static Kind> {
+    let directive = directive.to_lowercase();
+    KINDS
+        .iter()
+        .find(|kind| kind.directives.contains(&directive.as_str()))
+}
+
+/// The stylesheet of the admonitions. It only uses classes, so that it still applies
+/// once the `aside`s are downgraded to `div`s for EPUB2 readers.
+pub(crate) fn css() -> String {
+    let mut css = String::from(
+        ".admonition { margin: 1em 0; padding: 0 0.8em; border-left: 0.25em solid #448aff; }\n\
+         .admonition-title { font-weight: bold; }\n",
+    );
+    for kind in KINDS {
+        css.push_str(&format!(
+            ".admonition-{name} {{ border-left-color: {color}; }}\n\
+             .admonition-{name} > .admonition-title::before {{ content: \"{icon} \"; color: {color}; }}\n",
+            name = kind.name,
+            color = kind.color,
+            icon = kind.icon,
+        ));
+    }
+    css
+}
+
+/// mdbook preprocessor rendering the admonitions of the chapters of the EPUB to `aside`s.
+pub(crate) struct AdmonitionPreprocessor;
+
+impl Preprocessor for AdmonitionPreprocessor {
+    fn name(&self) -> &str {
+        "mdbookshelf-admonitions"
+    }
+
+    fn run(&self, _ctx: &PreprocessorContext, mut book: Book) -> mdbook::errors::Result<Book> {
+        book.for_each_mut(|item| {
+            if let BookItem::Chapter(chapter) = item {
+                chapter.content = replace_admonitions(&chapter.content);
+            }
+        });
+        Ok(book)
+    }
+
+    fn supports_renderer(&self, renderer: &str) -> bool {
+        renderer == "epub"
+    }
+}
+
+/// Replaces the admonition blockquotes and mdbook-admonish blocks of `content` by `aside`s,
+/// their Markdown body being kept (and its own admonitions replaced).
+fn replace_admonitions(content: &str) -> String {
+    let mut output = String::with_capacity(content.len());
+    let mut copied = 0;
+    let mut block: Option<(&'static Kind, Option<String>, String)> = None;
+    for (event, range) in new_cmark_parser(content, false).into_offset_iter() {
+        if range.start < copied {
+            continue;
+        }
+        let replacement = match event {
+            Event::Start(Tag::BlockQuote) => blockquote(&content[range.clone()]),
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                block = admonish(&info).map(|(kind, title)| (kind, title, String::new()));
+                None
+            }
+            Event::Text(text) => {
+                if let Some((_, _, body)) = &mut block {
+                    body.push_str(&text);
+                }
+                None
+            }
+            Event::End(TagEnd::CodeBlock) => block
+                .take()
+                .map(|(kind, title, body)| (kind, title, body.trim_end().to_owned())),
+            _ => None,
+        };
+        let Some((kind, title, body)) = replacement else {
+            continue;
+        };
+        // Container markers (`>`, but not list markers) must prefix every line
+        let line_start = content[..range.start].rfind('\n').map_or(0, |i| i + 1);
+        let prefix: String = content[line_start..range.start]
+            .chars()
+            .map(|c| if c == '>' { c } else { ' ' })
+            .collect();
+        output.push_str(&content[copied..range.start]);
+        output.push_str(&aside(
+            kind,
+            title.as_deref(),
+            &replace_admonitions(&body),
+            &prefix,
+        ));
+        if content[..range.end].ends_with('\n') {
+            output.push('\n');
+        }
+        copied = range.end;
+    }
+    output.push_str(&content[copied..]);
+    output
+}
+
+/// Parses the admonition `blockquote`, returning its kind, title and body.
+fn blockquote(blockquote: &str) -> Option<(&'static Kind, Option<String>, String)> {
+    let mut lines = blockquote.trim_end().lines().map(|line| {
+        let line = line.trim_start();
+        match line.strip_prefix('>') {
+            Some(line) => line.strip_prefix(' ').unwrap_or(line),
+            // Lazy continuation line
+            None => line,
+        }
+    });
+    let first = lines.next()?.trim();
+    let (directive, rest) = if let Some(alert) = first.strip_prefix("[!") {
+        // GitHub alert, alone on its line
+        (alert.strip_suffix(']')?, "")
+    } else {
+        let callout = first.strip_prefix("**")?;
+        let end = callout.find("**")?;
+        let rest = callout[end + 2..].trim_start();
+        (
+            callout[..end].trim_end_matches(':'),
+            rest.strip_prefix(':').unwrap_or(rest).trim_start(),
+        )
+    };
+    let kind = kind(directive)?;
+    let body: Vec<&str> = std::iter::once(rest)
+        .filter(|rest| !rest.is_empty())
+        .chain(lines)
+        .collect();
+    Some((kind, Some(capitalize(directive)), body.join("\n")))
+}
+
+/// Parses the info string of a mdbook-admonish block, returning its kind and title,
+/// if any. Unknown directives are notes titled after them, like mdbook-admonish does.
+fn admonish(info: &str) -> Option<(&'static Kind, Option<String>)> {
+    let rest = info.strip_prefix("admonish")?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let mut directive = None;
+    let mut title = None;
+    for token in tokens(rest) {
+        if let Some(value) = token.strip_prefix("title=") {
+            title = Some(unquote(value));
+        } else if token.starts_with('"') {
+            title = Some(unquote(&token));
+        } else if !token.contains('=') && directive.is_none() {
+            directive = Some(token);
+        }
+    }
+    let directive = directive.unwrap_or_else(|| String::from("note"));
+    let kind = kind(&directive).unwrap_or(&KINDS[0]);
+    let title = title.unwrap_or_else(|| capitalize(&directive));
+    Some((kind, Some(title).filter(|title| !title.is_empty())))
+}
+
+/// Splits `info` on the whitespace outside of double quotes.
+fn tokens(info: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quoted = false;
+    for c in info.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                token.push(c);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.strip_prefix('"').unwrap_or(value);
+    value.strip_suffix('"').unwrap_or(value).to_owned()
+}
+
+fn capitalize(directive: &str) -> String {
+    let mut chars = directive.chars();
+    match chars.next() {
+        Some(first) => first
+            .to_uppercase()
+            .chain(chars.flat_map(char::to_lowercase))
+            .collect(),
+        None => String::new(),
+    }
+}
+
+/// Renders the admonition as an `aside` HTML block wrapping its Markdown `body`,
+/// every line but the first one starting with `prefix`.
+fn aside(kind: &Kind, title: Option<&str>, body: &str, prefix: &str) -> String {
+    let blank = prefix.trim_end();
+    let mut aside = format!(r#"<aside class="admonition admonition-{}">"#, kind.name);
+    aside.push('\n');
+    if let Some(title) = title {
+        aside.push_str(&format!(
+            "{prefix}<p class=\"admonition-title\">{}</p>\n",
+            escape_xml(title)
+        ));
+    }
+    // Blank lines around the body, so that it is parsed as Markdown
+    aside.push_str(blank);
+    aside.push('\n');
+    for line in body.lines() {
+        aside.push_str(if line.trim().is_empty() {
+            blank
+        } else {
+            prefix
+        });
+        aside.push_str(if line.trim().is_empty() { "" } else { line });
+        aside.push('\n');
+    }
+    aside.push_str(blank);
+    aside.push('\n');
+    aside.push_str(prefix);
+    aside.push_str("</aside>");
+    aside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blockquote() {
+        let (kind, title, body) = blockquote("> [!WARNING]\n> Be careful\n>\n> - here").unwrap();
+        assert_eq!(kind.name, "warning");
+        assert_eq!(title.unwrap(), "Warning");
+        assert_eq!(body, "Be careful\n\n- here");
+
+        let (kind, title, body) = blockquote("> **Note**: read\nthis").unwrap();
+        assert_eq!(kind.name, "note");
+        assert_eq!(title.unwrap(), "Note");
+        assert_eq!(body, "read\nthis");
+
+        assert!(blockquote("> **Bold** text").is_none());
+        assert!(blockquote("> [!link]: https://example.org").is_none());
+    }
+
+    #[test]
+    fn test_admonish() {
+        let (kind, title) = admonish("admonish").unwrap();
+        assert_eq!((kind.name, title.unwrap().as_str()), ("note", "Note"));
+        let (kind, title) = admonish(r#"admonish hint "Use the force""#).unwrap();
+        assert_eq!(
+            (kind.name, title.unwrap().as_str()),
+            ("tip", "Use the force")
+        );
+        let (kind, title) = admonish(r#"admonish error title="" collapsible=true"#).unwrap();
+        assert_eq!((kind.name, title), ("danger", None));
+        assert!(admonish("admonishment").is_none());
+    }
+
+    #[test]
+    fn test_replace_admonitions() {
+        let content = concat!(
+            "> [!TIP]\n> A *tip*\n\nText\n\n",
+            "- item\n\n  ```admonish warning\n  Careful\n\n  > **Note**\n  > nested\n  ```\n\n",
+            "> Just a quote\n"
+        );
+        assert_eq!(
+            replace_admonitions(content),
+            concat!(
+                "<aside class=\"admonition admonition-tip\">\n<p class=\"admonition-title\">Tip</p>\n\nA *tip*\n\n</aside>\n\nText\n\n",
+                "- item\n\n  <aside class=\"admonition admonition-warning\">\n  <p class=\"admonition-title\">Warning</p>\n\n  Careful\n\n",
+                "  <aside class=\"admonition admonition-note\">\n  <p class=\"admonition-title\">Note</p>\n\n  nested\n\n  </aside>\n\n  </aside>\n\n",
+                "> Just a quote\n"
+            )
+        );
+        assert!(css().contains(".admonition-warning > .admonition-title::before"));
+    }
+}
@@ -1,10 +1,165 @@
 use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use mdbook::config::BookConfig;
+use mdbook::renderer::HtmlHandlebars;
 use mdbook::MDBook;
+use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 
 #[cfg(test)]
 use mockall::automock;
 
+use crate::admonitions::AdmonitionPreprocessor;
+use crate::checksum::{digest_file, Digests};
+use crate::colophon::{self, Colophon, ColophonPreprocessor};
+use crate::cover::{self, Cover, CoverImage};
+use crate::cross_links::CrossLinks;
+use crate::epub::{dc_element, Epub};
+use crate::export::{self, Format};
+use crate::fallbacks::{Fallbacks, FallbacksPreprocessor};
+use crate::highlight::{Highlight, HighlightPreprocessor};
+use crate::math::{MathOutput, MathPreprocessor};
+use crate::profile::{self, Profile};
+use crate::remote_images::{self, Localization};
+use crate::{fb2, images, links, single_file, thumbnail};
+use crate::{ImageFailure, ImageOptimization, LinkReport, Thumbnail};
+
+/// The series a book belongs to.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Series {
+    /// The series title
+    pub title: String,
+    /// The position of the book in the series
+    pub index: f64,
+}
+
+/// CSS injected in the generated EPUB.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Stylesheet {
+    /// The combined shelf and book CSS
+    pub css: String,
+    /// Replace the default stylesheet instead of extending it
+    pub replace: bool,
+}
+
+impl Stylesheet {
+    /// Inserts `css` before the CSS of `stylesheet`, creating it if needed, so that
+    /// the shelf and book CSS take precedence over it.
+    pub(crate) fn prepend(stylesheet: &mut Option<Stylesheet>, css: &str) {
+        stylesheet
+            .get_or_insert_with(Stylesheet::default)
+            .css
+            .insert_str(0, css);
+    }
+}
+
+/// Shelf-side settings applied while generating a book.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct BookOptions {
+    /// Environment variables set while loading the mdbook config.
+    pub env_var: Vec<(String, Option<String>)>,
+    /// Dublin Core elements (name, value) added to the EPUB metadata.
+    pub metadata: Vec<(String, String)>,
+    /// The EPUB filename. If not set, keeps the one chosen by mdbook-epub.
+    pub output_filename: Option<PathBuf>,
+    /// Also compute the BLAKE3 digest of the EPUB.
+    pub blake3: bool,
+    /// The EPUB unique identifier. If not set, keeps the one chosen by mdbook-epub.
+    pub identifier: Option<String>,
+    /// Build time (seconds since epoch) used to make the EPUB reproducible.
+    pub source_date: Option<i64>,
+    /// The series the book belongs to.
+    pub series: Option<Series>,
+    /// The colophon appended as the last chapter.
+    pub colophon: Option<Colophon>,
+    /// The cover embedded in the EPUB.
+    pub cover: Option<Cover>,
+    /// CSS added to the default stylesheet.
+    pub stylesheet: Option<Stylesheet>,
+    /// Larger images are downscaled to fit (width, height).
+    pub max_image_size: Option<(u32, u32)>,
+    /// Recompress the PNG and JPEG images, keeping the smaller version.
+    pub optimize_images: bool,
+    /// Quality of the re-encoded JPEG images. If not set, they are kept as is.
+    pub jpeg_quality: Option<u8>,
+    /// Rasterize the SVG images to PNG.
+    pub rasterize_svg: bool,
+    /// Remove the embedded fonts.
+    pub remove_fonts: bool,
+    /// Replace HTML5 elements by `div`s, for EPUB2 readers.
+    pub downgrade_html5: bool,
+    /// Formats the EPUB is exported to.
+    pub extra_formats: Vec<Format>,
+    /// Widths of the cover thumbnails written next to the EPUB.
+    pub thumbnail_widths: Vec<u32>,
+    /// Cover used for the thumbnails of the books which don't have one.
+    pub thumbnail_cover: Option<Cover>,
+    /// Validate the generated EPUB.
+    pub validate: bool,
+    /// Report the broken links and missing assets of the book.
+    pub link_report: bool,
+    /// Rewrite the links to other books of the shelf.
+    pub cross_links: Option<CrossLinks>,
+    /// Download the remote images and embed them in the EPUB.
+    pub remote_images: Option<Localization>,
+    /// Render the admonitions to `aside`s.
+    pub admonitions: bool,
+    /// Replace the HTML-only constructs by EPUB-safe markup.
+    pub fallbacks: Option<Fallbacks>,
+    /// Highlight the code blocks.
+    pub highlight: Option<Highlight>,
+    /// Render the TeX formulas.
+    pub math: Option<MathOutput>,
+}
+
+impl BookOptions {
+    /// Applies the settings of an e-reader `profile`.
+    pub(crate) fn apply_profile(&mut self, profile: &Profile) {
+        self.env_var.push((
+            String::from("MDBOOK_OUTPUT__EPUB__EPUB_VERSION"),
+            Some(profile.epub_version.to_string()),
+        ));
+        if !profile.css.is_empty() {
+            Stylesheet::prepend(&mut self.stylesheet, profile.css);
+        }
+        self.max_image_size = profile.max_image_size;
+        self.jpeg_quality = Some(profile.jpeg_quality);
+        self.rasterize_svg |= self.optimize_images && profile.rasterize_svg;
+        if profile.epub_version < 3 && self.math.is_some() {
+            self.math = Some(MathOutput::Text);
+        }
+        self.remove_fonts = !profile.embed_fonts;
+        self.downgrade_html5 = !profile.html5;
+    }
+}
+
+/// Information about a generated EPUB.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct BookOutput {
+    /// The book title
+    pub title: Option<String>,
+    /// The book authors
+    pub authors: Vec<String>,
+    /// The book language
+    pub language: Option<String>,
+    /// The book description
+    pub description: Option<String>,
+    /// The path to the generated EPUB, relative to the destination directory
+    pub path: PathBuf,
+    /// The size of the EPUB in bytes
+    pub epub_size: u64,
+    /// The digests of the EPUB
+    pub digests: Digests,
+    /// The cover thumbnails
+    pub thumbnails: Vec<Thumbnail>,
+    /// The broken links and missing assets report, if enabled
+    pub link_report: Option<LinkReport>,
+    /// The remote images which couldn't be embedded
+    pub image_failures: Vec<ImageFailure>,
+    /// The EPUB sizes before and after the image optimization, if enabled
+    pub image_optimization: Option<ImageOptimization>,
+}
+
 pub(crate) struct BookOp;
 
 #[cfg_attr(test, automock)]
@@ -22,48 +177,930 @@ pub(crate) struct Book;
 
 #[cfg_attr(test, automock)]
 impl Book {
-    /// Generate an EPUB from `path` to `dest`. Also modify manifest `entry` accordingly.
+    /// Generate an EPUB from `path` to `dest`, applying `options`.
     pub(crate) fn generate_epub(
         path: &Path,
-        env_var: Vec<(String, Option<String>)>,
+        options: &BookOptions,
         dest: &Path,
-    ) -> Result<(Option<String>, PathBuf, u64)> {
+    ) -> Result<BookOutput> {
         // TODO: multi thread gereration
-        // Env vars are global states, keep them only when loading mdbook config.
-        let md = temp_env::with_vars(env_var, || BookOp::load(path))
-            .map_err(|e| anyhow!("Could not load mdbook: {}", e))?;
+        let md = load(path, options)?;
+        let report = options.link_report.then(|| links::check(&md));
+
+        let output_path = match &options.output_filename {
+            Some(filename) => filename.to_owned(),
+            None => mdbook_epub::output_filename(Path::new(""), &md.config),
+        };
+
+        // mdbook-epub names the file after the title, which other books may share:
+        // generate into a directory of this book only, then move the EPUB into `dest`
+        std::fs::create_dir_all(dest)?;
+        let build_dir = tempfile::TempDir::new_in(dest)?;
+        // Titles with path separators lead mdbook-epub to write into sub directories
+        let built_file = mdbook_epub::output_filename(build_dir.path(), &md.config);
+        if let Some(parent) = built_file.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
 
-        if let Err(e) = BookOp::epub_generate(&md, dest) {
+        if let Err(e) = BookOp::epub_generate(&md, build_dir.path()) {
             log::warn!("epub_generate fail: {:?}", e);
         }
 
-        let output_file = mdbook_epub::output_filename(dest, &md.config);
+        let output_file = dest.join(&output_path);
+        if let Some(parent) = output_file.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(&built_file, &output_file)?;
         log::info!("Generated epub into {}", output_file.display());
 
+        let cover = options
+            .cover
+            .as_ref()
+            .map(|cover| cover_image(cover, &md.config.book))
+            .transpose()?;
+        let size_before = std::fs::metadata(&output_file)?.len();
+        let image_failures = post_process(&output_file, options, cover)?;
+
         let metadata = std::fs::metadata(&output_file)?;
         let epub_size = metadata.len();
-        let output_path = mdbook_epub::output_filename(Path::new(""), &md.config);
-        let title = md.config.book.title;
+        let image_optimization = (options.optimize_images || options.rasterize_svg).then(|| {
+            log::info!(
+                "Optimized the images of {}: {} to {} bytes",
+                output_file.display(),
+                size_before,
+                epub_size
+            );
+            ImageOptimization {
+                size_before,
+                size_after: epub_size,
+            }
+        });
+        let digests = digest_file(&output_file, options.blake3)?;
+
+        let thumbnails = if options.thumbnail_widths.is_empty() {
+            Vec::new()
+        } else {
+            let image = match Epub::open(&output_file)?.cover()? {
+                Some(image) => Some(image.to_vec()),
+                None => options
+                    .thumbnail_cover
+                    .as_ref()
+                    .map(|cover| cover_image(cover, &md.config.book))
+                    .transpose()?
+                    .map(|cover| cover.data),
+            };
+            match image {
+                Some(image) => thumbnail::write_thumbnails(
+                    &image,
+                    &options.thumbnail_widths,
+                    dest,
+                    &output_path,
+                )?,
+                None => {
+                    log::debug!("No cover to make thumbnails of {}", output_path.display());
+                    Vec::new()
+                }
+            }
+        };
+        for &format in &options.extra_formats {
+            if format.is_rendered_with_epub() {
+                let output_file = dest.join(format.output_path(&output_path));
+                render_format(&md, options, format, &output_file)?;
+                log::info!("Generated {} into {}", format.name(), output_file.display());
+            }
+        }
+        let link_report = report
+            .map(|report| links::write_report(&report, dest, &output_path))
+            .transpose()?;
+        let book = md.config.book;
+
+        Ok(BookOutput {
+            title: book.title,
+            authors: book.authors,
+            language: book.language,
+            description: book.description,
+            path: output_path,
+            epub_size,
+            digests,
+            thumbnails,
+            link_report,
+            image_failures,
+            image_optimization,
+        })
+    }
+
+    /// Renders the book at `path` with the mdbook HTML renderer, applying `options`,
+    /// and zips the site to `output_file`.
+    pub(crate) fn generate_html_zip(
+        path: &Path,
+        options: &BookOptions,
+        output_file: &Path,
+    ) -> Result<()> {
+        let mut md = load(path, options)?;
+        let build_dir = tempfile::TempDir::new()?;
+        md.config.build.build_dir = build_dir.path().to_path_buf();
+        md.execute_build_process(&HtmlHandlebars::new())?;
+
+        export::zip_dir(&md.build_dir_for("html"), output_file, options.source_date)?;
+        log::info!("Generated html site into {}", output_file.display());
+        Ok(())
+    }
+}
+
+/// Loads the mdbook at `path`, applying `options`.
+fn load(path: &Path, options: &BookOptions) -> Result<MDBook> {
+    // Env vars are global states, keep them only when loading mdbook config.
+    let load = |env_var: Vec<(String, Option<String>)>| {
+        temp_env::with_vars(env_var, || BookOp::load(path))
+            .map_err(|e| anyhow!("Could not load mdbook: {}", e))
+    };
+    let mut md = load(options.env_var.clone())?;
+    if options.admonitions && md.config.get("preprocessor.admonish").is_some() {
+        // Keep mdbook-admonish for the HTML site, the EPUB gets the in-process rendering
+        let mut env_var = options.env_var.clone();
+        env_var.push((
+            String::from("MDBOOK_PREPROCESSOR__ADMONISH__RENDERERS"),
+            Some(String::from(r#"["html"]"#)),
+        ));
+        md = load(env_var)?;
+    }
+
+    if let Some(colophon) = &options.colophon {
+        let build_time = options
+            .source_date
+            .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+            .unwrap_or_else(Utc::now);
+        md.with_preprocessor(ColophonPreprocessor {
+            content: colophon.render(build_time)?,
+        });
+    }
+    // Before the highlighting, which replaces the code blocks the admonitions and
+    // the playground notes are found in, and the formulas must not be looked for in
+    if options.admonitions {
+        md.with_preprocessor(AdmonitionPreprocessor);
+    }
+    if let Some(fallbacks) = &options.fallbacks {
+        md.with_preprocessor(FallbacksPreprocessor {
+            fallbacks: fallbacks.clone(),
+        });
+    }
+    if let Some(output) = options.math {
+        md.with_preprocessor(MathPreprocessor { output });
+    }
+    if let Some(highlight) = &options.highlight {
+        md.with_preprocessor(HighlightPreprocessor {
+            highlight: highlight.clone(),
+        });
+    }
+    Ok(md)
+}
+
+/// Renders `format` from the loaded book `md` to `output_file`.
+fn render_format(
+    md: &MDBook,
+    options: &BookOptions,
+    format: Format,
+    output_file: &Path,
+) -> Result<()> {
+    let fb2 = || {
+        let date = options
+            .source_date
+            .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+            .unwrap_or_else(Utc::now);
+        fb2::render(md, options.identifier.as_deref(), date)
+    };
+    match format {
+        Format::Html => {
+            let css = options
+                .stylesheet
+                .as_ref()
+                .map(|stylesheet| stylesheet.css.as_str());
+            std::fs::write(output_file, single_file::html(md, css)?)?
+        }
+        Format::Markdown => std::fs::write(output_file, single_file::markdown(md)?)?,
+        Format::Fb2 => std::fs::write(output_file, fb2()?)?,
+        Format::Fb2Zip => {
+            // The archive holds the `.fb2` file
+            let name = output_file
+                .with_extension("")
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            export::zip_files(
+                vec![(name, fb2()?.into_bytes())],
+                output_file,
+                options.source_date,
+            )?
+        }
+        Format::Kepub | Format::HtmlZip => {}
+    }
+    Ok(())
+}
+
+/// Loads or renders the cover image of `book`.
+fn cover_image(cover: &Cover, book: &BookConfig) -> Result<CoverImage> {
+    match cover {
+        Cover::Image(path) => cover::load(path),
+        Cover::Generated {
+            template,
+            shelf,
+            accent_color,
+        } => cover::generate(
+            template,
+            book.title.as_deref().unwrap_or_default(),
+            &book.authors,
+            shelf,
+            accent_color,
+        ),
+    }
+}
+
+/// Applies the changes to the generated EPUB which mdbook-epub doesn't support.
+fn post_process(
+    output_file: &Path,
+    options: &BookOptions,
+    cover: Option<CoverImage>,
+) -> Result<Vec<ImageFailure>> {
+    if options.metadata.is_empty()
+        && cover.is_none()
+        && options.identifier.is_none()
+        && options.source_date.is_none()
+        && options.series.is_none()
+        && options.stylesheet.is_none()
+        && options.max_image_size.is_none()
+        && !options.remove_fonts
+        && !options.downgrade_html5
+        && options.cross_links.is_none()
+        && options.remote_images.is_none()
+        && !options.optimize_images
+        && !options.rasterize_svg
+        && options.math != Some(MathOutput::MathMl)
+        && !options.colophon.as_ref().is_some_and(Colophon::has_qr_code)
+    {
+        return Ok(Vec::new());
+    }
+
+    let mut epub = Epub::open(output_file)?;
+    if !options.metadata.is_empty() {
+        let elements: String = options
+            .metadata
+            .iter()
+            .map(|(name, value)| dc_element(name, value))
+            .collect();
+        epub.append_metadata(&elements)?;
+    }
+    if let Some(identifier) = &options.identifier {
+        epub.set_identifier(identifier)?;
+    }
+    if let Some(series) = &options.series {
+        epub.set_series(&series.title, series.index)?;
+    }
+    if let Some(stylesheet) = &options.stylesheet {
+        epub.set_stylesheet(&stylesheet.css, stylesheet.replace)?;
+    }
+    // Keep the cover shipped with the book, unless explicitly overridden
+    let keep_cover = !matches!(options.cover, Some(Cover::Image(_))) && epub.has_cover()?;
+    if let Some(cover) = cover.filter(|_| !keep_cover) {
+        epub.set_cover(&cover.file_name, cover.media_type, cover.data)?;
+    }
+    if options.remove_fonts {
+        epub.remove_fonts()?;
+    }
+    if options.math == Some(MathOutput::MathMl) {
+        epub.add_property("math", "mathml")?;
+    }
+    if let Some(colophon) = options
+        .colophon
+        .as_ref()
+        .filter(|colophon| colophon.has_qr_code())
+    {
+        if epub.is_epub3()? {
+            epub.add_property("svg", "svg")?;
+        } else {
+            add_qr_code_image(&mut epub, colophon)?;
+        }
+    }
+    if options.downgrade_html5 {
+        for name in epub.content_documents()? {
+            let xhtml = profile::downgrade_html5(epub.get_str(&name)?);
+            epub.set(&name, xhtml.into_bytes());
+        }
+    }
+    if let Some(cross_links) = &options.cross_links {
+        let package_path = epub.package_path()?;
+        let package_dir = package_path
+            .rfind('/')
+            .map_or("", |end| &package_path[..=end]);
+        for name in epub.content_documents()? {
+            let document = name.strip_prefix(package_dir).unwrap_or(&name);
+            let xhtml = cross_links.rewrite(epub.get_str(&name)?, document);
+            epub.set(&name, xhtml.into_bytes());
+        }
+    }
+    let image_failures = match &options.remote_images {
+        Some(localization) => remote_images::localize(&mut epub, localization)?,
+        None => Vec::new(),
+    };
+    if options.rasterize_svg {
+        let names: Vec<String> = epub
+            .names()
+            .filter(|name| images::is_svg(name))
+            .map(str::to_owned)
+            .collect();
+        for name in names {
+            match cover::rasterize(epub.get(&name).unwrap_or_default()) {
+                Ok(png) => {
+                    log::debug!("Rasterized {}", name);
+                    epub.replace_image(&name, "png", "image/png", png)?;
+                }
+                Err(e) => log::warn!("Could not rasterize {}: {:#}", name, e),
+            }
+        }
+    }
+    for (name, data) in epub.entries_mut() {
+        if !images::is_raster_image(name) {
+            continue;
+        }
+        if let Some(max_size) = options.max_image_size {
+            match images::fit(data, max_size) {
+                Ok(Some(resized)) => {
+                    log::debug!(
+                        "Downscaled {} to fit in {}x{}",
+                        name,
+                        max_size.0,
+                        max_size.1
+                    );
+                    *data = resized;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    log::warn!("Could not downscale {}: {:#}", name, e);
+                    continue;
+                }
+            }
+        }
+        if options.optimize_images {
+            match images::recompress(data, options.jpeg_quality) {
+                Ok(Some(recompressed)) => {
+                    log::debug!(
+                        "Recompressed {} from {} to {} bytes",
+                        name,
+                        data.len(),
+                        recompressed.len()
+                    );
+                    *data = recompressed;
+                }
+                Ok(None) => {}
+                Err(e) => log::warn!("Could not recompress {}: {:#}", name, e),
+            }
+        }
+    }
+    if let Some(timestamp) = options.source_date {
+        epub.set_modified(timestamp)?;
+        epub.normalize(timestamp);
+    }
+    epub.save(output_file)?;
+    Ok(image_failures)
+}
 
-        Ok((title, output_path, epub_size))
+/// Replaces the inline SVG QR code of the colophon by a PNG image, for EPUB 2.
+fn add_qr_code_image(epub: &mut Epub, colophon: &Colophon) -> Result<()> {
+    let package_path = epub.package_path()?;
+    let package_dir = package_path
+        .rfind('/')
+        .map_or("", |end| &package_path[..=end]);
+    let mut found = false;
+    for name in epub.content_documents()? {
+        let document = name.strip_prefix(package_dir).unwrap_or(&name);
+        let src = format!(
+            "{}{}",
+            "../".repeat(document.matches('/').count()),
+            colophon::QR_CODE_IMAGE
+        );
+        if let Some(xhtml) = colophon.replace_qr_code(epub.get_str(&name)?, &src)? {
+            epub.set(&name, xhtml.into_bytes());
+            found = true;
+        }
+    }
+    if found {
+        let png = colophon.qr_code_png()?;
+        epub.add_item(
+            "colophon-qr-code",
+            colophon::QR_CODE_IMAGE,
+            "image/png",
+            None,
+            png,
+        )?;
     }
+    Ok(())
+}
+
+/// Reads the title from the `book.toml` in `path`, applying `env_var` like mdbook does.
+pub(crate) fn read_title(path: &Path, env_var: &[(String, Option<String>)]) -> Option<String> {
+    temp_env::with_vars(env_var, || {
+        let mut config = mdbook::Config::from_disk(path.join("book.toml")).ok()?;
+        config.update_from_env();
+        config.book.title
+    })
+}
+
+/// Generates the EPUB of the book at `path` with `options` into a temp directory,
+/// returning the directory, the output and the opened EPUB.
+#[cfg(test)]
+fn generate_test_epub(path: &Path, options: &BookOptions) -> (tempfile::TempDir, BookOutput, Epub) {
+    let dest = tempfile::TempDir::new().unwrap();
+    let output = Book::generate_epub(path, options, dest.path()).unwrap();
+    let epub = Epub::open(&dest.path().join(&output.path)).unwrap();
+    (dest, output, epub)
+}
+
+/// Generates the EPUB of the `tests/dummy` book, see [`generate_test_epub`].
+#[cfg(test)]
+fn generate_dummy_epub(options: &BookOptions) -> (tempfile::TempDir, BookOutput, Epub) {
+    generate_test_epub(&Path::new("tests").join("dummy"), options)
+}
+
+/// Writes a book titled "Hello Rust" whose single chapter is `chapter`.
+#[cfg(test)]
+fn test_book(chapter: &str) -> tempfile::TempDir {
+    let book = tempfile::TempDir::new().unwrap();
+    let src = book.path().join("src");
+    std::fs::create_dir(&src).unwrap();
+    std::fs::write(
+        book.path().join("book.toml"),
+        "[book]\ntitle = \"Hello Rust\"\n",
+    )
+    .unwrap();
+    std::fs::write(
+        src.join("SUMMARY.md"),
+        "# Summary\n\n- [Chapter 1](./chapter_1.md)\n",
+    )
+    .unwrap();
+    std::fs::write(src.join("chapter_1.md"), chapter).unwrap();
+    book
+}
+
+/// The texts (or `content` attributes, for empty elements) of the elements of the
+/// package document starting with `start_tag`.
+#[cfg(test)]
+fn package_values(epub: &Epub, start_tag: &str) -> Vec<String> {
+    let package = epub.get_str(&epub.package_path().unwrap()).unwrap();
+    package
+        .match_indices(start_tag)
+        .map(|(start, _)| {
+            let element = &package[start..];
+            let end = element.find('>').unwrap();
+            if element[..end].ends_with('/') {
+                crate::epub::attribute(element, "content")
+                    .unwrap_or_default()
+                    .to_owned()
+            } else {
+                let text = &element[end + 1..];
+                text[..text.find("</").unwrap()].to_owned()
+            }
+        })
+        .collect()
 }
 
 #[test]
 fn test_generate_epub() {
-    use std::path::Path;
-
     let path = Path::new("tests").join("dummy");
     let dest = Path::new("tests").join("book");
 
-    let (title, path, size) =
-        Book::generate_epub(path.as_path(), Vec::new(), dest.as_path()).unwrap();
+    let output =
+        Book::generate_epub(path.as_path(), &BookOptions::default(), dest.as_path()).unwrap();
 
-    assert!(size > 0, "Epub size should be bigger than 0");
-    assert_eq!(title.unwrap(), "Hello Rust", "Title doesn't match");
+    assert!(output.epub_size > 0, "Epub size should be bigger than 0");
+    assert_eq!(output.title.unwrap(), "Hello Rust", "Title doesn't match");
     assert_eq!(
-        path,
+        output.path,
         Path::new("Hello Rust.epub"),
         "Manifest entry path should be filled"
     );
 }
+
+#[test]
+fn test_generate_epub_with_metadata() {
+    let options = BookOptions {
+        env_var: vec![(
+            String::from("MDBOOK_BOOK__AUTHORS"),
+            Some(String::from(r#"["Ferris"]"#)),
+        )],
+        metadata: vec![(String::from("publisher"), String::from("Rustaceans"))],
+        ..Default::default()
+    };
+
+    let (_dest, output, epub) = generate_dummy_epub(&options);
+
+    assert_eq!(output.authors, vec![String::from("Ferris")]);
+    assert_eq!(package_values(&epub, "<dc:publisher"), vec!["Rustaceans"]);
+    assert_eq!(package_values(&epub, "<dc:creator"), vec!["Ferris"]);
+}
+
+#[test]
+fn test_generate_epub_with_output_filename() {
+    let options = BookOptions {
+        env_var: vec![(
+            String::from("MDBOOK_BOOK__TITLE"),
+            Some(String::from("Hello/Rust")),
+        )],
+        output_filename: Some(PathBuf::from("Hello_Rust.epub")),
+        ..Default::default()
+    };
+
+    let (dest, output, _) = generate_dummy_epub(&options);
+
+    assert_eq!(output.path, Path::new("Hello_Rust.epub"));
+    assert!(dest.path().join("Hello_Rust.epub").is_file());
+    assert!(!dest.path().join("Hello").exists());
+}
+
+#[test]
+fn test_generate_epub_with_same_title() {
+    let path = Path::new("tests").join("dummy");
+    let dest = tempfile::TempDir::new().unwrap();
+    let options = BookOptions {
+        output_filename: Some(PathBuf::from("Hello Rust.epub")),
+        ..Default::default()
+    };
+    let first = Book::generate_epub(&path, &options, dest.path()).unwrap();
+
+    // mdbook-epub names the other book "Hello Rust.epub" too
+    let options = BookOptions {
+        output_filename: Some(PathBuf::from("Hello Rust-x.epub")),
+        metadata: vec![(String::from("publisher"), String::from("Rustaceans"))],
+        ..Default::default()
+    };
+    Book::generate_epub(&path, &options, dest.path()).unwrap();
+
+    let digests = digest_file(&dest.path().join("Hello Rust.epub"), false).unwrap();
+    assert_eq!(digests, first.digests);
+    let mut files: Vec<_> = std::fs::read_dir(dest.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    files.sort();
+    assert_eq!(files, ["Hello Rust-x.epub", "Hello Rust.epub"]);
+}
+
+#[test]
+fn test_generate_epub_reproducible() {
+    let options = BookOptions {
+        identifier: Some(String::from(
+            "urn:uuid:d5a3b2f4-52d7-5bd0-8a1f-f2fb2a5e7a9a",
+        )),
+        source_date: Some(1_700_000_000),
+        ..Default::default()
+    };
+
+    let (dest1, output1, epub) = generate_dummy_epub(&options);
+    let (_dest2, output2, _) = generate_dummy_epub(&options);
+
+    assert_eq!(output1.digests, output2.digests);
+    // Both builds may run in the same second: check the entries don't get the build time
+    let file = std::fs::File::open(dest1.path().join(&output1.path)).unwrap();
+    let mut archive = zip::ZipArchive::new(file).unwrap();
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i).unwrap();
+        assert_eq!(
+            entry.last_modified(),
+            Some(crate::epub::zip_datetime(1_700_000_000)),
+            "{}",
+            entry.name()
+        );
+    }
+    assert_eq!(
+        package_values(&epub, "<dc:identifier"),
+        vec!["urn:uuid:d5a3b2f4-52d7-5bd0-8a1f-f2fb2a5e7a9a"]
+    );
+    assert_eq!(
+        package_values(&epub, "<meta property=\"dcterms:modified\""),
+        vec!["2023-11-14T22:13:20Z"]
+    );
+}
+
+#[test]
+fn test_generate_epub_with_series() {
+    let options = BookOptions {
+        series: Some(Series {
+            title: String::from("Rust Shelf"),
+            index: 2.0,
+        }),
+        ..Default::default()
+    };
+
+    let (_dest, _, epub) = generate_dummy_epub(&options);
+
+    assert_eq!(
+        package_values(&epub, "<meta property=\"belongs-to-collection\""),
+        vec!["Rust Shelf"]
+    );
+    assert_eq!(
+        package_values(
+            &epub,
+            "<meta refines=\"#mdbookshelf-collection\" property=\"group-position\""
+        ),
+        vec!["2"]
+    );
+    assert_eq!(
+        package_values(&epub, "<meta name=\"calibre:series\""),
+        vec!["Rust Shelf"]
+    );
+    assert_eq!(
+        package_values(&epub, "<meta name=\"calibre:series_index\""),
+        vec!["2"]
+    );
+}
+
+#[test]
+fn test_generate_epub_with_colophon() {
+    let options = BookOptions {
+        colophon: Some(Colophon {
+            template: String::from("# Colophon\n\nCommit {{ commit_sha }}"),
+            commit_sha: String::from("0f0e2b5c6d"),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let (_dest, _, epub) = generate_dummy_epub(&options);
+
+    let chapters = epub.content_documents().unwrap();
+    let last = epub.get_str(chapters.last().unwrap()).unwrap();
+    assert!(last.contains("Commit 0f0e2b5c6d"));
+}
+
+#[test]
+fn test_generate_epub_with_colophon_qr_code() {
+    let mut options = BookOptions {
+        colophon: Some(Colophon {
+            template: String::from("# Colophon\n\n<div>{{ qr_code }}</div>"),
+            qr_code: true,
+            url: String::from("https://example.com/dummy/"),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let (_dest, _, epub) = generate_dummy_epub(&options);
+    let package = epub.get_str(&epub.package_path().unwrap()).unwrap();
+    assert_eq!(package.matches(r#"properties="svg""#).count(), 1);
+
+    // Inline SVG is not allowed in EPUB 2
+    options.env_var.push((
+        String::from("MDBOOK_OUTPUT__EPUB__EPUB_VERSION"),
+        Some(String::from("2")),
+    ));
+    let (_dest, _, epub) = generate_dummy_epub(&options);
+    let chapters = epub.content_documents().unwrap();
+    let last = epub.get_str(chapters.last().unwrap()).unwrap();
+    assert!(!last.contains("<svg"));
+    assert!(last.contains(r#"<img src="colophon-qr-code.png""#));
+    assert!(epub.get("OEBPS/colophon-qr-code.png").is_some());
+}
+
+#[test]
+fn test_generate_epub_with_cover() {
+    let options = BookOptions {
+        cover: Some(Cover::Generated {
+            template: String::from(
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="12" height="18"><title>{{ title }}</title></svg>"#,
+            ),
+            shelf: String::from("My shelf"),
+            accent_color: String::from(cover::DEFAULT_ACCENT_COLOR),
+        }),
+        ..Default::default()
+    };
+
+    let (_dest, _, epub) = generate_dummy_epub(&options);
+
+    assert!(epub.has_cover().unwrap());
+    let png = epub.get("OEBPS/cover.png").unwrap();
+    assert!(png.starts_with(b"\x89PNG"));
+}
+
+#[test]
+fn test_generate_epub_with_thumbnails() {
+    let options = BookOptions {
+        thumbnail_widths: vec![8],
+        thumbnail_cover: Some(Cover::Generated {
+            template: String::from(
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="12" height="18"/>"#,
+            ),
+            shelf: String::from("My shelf"),
+            accent_color: String::from(cover::DEFAULT_ACCENT_COLOR),
+        }),
+        ..Default::default()
+    };
+
+    let (dest, output, epub) = generate_dummy_epub(&options);
+
+    assert_eq!(
+        output.thumbnails,
+        vec![Thumbnail {
+            path: PathBuf::from("Hello Rust-8.png"),
+            width: 8,
+            height: 12,
+        }]
+    );
+    assert!(dest.path().join("Hello Rust-8.png").is_file());
+    // The fallback cover is only used for thumbnails
+    assert!(!epub.has_cover().unwrap());
+}
+
+#[test]
+fn test_generate_epub_with_stylesheet() {
+    let options = BookOptions {
+        stylesheet: Some(Stylesheet {
+            css: String::from("pre { white-space: pre-wrap; }\n"),
+            replace: false,
+        }),
+        ..Default::default()
+    };
+
+    let (_dest, _, epub) = generate_dummy_epub(&options);
+
+    let stylesheet = epub
+        .get_str("OEBPS/stylesheet.css")
+        .expect("mdbook-epub writes its default stylesheet");
+    assert!(stylesheet.ends_with("pre { white-space: pre-wrap; }\n"));
+}
+
+#[test]
+fn test_generate_epub_with_profile() {
+    let mut options = BookOptions::default();
+    options.apply_profile(profile::find("epub2-compat").unwrap());
+
+    let (_dest, _, epub) = generate_dummy_epub(&options);
+
+    let package = epub.get_str(&epub.package_path().unwrap()).unwrap();
+    let version = crate::epub::attribute(&package[package.find("<package").unwrap()..], "version");
+    assert_eq!(version, Some("2.0"));
+    let stylesheet = epub.get_str("OEBPS/stylesheet.css").unwrap();
+    assert!(stylesheet.contains("pre { white-space: pre-wrap; }"));
+    assert!(options.downgrade_html5);
+    assert_eq!(options.max_image_size, Some((1200, 1600)));
+}
+
+#[test]
+fn test_generate_epub_with_image_optimization() {
+    let mut options = BookOptions {
+        optimize_images: true,
+        ..Default::default()
+    };
+    options.apply_profile(profile::find("kindle").unwrap());
+    assert!(options.rasterize_svg);
+    assert_eq!(options.jpeg_quality, Some(80));
+
+    let (_dest, output, _) = generate_dummy_epub(&options);
+
+    let optimization = output.image_optimization.unwrap();
+    assert!(optimization.size_before > 0);
+    assert_eq!(optimization.size_after, output.epub_size);
+}
+
+#[test]
+fn test_post_process_skips_broken_images() {
+    let (dest, output, mut epub) = generate_dummy_epub(&BookOptions::default());
+    let broken = b"\x89PNG\r\n\x1a\nbroken".to_vec();
+    epub.add_item("broken", "broken.png", "image/png", None, broken.clone())
+        .unwrap();
+    let epub_file = dest.path().join(&output.path);
+    epub.save(&epub_file).unwrap();
+    let options = BookOptions {
+        max_image_size: Some((100, 100)),
+        optimize_images: true,
+        ..Default::default()
+    };
+
+    post_process(&epub_file, &options, None).unwrap();
+
+    let epub = Epub::open(&epub_file).unwrap();
+    assert_eq!(epub.get("OEBPS/broken.png"), Some(broken.as_slice()));
+}
+
+#[test]
+fn test_generate_html_zip() {
+    let path = Path::new("tests").join("dummy");
+    let dest = tempfile::TempDir::new().unwrap();
+    let output_file = dest.path().join("Hello Rust.html.zip");
+
+    Book::generate_html_zip(path.as_path(), &BookOptions::default(), &output_file).unwrap();
+
+    let archive = zip::ZipArchive::new(std::fs::File::open(&output_file).unwrap()).unwrap();
+    let names: Vec<&str> = archive.file_names().collect();
+    assert!(names.contains(&"index.html"));
+    assert!(!path.join("book").join("index.html").exists());
+}
+
+#[test]
+fn test_generate_html_zip_without_highlight() {
+    let book = test_book("# Chapter 1\n\n```sh\necho hello\n```\n");
+    let dest = tempfile::TempDir::new().unwrap();
+    let output_file = dest.path().join("Hello Rust.html.zip");
+    let options = BookOptions {
+        highlight: Some(Highlight {
+            theme: String::from(crate::highlight::DEFAULT_THEME),
+            classes: true,
+        }),
+        ..Default::default()
+    };
+
+    Book::generate_html_zip(book.path(), &options, &output_file).unwrap();
+
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(&output_file).unwrap()).unwrap();
+    let mut chapter = String::new();
+    std::io::Read::read_to_string(
+        &mut archive.by_name("chapter_1.html").unwrap(),
+        &mut chapter,
+    )
+    .unwrap();
+    assert!(chapter.contains(r#"<code class="language-sh">"#));
+    assert!(!chapter.contains("hl-code"));
+}
+
+#[test]
+fn test_generate_epub_with_single_file_formats() {
+    let options = BookOptions {
+        extra_formats: vec![Format::Html, Format::Markdown],
+        ..Default::default()
+    };
+
+    let (dest, output, _) = generate_dummy_epub(&options);
+
+    let markdown =
+        std::fs::read_to_string(dest.path().join(output.path.with_extension("md"))).unwrap();
+    assert_eq!(markdown, "# Chapter 1\n\nHello Rust\n");
+    let html =
+        std::fs::read_to_string(dest.path().join(output.path.with_extension("html"))).unwrap();
+    assert!(html.contains("<section id=\"chapter_1\">\n<h1>Chapter 1</h1>"));
+    assert!(html.contains("<p>Hello Rust</p>"));
+}
+
+#[test]
+fn test_generate_epub_with_fb2() {
+    let options = BookOptions {
+        extra_formats: vec![Format::Fb2, Format::Fb2Zip],
+        ..Default::default()
+    };
+
+    let (dest, output, _) = generate_dummy_epub(&options);
+
+    let fb2 = std::fs::read_to_string(dest.path().join(output.path.with_extension("fb2"))).unwrap();
+    assert!(
+        fb2.contains("<section>\n<title><p>Chapter 1</p></title>\n<p>Hello Rust</p>\n</section>")
+    );
+    let zip_file = std::fs::File::open(dest.path().join(output.path.with_extension("fb2.zip")));
+    let mut archive = zip::ZipArchive::new(zip_file.unwrap()).unwrap();
+    let fb2_name = output.path.with_extension("fb2");
+    let entry = archive.by_name(&fb2_name.to_string_lossy()).unwrap();
+    assert_eq!(entry.size(), fb2.len() as u64);
+}
+
+#[test]
+fn test_generate_epub_with_cross_links_is_valid() {
+    use crate::cross_links::{LinkTarget, ShelfBook};
+
+    let book =
+        test_book("# Chapter 1\n\nSee [the other book](https://example.org/other/intro.html).\n");
+    let options = BookOptions {
+        cross_links: Some(CrossLinks {
+            url: String::from("https://example.org/hello/"),
+            target: LinkTarget::Epub,
+            books: vec![ShelfBook {
+                url: String::from("https://example.org/other/"),
+                epub: PathBuf::from("Other Book.epub"),
+                kepub: false,
+            }],
+        }),
+        ..Default::default()
+    };
+
+    let (dest, output, epub) = generate_test_epub(book.path(), &options);
+
+    let chapter = epub.get_str(&epub.content_documents().unwrap()[0]).unwrap();
+    assert!(chapter.contains(r#"href="Other%20Book.epub""#));
+    let findings = crate::validate::validate(&dest.path().join(&output.path), &output.path);
+    assert_eq!(findings.unwrap(), vec![]);
+}
+
+#[test]
+fn test_generate_epub_with_math_and_highlight() {
+    let book = test_book("# Chapter 1\n\n```sh\necho $$\n```\n\nThe area is $$ \\pi r^2 $$\n");
+    let options = BookOptions {
+        highlight: Some(Highlight {
+            theme: String::from(crate::highlight::DEFAULT_THEME),
+            classes: true,
+        }),
+        math: Some(MathOutput::MathMl),
+        ..Default::default()
+    };
+
+    let (_dest, _, epub) = generate_test_epub(book.path(), &options);
+
+    let chapter = epub.get_str(&epub.content_documents().unwrap()[0]).unwrap();
+    assert_eq!(chapter.matches("<math").count(), 1);
+    assert!(chapter.contains(r#"alttext="\pi r^2""#));
+    assert!(chapter.contains("<pre class=\"hl-code\">"));
+    assert!(chapter.contains("$$"));
+}
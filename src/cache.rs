@@ -0,0 +1,221 @@
+//! Build cache used to skip books which did not change since the last run.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::book::BookOptions;
+use crate::checksum;
+use crate::cover::Cover;
+use crate::ManifestEntry;
+
+/// Name of the cache file stored inside the working directory.
+const CACHE_FILE: &str = ".mdbookshelf-cache.json";
+
+/// Git tag of mdbook-epub used to render EPUBs, checked against `Cargo.toml` by the tests.
+const MDBOOK_EPUB_TAG: &str = "v0.5.1";
+
+/// Everything which has an effect on the EPUB generated for a book.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CacheKey {
+    /// The commit sha the book was generated from
+    pub commit_sha: String,
+    /// The effective shelf-side overrides of the book
+    pub options: BookOptions,
+    /// The mdbookshelf version
+    pub mdbookshelf_version: String,
+    /// The mdbook-epub version
+    pub mdbook_epub_version: String,
+    /// The SHA-256 digest of the cover image file, whose path only is in the options
+    /// (generated covers hold their template contents)
+    pub cover_sha256: Option<String>,
+}
+
+impl CacheKey {
+    pub(crate) fn new(commit_sha: &str, options: &BookOptions) -> Self {
+        CacheKey {
+            commit_sha: commit_sha.to_owned(),
+            options: options.clone(),
+            mdbookshelf_version: env!("CARGO_PKG_VERSION").to_owned(),
+            // mdbook-epub versions carry the version of the mdbook they are built with
+            mdbook_epub_version: format!(
+                "{}+{}",
+                MDBOOK_EPUB_TAG.trim_start_matches('v'),
+                mdbook::MDBOOK_VERSION
+            ),
+            cover_sha256: match &options.cover {
+                Some(Cover::Image(path)) => checksum::digest_file(path, false)
+                    .ok()
+                    .map(|digests| digests.sha256),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheRecord {
+    key: CacheKey,
+    entry: ManifestEntry,
+}
+
+/// The manifest entries of previously generated books, indexed by book id.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct BuildCache {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    dirty: bool,
+    records: HashMap<String, CacheRecord>,
+}
+
+impl BuildCache {
+    /// Loads the cache from `working_dir`. A missing or unreadable cache is treated as empty.
+    pub(crate) fn load(working_dir: &Path) -> Self {
+        let path = working_dir.join(CACHE_FILE);
+        let mut cache = match File::open(&path) {
+            Ok(f) => serde_json::from_reader(f).unwrap_or_else(|e| {
+                warn!("Ignoring invalid build cache {}: {}", path.display(), e);
+                BuildCache::default()
+            }),
+            Err(_) => BuildCache::default(),
+        };
+        cache.path = path;
+        cache
+    }
+
+    /// Returns the cached entry of book `id` if it was generated with the same `key`
+    /// and its files are still present in `dest`.
+    pub(crate) fn lookup(&self, id: &str, key: &CacheKey, dest: &Path) -> Option<&ManifestEntry> {
+        let record = self.records.get(id).filter(|r| r.key == *key)?;
+        let entry = &record.entry;
+        let paths = std::iter::once(&entry.path)
+            .chain(entry.artifacts.iter().map(|a| &a.path))
+            .chain(entry.thumbnails.iter().map(|t| &t.path))
+            .chain(entry.link_report.iter().map(|r| &r.path));
+        for path in paths {
+            if !dest.join(path).is_file() {
+                debug!("Cached file {} is missing", path.display());
+                return None;
+            }
+        }
+        Some(entry)
+    }
+
+    pub(crate) fn insert(&mut self, id: &str, key: CacheKey, entry: &ManifestEntry) {
+        let record = CacheRecord {
+            key,
+            entry: entry.clone(),
+        };
+        self.records.insert(id.to_owned(), record);
+        self.dirty = true;
+    }
+
+    /// Writes the cache back to disk if it was modified.
+    pub(crate) fn save(&self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        debug!("Writing build cache to {}", self.path.display());
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let f = File::create(&self.path)?;
+        serde_json::to_writer_pretty(f, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn entry() -> ManifestEntry {
+        ManifestEntry {
+            path: PathBuf::from("Hello Rust.epub"),
+            title: String::from("Hello Rust"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        let working_dir = TempDir::new().unwrap();
+        let dest = TempDir::new().unwrap();
+        let options = BookOptions {
+            env_var: vec![(String::from("MDBOOK_BOOK__TITLE"), Some(String::from("A")))],
+            ..Default::default()
+        };
+        let key = CacheKey::new("sha", &options);
+
+        let mut cache = BuildCache::load(working_dir.path());
+        cache.insert("book", key.clone(), &entry());
+        // EPUB was removed from the destination directory
+        assert!(cache.lookup("book", &key, dest.path()).is_none());
+
+        File::create(dest.path().join("Hello Rust.epub")).unwrap();
+        assert_eq!(cache.lookup("book", &key, dest.path()), Some(&entry()));
+        assert!(cache.lookup("other", &key, dest.path()).is_none());
+        assert!(cache
+            .lookup("book", &CacheKey::new("sha2", &options), dest.path())
+            .is_none());
+        assert!(cache
+            .lookup(
+                "book",
+                &CacheKey::new("sha", &Default::default()),
+                dest.path()
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn test_cover_image_key() {
+        let dir = TempDir::new().unwrap();
+        let cover = dir.path().join("cover.png");
+        std::fs::write(&cover, b"first").unwrap();
+        let options = BookOptions {
+            cover: Some(Cover::Image(cover.clone())),
+            ..Default::default()
+        };
+        let key = CacheKey::new("sha", &options);
+        assert_eq!(key, CacheKey::new("sha", &options));
+
+        // Same path, new image
+        std::fs::write(&cover, b"second").unwrap();
+        assert_ne!(key, CacheKey::new("sha", &options));
+    }
+
+    #[test]
+    fn test_mdbook_epub_tag() {
+        // A stale tag would keep serving the EPUBs rendered by the previous mdbook-epub
+        let manifest: toml::Value = include_str!("../Cargo.toml").parse().unwrap();
+        let tag = manifest["dependencies"]["mdbook-epub"].get("tag");
+        assert_eq!(tag.and_then(toml::Value::as_str), Some(MDBOOK_EPUB_TAG));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let working_dir = TempDir::new().unwrap();
+        let key = CacheKey::new("sha", &Default::default());
+
+        let mut cache = BuildCache::load(working_dir.path());
+        cache.save().unwrap();
+        assert!(!working_dir.path().join(CACHE_FILE).exists());
+
+        cache.insert("book", key.clone(), &entry());
+        cache.save().unwrap();
+
+        let cache = BuildCache::load(working_dir.path());
+        let record = cache.records.get("book").unwrap();
+        assert_eq!(record.key, key);
+        assert_eq!(record.entry, entry());
+    }
+}
@@ -0,0 +1,162 @@
+//! Digests of the generated files.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use log::info;
+use sha2::{Digest, Sha256};
+
+use crate::ManifestEntry;
+
+/// Name of the checksums file written in the destination directory.
+const SHA256SUMS: &str = "SHA256SUMS";
+
+/// The digests of a file, hex encoded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Digests {
+    pub sha256: String,
+    pub blake3: Option<String>,
+}
+
+/// Computes the SHA-256 digest of the file at `path`, and its BLAKE3 digest if `blake3` is set.
+pub(crate) fn digest_file(path: &Path, blake3: bool) -> io::Result<Digests> {
+    let mut file = File::open(path)?;
+    let mut sha256 = Sha256::new();
+    let mut blake3 = blake3.then(blake3::Hasher::new);
+
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        sha256.update(&buf[..n]);
+        if let Some(hasher) = blake3.as_mut() {
+            hasher.update(&buf[..n]);
+        }
+    }
+
+    Ok(Digests {
+        sha256: format!("{:x}", sha256.finalize()),
+        blake3: blake3.map(|hasher| hasher.finalize().to_hex().to_string()),
+    })
+}
+
+/// Writes the digests of the files of all `entries` to `SHA256SUMS` in `dest`,
+/// in the format expected by `sha256sum --check`: the generated books, then their
+/// thumbnails and link reports.
+pub(crate) fn write_sha256sums(dest: &Path, entries: &[ManifestEntry]) -> io::Result<PathBuf> {
+    let sums_path = dest.join(SHA256SUMS);
+    info!("Writing checksums to {}", sums_path.display());
+
+    let mut f = File::create(&sums_path)?;
+    let mut written = HashSet::new();
+    for entry in entries {
+        for artifact in &entry.artifacts {
+            if written.insert(&artifact.path) {
+                writeln!(f, "{}  {}", artifact.sha256, artifact.path.display())?;
+            }
+        }
+        // Their digests are not in the manifest
+        let others = entry
+            .thumbnails
+            .iter()
+            .map(|thumbnail| &thumbnail.path)
+            .chain(entry.link_report.iter().map(|report| &report.path))
+            .chain(
+                entry
+                    .artifacts
+                    .iter()
+                    .filter_map(|artifact| artifact.link_report.as_ref())
+                    .map(|report| &report.path),
+            );
+        for path in others {
+            if written.insert(path) {
+                let digests = digest_file(&dest.join(path), false)?;
+                writeln!(f, "{}  {}", digests.sha256, path.display())?;
+            }
+        }
+    }
+    Ok(sums_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{Artifact, LinkReport, Thumbnail};
+
+    #[test]
+    fn test_digest_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("abc");
+        std::fs::write(&path, "abc").unwrap();
+
+        let digests = digest_file(&path, false).unwrap();
+        assert_eq!(
+            digests.sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(digests.blake3, None);
+
+        let digests = digest_file(&path, true).unwrap();
+        assert_eq!(
+            digests.blake3.unwrap(),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+    }
+
+    #[test]
+    fn test_write_sha256sums() {
+        let dir = TempDir::new().unwrap();
+        let artifact = |path: &str, sha256: &str| Artifact {
+            path: PathBuf::from(path),
+            sha256: sha256.to_owned(),
+            ..Default::default()
+        };
+        let entries = vec![
+            ManifestEntry {
+                artifacts: vec![
+                    artifact("A-kindle.epub", "aa"),
+                    artifact("A-kobo.epub", "ab"),
+                ],
+                ..Default::default()
+            },
+            ManifestEntry {
+                artifacts: vec![Artifact {
+                    link_report: Some(LinkReport {
+                        path: PathBuf::from("B.links.json"),
+                        ..Default::default()
+                    }),
+                    ..artifact("B.epub", "bb")
+                }],
+                link_report: Some(LinkReport {
+                    path: PathBuf::from("B.links.json"),
+                    ..Default::default()
+                }),
+                thumbnails: vec![Thumbnail {
+                    path: PathBuf::from("B-200.png"),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+        ];
+        std::fs::write(dir.path().join("B.links.json"), "abc").unwrap();
+        std::fs::write(dir.path().join("B-200.png"), "").unwrap();
+
+        let path = write_sha256sums(dir.path(), &entries).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            concat!(
+                "aa  A-kindle.epub\nab  A-kobo.epub\nbb  B.epub\n",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  B-200.png\n",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  B.links.json\n"
+            )
+        );
+    }
+}
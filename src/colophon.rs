@@ -0,0 +1,213 @@
+//! Colophon chapter appended to the books, telling readers which revision they hold.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use mdbook::book::{Book, BookItem, Chapter};
+use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use serde::{Deserialize, Serialize};
+
+use crate::cover;
+use crate::epub::escape_xml;
+
+/// Template used when the shelf doesn't provide one.
+pub(crate) const DEFAULT_TEMPLATE: &str = r#"# Colophon
+
+This book was generated by [mdbookshelf](https://github.com/rams3s/mdbookshelf) {{ mdbookshelf_version }}.
+
+- Source: <{{ repo_url }}>
+{% if url %}- Online version: <{{ url }}>
+{% endif %}- Commit: `{{ commit_sha }}`
+- Last modified: {{ last_modified | date(format="%Y-%m-%d %H:%M UTC") }}
+- Built: {{ build_time | date(format="%Y-%m-%d %H:%M UTC") }}
+{% if qr_code %}
+<div class="colophon-qr-code">{{ qr_code }}</div>
+{% endif %}"#;
+
+/// Everything needed to render the colophon of a book, except the build time.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Colophon {
+    /// The Tera template rendering the chapter as Markdown
+    pub template: String,
+    /// Add a QR code linking to the online version
+    pub qr_code: bool,
+    /// The git repository url
+    pub repo_url: String,
+    /// The online rendered book url
+    pub url: String,
+    /// The commit sha the book is generated from
+    pub commit_sha: String,
+    /// The commit date
+    pub last_modified: String,
+    /// The book version
+    pub version: String,
+}
+
+#[derive(Serialize)]
+struct ColophonContext<'a> {
+    repo_url: &'a str,
+    url: &'a str,
+    commit_sha: &'a str,
+    last_modified: &'a str,
+    version: &'a str,
+    build_time: String,
+    mdbookshelf_version: &'a str,
+    qr_code: Option<String>,
+}
+
+impl Colophon {
+    /// Renders the colophon chapter content.
+    pub(crate) fn render(&self, build_time: DateTime<Utc>) -> Result<String> {
+        let qr_code = if self.has_qr_code() {
+            Some(qr_code_svg(&self.url)?)
+        } else {
+            None
+        };
+        let context = ColophonContext {
+            repo_url: &self.repo_url,
+            url: &self.url,
+            commit_sha: &self.commit_sha,
+            last_modified: &self.last_modified,
+            version: &self.version,
+            build_time: build_time.to_rfc3339(),
+            mdbookshelf_version: env!("CARGO_PKG_VERSION"),
+            qr_code,
+        };
+        let context = tera::Context::from_serialize(context)?;
+        tera::Tera::one_off(&self.template, &context, false)
+            .map_err(|e| anyhow!("Could not render colophon: {:?}", e))
+    }
+
+    /// Whether the colophon shows a QR code: there is none without an online url.
+    pub(crate) fn has_qr_code(&self) -> bool {
+        self.qr_code && !self.url.is_empty()
+    }
+
+    /// Replaces the inline SVG QR code in `xhtml`, which EPUB 2 doesn't allow, by an
+    /// image of `src`. Returns `None` if `xhtml` doesn't contain the QR code.
+    pub(crate) fn replace_qr_code(&self, xhtml: &str, src: &str) -> Result<Option<String>> {
+        let svg = qr_code_svg(&self.url)?;
+        if !xhtml.contains(&svg) {
+            return Ok(None);
+        }
+        let image = format!(r#"<img src="{}" alt="{}"/>"#, src, escape_xml(&self.url));
+        Ok(Some(xhtml.replacen(&svg, &image, 1)))
+    }
+
+    /// Returns the QR code as a PNG image.
+    pub(crate) fn qr_code_png(&self) -> Result<Vec<u8>> {
+        cover::rasterize(qr_code_svg(&self.url)?.as_bytes())
+    }
+}
+
+/// Name of the QR code image of the EPUB 2 books, next to the package document.
+pub(crate) const QR_CODE_IMAGE: &str = "colophon-qr-code.png";
+
+/// Returns an inline SVG QR code encoding `data`.
+fn qr_code_svg(data: &str) -> Result<String> {
+    let image = QrCode::new(data.as_bytes())?
+        .render::<svg::Color>()
+        .min_dimensions(160, 160)
+        .build();
+    // Drop the XML declaration, which is not allowed inside the chapter
+    Ok(match image.find("<svg") {
+        Some(start) => image[start..].to_owned(),
+        None => image,
+    })
+}
+
+/// mdbook preprocessor appending the colophon as the last chapter.
+pub(crate) struct ColophonPreprocessor {
+    pub content: String,
+}
+
+impl Preprocessor for ColophonPreprocessor {
+    fn name(&self) -> &str {
+        "mdbookshelf-colophon"
+    }
+
+    fn run(&self, _ctx: &PreprocessorContext, mut book: Book) -> mdbook::errors::Result<Book> {
+        let chapter = Chapter::new(
+            "Colophon",
+            self.content.to_owned(),
+            "colophon.md",
+            Vec::new(),
+        );
+        book.push_item(BookItem::Chapter(chapter));
+        Ok(book)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn colophon() -> Colophon {
+        Colophon {
+            template: DEFAULT_TEMPLATE.to_owned(),
+            qr_code: false,
+            repo_url: String::from("https://github.com/rams3s/mdbook-dummy.git"),
+            url: String::from("https://example.com/dummy/"),
+            commit_sha: String::from("0f0e2b5c6d"),
+            last_modified: String::from("2023-11-14T22:13:20+00:00"),
+            version: String::from("v1.0"),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let build_time = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let content = colophon().render(build_time).unwrap();
+
+        assert!(content.starts_with("# Colophon"));
+        assert!(content.contains("- Source: <https://github.com/rams3s/mdbook-dummy.git>"));
+        assert!(content.contains("- Online version: <https://example.com/dummy/>\n"));
+        assert!(content.contains("- Commit: `0f0e2b5c6d`"));
+        assert!(content.contains("- Last modified: 2023-11-14 22:13 UTC"));
+        assert!(content.contains("- Built: 2023-11-14 22:13 UTC"));
+        assert!(!content.contains("<svg"));
+    }
+
+    #[test]
+    fn test_render_without_url() {
+        let colophon = Colophon {
+            qr_code: true,
+            url: String::new(),
+            ..colophon()
+        };
+        let content = colophon.render(Utc::now()).unwrap();
+
+        assert!(!content.contains("Online version"));
+        assert!(
+            content.contains("- Source: <https://github.com/rams3s/mdbook-dummy.git>\n- Commit:")
+        );
+        assert!(!content.contains("<svg"));
+        assert!(!colophon.has_qr_code());
+    }
+
+    #[test]
+    fn test_render_qr_code() {
+        let colophon = Colophon {
+            template: String::from("{{ version }} {{ qr_code }}"),
+            qr_code: true,
+            ..colophon()
+        };
+        let content = colophon.render(Utc::now()).unwrap();
+
+        assert!(content.starts_with("v1.0 <svg xmlns="));
+        assert!(!content.contains("<?xml"));
+
+        let xhtml = format!("<body><p>{content}</p></body>");
+        let replaced = colophon.replace_qr_code(&xhtml, "qr.png").unwrap().unwrap();
+        assert_eq!(
+            replaced,
+            r#"<body><p>v1.0 <img src="qr.png" alt="https://example.com/dummy/"/></p></body>"#
+        );
+        assert!(colophon
+            .replace_qr_code("<body/>", "qr.png")
+            .unwrap()
+            .is_none());
+        assert!(colophon.qr_code_png().unwrap().starts_with(b"\x89PNG"));
+    }
+}
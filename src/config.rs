@@ -5,6 +5,7 @@
 #![deny(missing_docs)]
 
 use std::{
+    collections::HashMap,
     fs::File,
     io::Read,
     path::{Path, PathBuf},
@@ -14,19 +15,94 @@ use std::{
 use anyhow::{anyhow, Error, Result};
 use serde::{Deserialize, Deserializer, Serialize};
 use toml::{value::Table, Value};
+use uuid::Uuid;
 
 /// The overall configuration object for MDBookshelf, essentially an in-memory
 /// representation of `bookshelf.toml`.
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Config {
+    /// Render the admonitions (GitHub alerts, callouts, mdbook-admonish blocks) of the books.
+    pub admonitions: bool,
+    /// Also compute BLAKE3 digests of the generated files.
+    pub blake3: bool,
     /// An array of BookRepoConfig
     pub book_repo_configs: Vec<BookRepoConfig>,
+    /// Append a colophon chapter to every book.
+    pub colophon: bool,
+    /// Add a QR code linking to the online version to the colophon.
+    pub colophon_qr_code: bool,
+    /// Tera template of the colophon (if not set, uses the built-in one).
+    pub colophon_template: Option<PathBuf>,
+    /// Accent color of the generated covers (defaults to rust orange).
+    pub cover_accent_color: Option<String>,
+    /// SVG Tera template of the generated covers (if not set, uses the built-in one).
+    pub cover_template: Option<PathBuf>,
+    /// What links to other books of the shelf are rewritten to (`epub`, `online` or `keep`).
+    pub cross_book_links: Option<String>,
     /// Destination directory.
     pub destination_dir: Option<PathBuf>,
+    /// Formats the books are exported to, besides EPUB.
+    pub extra_formats: Vec<String>,
+    /// Markup replacing the HTML-only constructs by profile name, or `default` for all the profiles.
+    pub fallback_markup: HashMap<String, FallbackMarkup>,
+    /// Regenerate all books, ignoring the build cache (command line only).
+    pub force: bool,
+    /// Generate a cover for the books which don't ship one.
+    pub generate_covers: bool,
+    /// Highlight the code blocks of the books.
+    pub highlight_code: bool,
+    /// Style the highlighted code with CSS classes added to the stylesheet, instead of inline styles.
+    pub highlight_classes: bool,
+    /// Theme of the highlighted code (if not set, uses the profile one).
+    pub highlight_theme: Option<String>,
+    /// Themes of the highlighted code by profile name, taking precedence over `highlight_theme`.
+    pub highlight_themes: HashMap<String, String>,
+    /// Replace the embeds, videos, collapsible blocks and playground buttons by EPUB-safe markup.
+    pub html_fallbacks: bool,
+    /// Hosts (and their subdomains) remote images may be downloaded from (any if empty).
+    pub image_allowlist: Vec<String>,
+    /// Directory the downloaded remote images are cached in (defaults to `.images` in the working directory).
+    pub image_cache_dir: Option<PathBuf>,
+    /// Maximum size of a remote image in bytes (defaults to 5 MiB).
+    pub image_max_size: Option<u64>,
+    /// Maximum size of the remote images of a book in bytes (defaults to 50 MiB).
+    pub image_max_total_size: Option<u64>,
+    /// Base URL of a stand-in server the remote images are requested from instead.
+    pub image_proxy: Option<String>,
+    /// Report the broken links and missing assets of every book.
+    pub link_report: bool,
+    /// Download the remote images of the books and embed them in the EPUBs.
+    pub localize_images: bool,
+    /// Convert the TeX formulas of the books to MathML (plain text for EPUB2 profiles).
+    pub math: bool,
+    /// Recompress the images of the books, and rasterize their SVG images for the profiles which need it.
+    pub optimize_images: bool,
+    /// Pattern of the EPUB filenames (defaults to `{title}.epub`).
+    pub output_filename: Option<String>,
+    /// E-reader profiles, one EPUB is generated for each of them.
+    pub profiles: Vec<String>,
+    /// Rasterize the SVG images of the books to PNG, whatever the profile.
+    pub rasterize_svg: bool,
+    /// Replace the default mdbook-epub stylesheet instead of extending it.
+    pub replace_stylesheet: bool,
+    /// Generate byte-identical EPUBs for the same commit.
+    pub reproducible: bool,
+    /// Add the books to a series named after the shelf title.
+    pub series: bool,
+    /// Fail the build if a generated EPUB has validation problems (implies `validate`).
+    pub strict_validation: bool,
+    /// CSS added to every book.
+    pub stylesheet: Option<PathBuf>,
     /// Templates directory (if not set, will generate manifest.json).
     pub templates_dir: Option<PathBuf>,
+    /// Widths of the cover thumbnails written next to the EPUBs (none by default).
+    pub thumbnail_widths: Vec<u32>,
     /// Title of the book collection.
     pub title: String,
+    /// Validate the generated EPUBs, recording the problems in the manifest.
+    pub validate: bool,
+    /// Rebuild every book to check it is reproducible (command line only).
+    pub verify_reproducible: bool,
     /// Working directory.
     pub working_dir: Option<PathBuf>,
 }
@@ -64,37 +140,227 @@ impl<'de> Deserialize<'de> for Config {
             }
         };
 
+        let admonitions: bool = table
+            .remove("admonitions")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let blake3: bool = table
+            .remove("blake3")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
         let book_repo_configs: Vec<BookRepoConfig> = table
             .remove("book")
             .and_then(|value| value.try_into().ok())
             .unwrap_or_default();
+        let colophon: bool = table
+            .remove("colophon")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let colophon_qr_code: bool = table
+            .remove("colophon-qr-code")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let colophon_template: Option<PathBuf> = table
+            .remove("colophon-template")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let cover_accent_color: Option<String> = table
+            .remove("cover-accent-color")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let cover_template: Option<PathBuf> = table
+            .remove("cover-template")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let cross_book_links: Option<String> = table
+            .remove("cross-book-links")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
         let destination_dir: Option<PathBuf> = table
             .remove("destination-dir")
             .and_then(|value| value.try_into().ok())
             .unwrap_or_default();
+        let extra_formats: Vec<String> = table
+            .remove("extra-formats")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let fallback_markup: HashMap<String, FallbackMarkup> = table
+            .remove("fallback-markup")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let generate_covers: bool = table
+            .remove("generate-covers")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let highlight_code: bool = table
+            .remove("highlight-code")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let highlight_classes: bool = table
+            .remove("highlight-classes")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let highlight_theme: Option<String> = table
+            .remove("highlight-theme")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let highlight_themes: HashMap<String, String> = table
+            .remove("highlight-themes")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let html_fallbacks: bool = table
+            .remove("html-fallbacks")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let image_allowlist: Vec<String> = table
+            .remove("image-allowlist")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let image_cache_dir: Option<PathBuf> = table
+            .remove("image-cache-dir")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let image_max_size: Option<u64> = table
+            .remove("image-max-size")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let image_max_total_size: Option<u64> = table
+            .remove("image-max-total-size")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let image_proxy: Option<String> = table
+            .remove("image-proxy")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let link_report: bool = table
+            .remove("link-report")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let localize_images: bool = table
+            .remove("localize-images")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let math: bool = table
+            .remove("math")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let optimize_images: bool = table
+            .remove("optimize-images")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let output_filename: Option<String> = table
+            .remove("output-filename")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let profiles: Vec<String> = table
+            .remove("profiles")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let rasterize_svg: bool = table
+            .remove("rasterize-svg")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let replace_stylesheet: bool = table
+            .remove("replace-stylesheet")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let reproducible: bool = table
+            .remove("reproducible")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let series: bool = table
+            .remove("series")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let strict_validation: bool = table
+            .remove("strict-validation")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
+        let stylesheet: Option<PathBuf> = table
+            .remove("stylesheet")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
         let templates_dir: Option<PathBuf> = table
             .remove("templates-dir")
             .and_then(|value| value.try_into().ok())
             .unwrap_or_default();
+        let thumbnail_widths: Vec<u32> = table
+            .remove("thumbnail-widths")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
         let title: String = table
             .remove("title")
             .and_then(|value| value.try_into().ok())
             .unwrap_or_default();
+        let validate: bool = table
+            .remove("validate")
+            .and_then(|value| value.try_into().ok())
+            .unwrap_or_default();
         let working_dir: Option<PathBuf> = table
             .remove("working-dir")
             .and_then(|value| value.try_into().ok())
             .unwrap_or_default();
 
         Ok(Config {
+            admonitions,
+            blake3,
             book_repo_configs,
+            colophon,
+            colophon_qr_code,
+            colophon_template,
+            cover_accent_color,
+            cover_template,
+            cross_book_links,
             destination_dir,
+            extra_formats,
+            fallback_markup,
+            force: false,
+            generate_covers,
+            highlight_code,
+            highlight_classes,
+            highlight_theme,
+            highlight_themes,
+            html_fallbacks,
+            image_allowlist,
+            image_cache_dir,
+            image_max_size,
+            image_max_total_size,
+            image_proxy,
+            link_report,
+            localize_images,
+            math,
+            optimize_images,
+            output_filename,
+            profiles,
+            rasterize_svg,
+            replace_stylesheet,
+            reproducible,
+            series,
+            strict_validation,
+            stylesheet,
             templates_dir,
+            thumbnail_widths,
             title,
+            validate,
+            verify_reproducible: false,
             working_dir,
         })
     }
 }
 
+/// Markup replacing the HTML-only constructs of the chapters, `{url}` and `{title}` being
+/// substituted. The fields which are not set keep the built-in markup.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct FallbackMarkup {
+    /// Replaces the `<iframe>` embeds
+    pub embed: Option<String>,
+    /// Replaces the `<video>` elements
+    pub video: Option<String>,
+    /// Follows the Rust code blocks which have a playground button in HTML
+    pub playground: Option<String>,
+}
+
 /// The configuration for a single book
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 #[serde(default, rename_all = "kebab-case")]
@@ -111,10 +377,55 @@ pub struct BookRepoConfig {
     /// Dynamic mdBook config.
     /// Use special environment variables to change config while loading mdbook
     pub env_var: Option<Table>,
+    /// The book's authors. If set, overwrites the value read from the book itself.
+    pub authors: Option<Vec<String>>,
+    /// The book's language. If set, overwrites the value read from the book itself.
+    pub language: Option<String>,
+    /// The book's description. If set, overwrites the value read from the book itself.
+    pub description: Option<String>,
+    /// The book's publisher, added to the EPUB metadata.
+    pub publisher: Option<String>,
+    /// The book's subject tags, added to the EPUB metadata.
+    pub subject: Option<Vec<String>>,
+    /// The book's copyright statement, added to the EPUB metadata.
+    pub rights: Option<String>,
+    /// Pattern of the EPUB filename. If set, overwrites the shelf's pattern.
+    pub output_filename: Option<String>,
+    /// The book's unique identifier.
+    /// If not set, it is derived from the repository url and folder.
+    pub uuid: Option<Uuid>,
+    /// The book's position in the shelf series.
+    /// If not set, it is the position of the book in the config.
+    #[serde(deserialize_with = "deserialize_finite")]
+    pub series_index: Option<f64>,
+    /// The cover image (PNG, JPEG or SVG), relative to `bookshelf.toml`.
+    pub cover: Option<PathBuf>,
+    /// CSS added to the book, after the shelf one.
+    pub stylesheet: Option<PathBuf>,
+    /// Replace the default mdbook-epub stylesheet instead of extending it.
+    /// If not set, uses the shelf setting.
+    pub replace_stylesheet: Option<bool>,
+    /// E-reader profiles of the book. If not set, uses the shelf ones.
+    pub profiles: Option<Vec<String>>,
+    /// Formats the book is exported to, besides EPUB. If not set, uses the shelf ones.
+    pub extra_formats: Option<Vec<String>>,
+    /// Convert the TeX formulas to MathML. If not set, uses the shelf setting.
+    pub math: Option<bool>,
 }
 
+// `series_index` is finite, so that the equality is reflexive
 impl Eq for BookRepoConfig {}
 
+/// Deserializes an optional number, rejecting NaN and the infinities.
+fn deserialize_finite<'de, D: Deserializer<'de>>(de: D) -> Result<Option<f64>, D::Error> {
+    match Option::<f64>::deserialize(de)? {
+        Some(value) if !value.is_finite() => Err(serde::de::Error::custom(format!(
+            "expected a finite number, got {value}"
+        ))),
+        value => Ok(value),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::iter::FromIterator;
@@ -126,16 +437,54 @@ mod tests {
     const COMPLEX_CONFIG: &str = r#"
         title = "My bookshelf"
         templates-dir = "templates/"
+        output-filename = "{slug}-{version}.epub"
+        series = true
+        colophon = true
+        colophon-template = "colophon.md"
+        generate-covers = true
+        cover-accent-color = "teal"
+        thumbnail-widths = [200, 600]
+        stylesheet = "epub.css"
+        profiles = ["kindle", "kobo"]
+        extra-formats = ["kepub"]
+        strict-validation = true
+        link-report = true
+        cross-book-links = "epub"
+        localize-images = true
+        image-allowlist = ["img.shields.io"]
+        image-max-size = 100000
+        highlight-code = true
+        math = true
+        html-fallbacks = true
+        admonitions = true
+
+        [highlight-themes]
+        kindle = "Solarized (light)"
+
+        [fallback-markup.kindle]
+        video = "<p><a href=\"{url}\">{title}</a></p>"
 
         [[book]]
         title = "Some Book"
         repo-url = "git_source"
         url = "source"
         folder = "./foo"
+        output-filename = "{slug}.epub"
+        uuid = "3b241101-e2bb-4255-8caf-4136c566a962"
+        cover = "covers/some-book.png"
+        stylesheet = "css/some-book.css"
+        replace-stylesheet = true
 
         [[book]]
         repo-url = "git_source2"
         url = "source2"
+        authors = ["Jane Doe", "John Doe"]
+        language = "fr"
+        publisher = "Rustaceans"
+        subject = ["Rust", "Programming"]
+        series-index = 1.5
+        profiles = ["epub2-compat"]
+        math = false
 
         [book.env-var]
         MDBOOK_PREPROCESSOR__NOCOMMENT = """\
@@ -155,6 +504,11 @@ mod tests {
                 folder: Some(PathBuf::from("./foo")),
                 repo_url: String::from("git_source"),
                 url: String::from("source"),
+                output_filename: Some(String::from("{slug}.epub")),
+                uuid: Uuid::parse_str("3b241101-e2bb-4255-8caf-4136c566a962").ok(),
+                cover: Some(PathBuf::from("covers/some-book.png")),
+                stylesheet: Some(PathBuf::from("css/some-book.css")),
+                replace_stylesheet: Some(true),
                 ..Default::default()
             },
             BookRepoConfig {
@@ -167,6 +521,13 @@ mod tests {
                     ),
                     (String::from("MDBOOK_PREPROCESSOR__NOP"), Value::from("")),
                 ])),
+                authors: Some(vec![String::from("Jane Doe"), String::from("John Doe")]),
+                language: Some(String::from("fr")),
+                publisher: Some(String::from("Rustaceans")),
+                subject: Some(vec![String::from("Rust"), String::from("Programming")]),
+                series_index: Some(1.5),
+                profiles: Some(vec![String::from("epub2-compat")]),
+                math: Some(false),
                 ..Default::default()
             },
         ];
@@ -174,7 +535,48 @@ mod tests {
         let got = Config::from_str(src).unwrap();
 
         assert_eq!(got.title, "My bookshelf");
+        assert_eq!(got.output_filename.unwrap(), "{slug}-{version}.epub");
+        assert!(got.series);
+        assert!(got.colophon);
+        assert!(!got.colophon_qr_code);
+        assert_eq!(got.colophon_template.unwrap(), Path::new("colophon.md"));
+        assert!(got.generate_covers);
+        assert_eq!(got.cover_accent_color.unwrap(), "teal");
+        assert!(got.cover_template.is_none());
+        assert_eq!(got.thumbnail_widths, vec![200, 600]);
+        assert_eq!(got.stylesheet.unwrap(), Path::new("epub.css"));
+        assert!(!got.replace_stylesheet);
+        assert_eq!(got.profiles, vec!["kindle", "kobo"]);
+        assert_eq!(got.extra_formats, vec!["kepub"]);
+        assert!(!got.validate);
+        assert!(got.strict_validation);
+        assert!(got.link_report);
+        assert_eq!(got.cross_book_links.unwrap(), "epub");
+        assert!(got.localize_images);
+        assert_eq!(got.image_allowlist, vec!["img.shields.io"]);
+        assert_eq!(got.image_max_size, Some(100000));
+        assert_eq!(got.image_max_total_size, None);
+        assert!(got.highlight_code);
+        assert_eq!(got.highlight_themes["kindle"], "Solarized (light)");
+        assert!(got.math);
+        assert!(got.html_fallbacks);
+        assert!(got.admonitions);
+        assert_eq!(
+            got.fallback_markup["kindle"].video.as_deref(),
+            Some(r#"<p><a href="{url}">{title}</a></p>"#)
+        );
+        assert!(got.fallback_markup["kindle"].embed.is_none());
         assert_eq!(got.templates_dir.unwrap().to_str().unwrap(), "templates/");
         assert_eq!(got.book_repo_configs, book_repo_configs);
     }
+
+    #[test]
+    fn test_series_index_is_finite() {
+        let parse = |src: &str| toml::from_str::<BookRepoConfig>(src).map(|got| got.series_index);
+
+        assert_eq!(parse("series-index = 2").unwrap(), Some(2.0));
+        assert_eq!(parse("").unwrap(), None);
+        assert!(parse("series-index = nan").is_err());
+        assert!(parse("series-index = inf").is_err());
+    }
 }
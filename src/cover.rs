@@ -0,0 +1,190 @@
+//! Cover images embedded in the generated EPUBs.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use resvg::{tiny_skia, usvg};
+use serde::{Deserialize, Serialize};
+
+/// Template used when the shelf doesn't provide one.
+pub(crate) const DEFAULT_TEMPLATE: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="1200" height="1800" viewBox="0 0 1200 1800">
+  <rect width="1200" height="1800" fill="#fdfcf8"/>
+  <rect width="1200" height="540" fill="{{ accent_color }}"/>
+  <rect y="1720" width="1200" height="80" fill="{{ accent_color }}"/>
+  <text x="100" y="720" font-family="sans-serif" font-size="96" font-weight="bold" fill="#222222">
+  {%- for line in title_lines %}
+    <tspan x="100" dy="{% if loop.first %}0{% else %}120{% endif %}">{{ line }}</tspan>
+  {%- endfor %}
+  </text>
+  <text x="100" y="1440" font-family="sans-serif" font-size="56" fill="#444444">{{ authors | join(sep=", ") }}</text>
+  <text x="100" y="1640" font-family="sans-serif" font-size="40" fill="#888888">{{ shelf }}</text>
+</svg>
+"##;
+
+/// Accent color used when the shelf doesn't set one.
+pub(crate) const DEFAULT_ACCENT_COLOR: &str = "#b7410e";
+
+/// Maximum number of characters per line of the title in the default template.
+const TITLE_LINE_WIDTH: usize = 18;
+
+/// Where the cover of a book comes from.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Cover {
+    /// An image file (PNG, JPEG or SVG)
+    Image(PathBuf),
+    /// Rendered from an SVG Tera template, unless the book already has a cover
+    Generated {
+        /// The SVG Tera template
+        template: String,
+        /// The shelf title
+        shelf: String,
+        /// The accent color, as an SVG color
+        accent_color: String,
+    },
+}
+
+/// A cover image ready to be embedded in an EPUB.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CoverImage {
+    /// The file name inside the EPUB
+    pub file_name: String,
+    /// The image media type
+    pub media_type: &'static str,
+    /// The encoded image
+    pub data: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct CoverContext<'a> {
+    title: &'a str,
+    title_lines: Vec<String>,
+    authors: &'a [String],
+    shelf: &'a str,
+    accent_color: &'a str,
+}
+
+/// Loads the cover image at `path`, rasterizing it if it is an SVG.
+pub(crate) fn load(path: &Path) -> Result<CoverImage> {
+    let data =
+        std::fs::read(path).with_context(|| format!("Could not read cover {}", path.display()))?;
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    match extension.as_str() {
+        "png" => Ok(png(data)),
+        "jpg" | "jpeg" => Ok(CoverImage {
+            file_name: String::from("cover.jpg"),
+            media_type: "image/jpeg",
+            data,
+        }),
+        "svg" => Ok(png(rasterize(&data)?)),
+        _ => bail!("Unsupported cover format {}", path.display()),
+    }
+}
+
+/// Renders the cover `template` of a book and rasterizes it to PNG.
+pub(crate) fn generate(
+    template: &str,
+    title: &str,
+    authors: &[String],
+    shelf: &str,
+    accent_color: &str,
+) -> Result<CoverImage> {
+    let context = CoverContext {
+        title,
+        title_lines: wrap(title, TITLE_LINE_WIDTH),
+        authors,
+        shelf,
+        accent_color,
+    };
+    let context = tera::Context::from_serialize(context)?;
+    let svg = tera::Tera::one_off(template, &context, true)
+        .map_err(|e| anyhow!("Could not render cover: {:?}", e))?;
+    Ok(png(rasterize(svg.as_bytes())?))
+}
+
+fn png(data: Vec<u8>) -> CoverImage {
+    CoverImage {
+        file_name: String::from("cover.png"),
+        media_type: "image/png",
+        data,
+    }
+}
+
+/// Rasterizes an SVG document to PNG, at the size declared by the document.
+pub(crate) fn rasterize(svg: &[u8]) -> Result<Vec<u8>> {
+    let mut options = usvg::Options::default();
+    options.fontdb_mut().load_system_fonts();
+    let tree = usvg::Tree::from_data(svg, &options)?;
+    let size = tree.size().to_int_size();
+    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+        .ok_or_else(|| anyhow!("Invalid SVG size {}x{}", size.width(), size.height()))?;
+    resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+    Ok(pixmap.encode_png()?)
+}
+
+/// Splits `text` in lines of at most `width` characters, breaking between words.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if line.chars().count() + 1 + word.chars().count() <= width => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.to_owned()),
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(
+            wrap("The Rust Programming Language", 18),
+            vec!["The Rust", "Programming", "Language"]
+        );
+        assert_eq!(wrap("Rust by Example", 18), vec!["Rust by Example"]);
+        assert!(wrap("", 18).is_empty());
+    }
+
+    #[test]
+    fn test_generate() {
+        let authors = vec![String::from("Ferris")];
+        let cover = generate(
+            DEFAULT_TEMPLATE,
+            "Hello <Rust>",
+            &authors,
+            "My shelf",
+            DEFAULT_ACCENT_COLOR,
+        )
+        .unwrap();
+
+        assert_eq!(cover.file_name, "cover.png");
+        assert_eq!(cover.media_type, "image/png");
+        assert!(cover.data.starts_with(b"\x89PNG"));
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("cover.svg");
+        std::fs::write(
+            &path,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="6"/>"#,
+        )
+        .unwrap();
+
+        let cover = load(&path).unwrap();
+        assert_eq!(cover.media_type, "image/png");
+        assert!(cover.data.starts_with(b"\x89PNG"));
+
+        assert!(load(&dir.path().join("cover.gif")).is_err());
+    }
+}
@@ -0,0 +1,235 @@
+//! Rewriting of the links between the books of the shelf.
+
+use std::path::{Path, PathBuf};
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::export::Format;
+use crate::single_file::replace_attribute;
+
+/// Characters escaped in the EPUB filenames used as link targets.
+const FILENAME: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?');
+
+/// What links to other books of the shelf are rewritten to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum LinkTarget {
+    /// The filename of the other book EPUB
+    Epub,
+    /// The other book online version, under its canonical `url`
+    Online,
+    /// The link as written
+    Keep,
+}
+
+impl LinkTarget {
+    /// Returns the target called `name` in `bookshelf.toml`.
+    pub(crate) fn from_name(name: &str) -> Option<LinkTarget> {
+        match name {
+            "epub" => Some(LinkTarget::Epub),
+            "online" => Some(LinkTarget::Online),
+            "keep" => Some(LinkTarget::Keep),
+            _ => None,
+        }
+    }
+}
+
+/// Another book of the shelf.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ShelfBook {
+    /// The book online version url
+    pub url: String,
+    /// The book EPUB filename
+    pub epub: PathBuf,
+    /// The book is also exported to KEPUB, next to its EPUB
+    pub kepub: bool,
+}
+
+/// The links rewritten in a book.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CrossLinks {
+    /// The online version url of the book, relative links escaping the book are based on
+    pub url: String,
+    pub target: LinkTarget,
+    /// The other books of the shelf
+    pub books: Vec<ShelfBook>,
+}
+
+impl CrossLinks {
+    /// Rewrites the links of the content document `xhtml`, at `document` in the EPUB
+    /// (relative to the package directory).
+    pub(crate) fn rewrite(&self, xhtml: &str, document: &str) -> String {
+        replace_attribute(xhtml, "href", |href| {
+            let absolute = self.absolute(href, document);
+            let href = absolute.as_deref().unwrap_or(href);
+            self.books
+                .iter()
+                .find_map(|book| {
+                    let rest = strip_base(href, &book.url)?;
+                    match self.target {
+                        LinkTarget::Epub => Some(encode_filename(&book.epub)),
+                        LinkTarget::Online => Some(format!("{}{}", base(&book.url), rest)),
+                        LinkTarget::Keep => None,
+                    }
+                })
+                .or(absolute)
+        })
+    }
+
+    /// Points the links of `xhtml` rewritten to the EPUB of other books to their KEPUB
+    /// instead, for the books also exported to KEPUB.
+    pub(crate) fn to_kepub(&self, xhtml: &str) -> String {
+        if self.target != LinkTarget::Epub {
+            return xhtml.to_owned();
+        }
+        replace_attribute(xhtml, "href", |href| {
+            self.books
+                .iter()
+                .filter(|book| book.kepub)
+                .find(|book| href == encode_filename(&book.epub))
+                .map(|book| encode_filename(&Format::Kepub.output_path(&book.epub)))
+        })
+    }
+
+    /// Turns `href` into an absolute link based on the book url, if it is relative
+    /// and escapes the book.
+    fn absolute(&self, href: &str, document: &str) -> Option<String> {
+        if href.contains(':') || href.starts_with('#') || self.url.is_empty() {
+            return None;
+        }
+        let mut depth = document.matches('/').count() as isize;
+        for part in href.split(['#', '?']).next().unwrap_or_default().split('/') {
+            match part {
+                ".." => depth -= 1,
+                "" | "." => {}
+                _ => depth += 1,
+            }
+            if depth < 0 {
+                let url = Url::parse(&self.url).ok()?.join(document).ok()?;
+                return Some(url.join(href).ok()?.to_string());
+            }
+        }
+        None
+    }
+}
+
+/// Percent-encodes `filename` to be used as a link target.
+fn encode_filename(filename: &Path) -> String {
+    utf8_percent_encode(&filename.to_string_lossy(), FILENAME).to_string()
+}
+
+/// The directory of the book online version at `url`, ending with a `/`.
+fn base(url: &str) -> String {
+    match url.rfind('/') {
+        Some(end) if url[end..].contains('.') && url[..end].contains("//") => {
+            url[..=end].to_owned()
+        }
+        _ => format!("{}/", url.trim_end_matches('/')),
+    }
+}
+
+/// Returns the rest of `href` if it points into the book online at `url`,
+/// whatever its scheme.
+fn strip_base<'a>(href: &'a str, url: &str) -> Option<&'a str> {
+    let base = base(url);
+    let base = without_scheme(&base).trim_end_matches('/');
+    let rest = without_scheme(href).strip_prefix(base)?;
+    if rest.is_empty() || rest.starts_with(['/', '#', '?']) {
+        Some(rest.trim_start_matches('/'))
+    } else {
+        None
+    }
+}
+
+/// Returns `url` without its scheme.
+fn without_scheme(url: &str) -> &str {
+    url.split_once("://").map_or(url, |(_, rest)| rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cross_links(target: LinkTarget) -> CrossLinks {
+        CrossLinks {
+            url: String::from("https://rust-lang-nursery.github.io/rust-cookbook/"),
+            target,
+            books: vec![ShelfBook {
+                url: String::from("https://doc.rust-lang.org/stable/book/index.html"),
+                epub: PathBuf::from("The Rust Programming Language.epub"),
+                kepub: true,
+            }],
+        }
+    }
+
+    const XHTML: &str = concat!(
+        r#"<a href="http://doc.rust-lang.org/stable/book/ch03-00.html#loops">a</a>"#,
+        r#"<a href="../../std/index.html">b</a>"#,
+        r#"<a href="../intro.html">c</a>"#,
+        r#"<a href="https://doc.rust-lang.org/stable/bookshelf/">d</a>"#,
+    );
+
+    #[test]
+    fn test_rewrite() {
+        assert_eq!(
+            cross_links(LinkTarget::Epub).rewrite(XHTML, "web/clients.html"),
+            concat!(
+                r#"<a href="The%20Rust%20Programming%20Language.epub">a</a>"#,
+                r#"<a href="https://rust-lang-nursery.github.io/std/index.html">b</a>"#,
+                r#"<a href="../intro.html">c</a>"#,
+                r#"<a href="https://doc.rust-lang.org/stable/bookshelf/">d</a>"#,
+            )
+        );
+        assert!(cross_links(LinkTarget::Online)
+            .rewrite(XHTML, "web/clients.html")
+            .starts_with(
+                r#"<a href="https://doc.rust-lang.org/stable/book/ch03-00.html#loops">a</a>"#
+            ));
+        assert!(cross_links(LinkTarget::Keep)
+            .rewrite(XHTML, "web/clients.html")
+            .starts_with(
+                r#"<a href="http://doc.rust-lang.org/stable/book/ch03-00.html#loops">a</a>"#
+            ));
+    }
+
+    #[test]
+    fn test_to_kepub() {
+        let xhtml = cross_links(LinkTarget::Epub).rewrite(XHTML, "web/clients.html");
+        assert!(cross_links(LinkTarget::Epub)
+            .to_kepub(&xhtml)
+            .starts_with(r#"<a href="The%20Rust%20Programming%20Language.kepub.epub">a</a>"#));
+
+        let mut cross_links = cross_links(LinkTarget::Epub);
+        cross_links.books[0].kepub = false;
+        assert_eq!(cross_links.to_kepub(&xhtml), xhtml);
+    }
+
+    #[test]
+    fn test_strip_base() {
+        let url = "https://github.com/rust-lang-nursery/rust-cookbook";
+        assert_eq!(
+            strip_base("https://github.com/rust-lang-nursery/rust-cookbook", url),
+            Some("")
+        );
+        assert_eq!(
+            strip_base(
+                "http://github.com/rust-lang-nursery/rust-cookbook/a.html",
+                url
+            ),
+            Some("a.html")
+        );
+        assert_eq!(
+            strip_base("https://github.com/rust-lang-nursery/rust-cookbook2", url),
+            None
+        );
+    }
+}
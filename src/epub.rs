@@ -0,0 +1,951 @@
+//! Post-processing of the EPUB files generated by mdbook-epub.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime as ChronoDateTime, Datelike, Timelike, Utc};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+use crate::single_file::replace_attribute;
+use crate::validate::resolve;
+
+pub(crate) const CONTAINER_PATH: &str = "META-INF/container.xml";
+
+/// An EPUB loaded in memory, keeping the order of its entries.
+#[derive(Debug, Default)]
+pub(crate) struct Epub {
+    entries: Vec<(String, Vec<u8>)>,
+    /// Modification time given to all entries when saving (defaults to now)
+    last_modified: Option<DateTime>,
+}
+
+impl Epub {
+    /// Reads all entries of the EPUB at `path`.
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+        let mut archive = ZipArchive::new(file)?;
+        let mut entries = Vec::with_capacity(archive.len());
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            if file.is_dir() {
+                continue;
+            }
+            let mut data = Vec::with_capacity(file.size() as usize);
+            file.read_to_end(&mut data)?;
+            entries.push((file.name().to_owned(), data));
+        }
+        Ok(Epub {
+            entries,
+            last_modified: None,
+        })
+    }
+
+    /// Writes the EPUB to `path`, starting with the uncompressed `mimetype` entry.
+    pub(crate) fn save(&self, path: &Path) -> Result<()> {
+        let mut zip = ZipWriter::new(File::create(path)?);
+        let mut options = SimpleFileOptions::default();
+        if let Some(last_modified) = self.last_modified {
+            options = options.last_modified_time(last_modified);
+        }
+        let stored = options.compression_method(CompressionMethod::Stored);
+        let deflated = options.compression_method(CompressionMethod::Deflated);
+
+        zip.start_file("mimetype", stored)?;
+        zip.write_all(self.get("mimetype").unwrap_or(b"application/epub+zip"))?;
+        for (name, data) in self.entries.iter().filter(|(name, _)| name != "mimetype") {
+            zip.start_file(name.as_str(), deflated)?;
+            zip.write_all(data)?;
+        }
+        zip.finish()?;
+        Ok(())
+    }
+
+    /// Returns the content of entry `name`.
+    pub(crate) fn get(&self, name: &str) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    /// Returns the content of entry `name` as text.
+    pub(crate) fn get_str(&self, name: &str) -> Result<&str> {
+        let data = self
+            .get(name)
+            .ok_or_else(|| anyhow!("Missing {} in EPUB", name))?;
+        std::str::from_utf8(data).with_context(|| format!("{} is not valid UTF-8", name))
+    }
+
+    /// Replaces the content of entry `name`, adding it if it does not exist yet.
+    pub(crate) fn set(&mut self, name: &str, data: Vec<u8>) {
+        match self.entries.iter_mut().find(|(n, _)| n == name) {
+            Some(entry) => entry.1 = data,
+            None => self.entries.push((name.to_owned(), data)),
+        }
+    }
+
+    /// Path of the OPF package document, as declared in `META-INF/container.xml`.
+    pub(crate) fn package_path(&self) -> Result<String> {
+        let container = self.get_str(CONTAINER_PATH)?;
+        let rootfile = container
+            .find("<rootfile ")
+            .map(|start| &container[start..])
+            .ok_or_else(|| anyhow!("No rootfile declared in {}", CONTAINER_PATH))?;
+        attribute(rootfile, "full-path")
+            .map(str::to_owned)
+            .ok_or_else(|| anyhow!("No rootfile path declared in {}", CONTAINER_PATH))
+    }
+
+    /// Makes the archive reproducible: entries are sorted by name and all get
+    /// `timestamp` (seconds since epoch) as modification time.
+    pub(crate) fn normalize(&mut self, timestamp: i64) {
+        self.entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        self.last_modified = Some(zip_datetime(timestamp));
+    }
+
+    /// Replaces the unique identifier of the book in the package document and
+    /// in the NCX table of contents (if any).
+    pub(crate) fn set_identifier(&mut self, identifier: &str) -> Result<()> {
+        let package_path = self.package_path()?;
+        let package = self.get_str(&package_path)?;
+        let (package, previous) =
+            replace_element_text(package, "<dc:identifier", "</dc:identifier>", identifier)
+                .ok_or_else(|| anyhow!("No identifier in {}", package_path))?;
+        self.set(&package_path, package.into_bytes());
+
+        let identifier = escape_xml(identifier);
+        for (name, data) in self.entries.iter_mut() {
+            if !name.ends_with(".ncx") {
+                continue;
+            }
+            if let Ok(ncx) = std::str::from_utf8(data) {
+                *data = ncx.replace(&previous, &identifier).into_bytes();
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the `dcterms:modified` date of an EPUB3 package document to `timestamp`.
+    pub(crate) fn set_modified(&mut self, timestamp: i64) -> Result<()> {
+        let package_path = self.package_path()?;
+        let package = self.get_str(&package_path)?;
+        let modified = ChronoDateTime::<Utc>::from_timestamp(timestamp, 0)
+            .unwrap_or_default()
+            .format("%Y-%m-%dT%H:%M:%SZ")
+            .to_string();
+
+        if let Some((package, _)) = replace_element_text(
+            package,
+            "<meta property=\"dcterms:modified\"",
+            "</meta>",
+            &modified,
+        ) {
+            self.set(&package_path, package.into_bytes());
+            Ok(())
+        } else if self.is_epub3()? {
+            let element = format!("    <meta property=\"dcterms:modified\">{modified}</meta>\n");
+            self.append_metadata(&element)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Adds the collection the book belongs to, as EPUB3 metadata and Calibre series.
+    pub(crate) fn set_series(&mut self, title: &str, index: f64) -> Result<()> {
+        let title = escape_xml(title);
+        let mut elements = String::new();
+        if self.is_epub3()? {
+            elements.push_str(&format!(
+                "    <meta property=\"belongs-to-collection\" id=\"mdbookshelf-collection\">{title}</meta>\n\
+                 \x20   <meta refines=\"#mdbookshelf-collection\" property=\"collection-type\">series</meta>\n\
+                 \x20   <meta refines=\"#mdbookshelf-collection\" property=\"group-position\">{index}</meta>\n"
+            ));
+        }
+        elements.push_str(&format!(
+            "    <meta name=\"calibre:series\" content=\"{title}\"/>\n\
+             \x20   <meta name=\"calibre:series_index\" content=\"{index}\"/>\n"
+        ));
+        self.append_metadata(&elements)
+    }
+
+    /// Returns whether the package document declares EPUB version 3.
+    pub(crate) fn is_epub3(&self) -> Result<bool> {
+        let package_path = self.package_path()?;
+        let package = self.get_str(&package_path)?;
+        let version = package
+            .find("<package")
+            .and_then(|start| attribute(&package[start..], "version"));
+        Ok(version.is_some_and(|version| version.starts_with('3')))
+    }
+
+    /// Returns whether the package document declares a cover image.
+    pub(crate) fn has_cover(&self) -> Result<bool> {
+        let package = self.get_str(&self.package_path()?)?;
+        Ok(package.contains("properties=\"cover-image\"")
+            || package.contains("<meta name=\"cover\""))
+    }
+
+    /// Returns the cover image declared in the package document, if any.
+    pub(crate) fn cover(&self) -> Result<Option<&[u8]>> {
+        let package_path = self.package_path()?;
+        let package = self.get_str(&package_path)?;
+        let id = package
+            .find("<meta name=\"cover\"")
+            .and_then(|start| attribute(&package[start..], "content"));
+        let item = manifest_items(package).find(|item| {
+            let properties = attribute(item, "properties").unwrap_or_default();
+            properties.split_whitespace().any(|p| p == "cover-image")
+                || (id.is_some() && attribute(item, "id") == id)
+        });
+        let Some(href) = item.and_then(|item| attribute(item, "href")) else {
+            return Ok(None);
+        };
+        Ok(self.get(&package_relative(&package_path, href)))
+    }
+
+    /// Adds `data` as the cover image of the book, next to the package document,
+    /// replacing the previously declared cover (if any).
+    pub(crate) fn set_cover(
+        &mut self,
+        file_name: &str,
+        media_type: &str,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let package_path = self.package_path()?;
+        let mut package = self
+            .get_str(&package_path)?
+            .replace(" properties=\"cover-image\"", "");
+        if let Some(start) = package.find("<meta name=\"cover\"") {
+            if let Some(end) = package[start..].find("/>") {
+                package.replace_range(start..start + end + 2, "");
+            }
+        }
+
+        self.set(&package_path, package.into_bytes());
+
+        let properties = self.is_epub3()?.then_some("cover-image");
+        self.add_item("mdbookshelf-cover", file_name, media_type, properties, data)?;
+        self.append_metadata("    <meta name=\"cover\" content=\"mdbookshelf-cover\"/>\n")
+    }
+
+    /// Adds `data` to the package manifest, at `href` relative to the package document.
+    pub(crate) fn add_item(
+        &mut self,
+        id: &str,
+        href: &str,
+        media_type: &str,
+        properties: Option<&str>,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let package_path = self.package_path()?;
+        let mut package = self.get_str(&package_path)?.to_owned();
+        let properties = properties
+            .map(|properties| format!(" properties=\"{properties}\""))
+            .unwrap_or_default();
+        let item = format!(
+            "    <item id=\"{id}\" href=\"{href}\" media-type=\"{media_type}\"{properties}/>\n"
+        );
+        let end = package
+            .find("</manifest>")
+            .ok_or_else(|| anyhow!("No manifest in {}", package_path))?;
+        package.insert_str(end, &item);
+        self.set(&package_path, package.into_bytes());
+        self.set(&package_relative(&package_path, href), data);
+        Ok(())
+    }
+
+    /// Appends `css` to the first stylesheet of the package manifest (the default
+    /// mdbook-epub one), or replaces its content if `replace` is set.
+    pub(crate) fn set_stylesheet(&mut self, css: &str, replace: bool) -> Result<()> {
+        let package_path = self.package_path()?;
+        let package = self.get_str(&package_path)?;
+        let href = manifest_items(package)
+            .find(|item| attribute(item, "media-type") == Some("text/css"))
+            .and_then(|item| attribute(item, "href"))
+            .ok_or_else(|| anyhow!("No stylesheet in {}", package_path))?;
+        let name = package_relative(&package_path, href);
+
+        let mut stylesheet = if replace {
+            String::new()
+        } else {
+            let mut stylesheet = self.get_str(&name)?.to_owned();
+            if !stylesheet.is_empty() && !stylesheet.ends_with('\n') {
+                stylesheet.push('\n');
+            }
+            stylesheet
+        };
+        stylesheet.push_str(css);
+        self.set(&name, stylesheet.into_bytes());
+        Ok(())
+    }
+
+    /// Names of all entries.
+    pub(crate) fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Mutable access to the content of all entries, with their names.
+    pub(crate) fn entries_mut(&mut self) -> impl Iterator<Item = (&str, &mut Vec<u8>)> {
+        self.entries
+            .iter_mut()
+            .map(|(name, data)| (name.as_str(), data))
+    }
+
+    /// Replaces the image entry `name` by `data`, with `extension` and `media_type`,
+    /// updating its manifest item and the references of the content documents and
+    /// stylesheets. A suffix is added to the new name if another entry already has it.
+    pub(crate) fn replace_image(
+        &mut self,
+        name: &str,
+        extension: &str,
+        media_type: &str,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let suffix = (0..)
+            .map(|i| {
+                if i == 0 {
+                    String::new()
+                } else {
+                    format!("-{i}")
+                }
+            })
+            .find(|suffix| {
+                let new_name = renamed(name, suffix, extension);
+                new_name == name || self.get(&new_name).is_none()
+            })
+            .unwrap_or_default();
+        let package_path = self.package_path()?;
+        let package = self.get_str(&package_path)?;
+        let item = manifest_items(package)
+            .find(|item| {
+                attribute(item, "href")
+                    .map(|href| resolve(&package_path, href))
+                    .as_deref()
+                    == Some(name)
+            })
+            .ok_or_else(|| anyhow!("No manifest item for {}", name))?;
+        let href = attribute(item, "href").unwrap_or_default();
+        let replaced = item
+            .replacen(
+                &format!("\"{href}\""),
+                &format!("\"{}\"", renamed(href, &suffix, extension)),
+                1,
+            )
+            .replacen(
+                attribute(item, "media-type").unwrap_or_default(),
+                media_type,
+                1,
+            );
+        let package = package.replacen(item, &replaced, 1);
+        self.set(&package_path, package.into_bytes());
+
+        for document in self.content_documents()? {
+            let mut xhtml = self.get_str(&document)?.to_owned();
+            for attribute in ["src", "xlink:href"] {
+                xhtml = replace_attribute(&xhtml, attribute, |value| {
+                    (resolve(&document, value) == name).then(|| renamed(value, &suffix, extension))
+                });
+            }
+            self.set(&document, xhtml.into_bytes());
+        }
+        for (stylesheet, data) in self.entries.iter_mut() {
+            if !stylesheet.ends_with(".css") {
+                continue;
+            }
+            if let Ok(css) = std::str::from_utf8(data) {
+                *data = replace_urls(css, |url| {
+                    (resolve(stylesheet, url) == name).then(|| renamed(url, &suffix, extension))
+                })
+                .into_bytes();
+            }
+        }
+
+        let new_name = renamed(name, &suffix, extension);
+        if let Some(entry) = self.entries.iter_mut().find(|(n, _)| n == name) {
+            *entry = (new_name, data);
+        }
+        Ok(())
+    }
+
+    /// Adds `property` to the EPUB3 manifest item of the content documents containing `element`.
+    pub(crate) fn add_property(&mut self, element: &str, property: &str) -> Result<()> {
+        if !self.is_epub3()? {
+            return Ok(());
+        }
+        let start_tag = format!("<{element}");
+        let documents: Vec<String> = self
+            .content_documents()?
+            .into_iter()
+            .filter(|name| {
+                self.get_str(name)
+                    .is_ok_and(|xhtml| xhtml.contains(&start_tag))
+            })
+            .collect();
+        let package_path = self.package_path()?;
+        let mut package = self.get_str(&package_path)?.to_owned();
+        let items: Vec<String> = manifest_items(&package)
+            .filter(|item| {
+                attribute(item, "href")
+                    .is_some_and(|href| documents.contains(&resolve(&package_path, href)))
+            })
+            .map(str::to_owned)
+            .collect();
+        for item in items {
+            let updated = match attribute(&item, "properties") {
+                Some(properties) if properties.split_whitespace().any(|p| p == property) => {
+                    continue
+                }
+                Some(properties) => item.replacen(
+                    &format!("properties=\"{properties}\""),
+                    &format!("properties=\"{properties} {property}\""),
+                    1,
+                ),
+                None => {
+                    let end = item
+                        .trim_end_matches('>')
+                        .trim_end_matches('/')
+                        .trim_end()
+                        .len();
+                    format!("{} properties=\"{property}\"{}", &item[..end], &item[end..])
+                }
+            };
+            package = package.replacen(&item, &updated, 1);
+        }
+        self.set(&package_path, package.into_bytes());
+        Ok(())
+    }
+
+    /// Names of the XHTML content documents, excluding the navigation document.
+    pub(crate) fn content_documents(&self) -> Result<Vec<String>> {
+        let package_path = self.package_path()?;
+        let package = self.get_str(&package_path)?;
+        Ok(manifest_items(package)
+            .filter(|item| attribute(item, "media-type") == Some("application/xhtml+xml"))
+            .filter(|item| {
+                let properties = attribute(item, "properties").unwrap_or_default();
+                !properties.split_whitespace().any(|p| p == "nav")
+            })
+            .filter_map(|item| attribute(item, "href"))
+            .map(|href| package_relative(&package_path, href))
+            .collect())
+    }
+
+    /// Removes the embedded fonts, their manifest items and the `@font-face` rules using them.
+    pub(crate) fn remove_fonts(&mut self) -> Result<()> {
+        let package_path = self.package_path()?;
+        let package = self.get_str(&package_path)?;
+        let fonts: Vec<&str> = manifest_items(package)
+            .filter(|item| is_font(item))
+            .collect();
+        if fonts.is_empty() {
+            return Ok(());
+        }
+
+        let names: Vec<String> = fonts
+            .iter()
+            .filter_map(|item| attribute(item, "href"))
+            .map(|href| package_relative(&package_path, href))
+            .collect();
+        let mut stripped = package.to_owned();
+        for item in fonts {
+            stripped = stripped.replacen(item, "", 1);
+        }
+        self.set(&package_path, stripped.into_bytes());
+        self.entries.retain(|(name, _)| !names.contains(name));
+
+        for (name, data) in self.entries.iter_mut() {
+            if !name.ends_with(".css") {
+                continue;
+            }
+            if let Ok(css) = std::str::from_utf8(data) {
+                *data = remove_font_faces(css).into_bytes();
+            }
+        }
+        Ok(())
+    }
+
+    /// Inserts `elements` at the end of the `<metadata>` of the package document.
+    pub(crate) fn append_metadata(&mut self, elements: &str) -> Result<()> {
+        let package_path = self.package_path()?;
+        let package = self.get_str(&package_path)?;
+        let end = package
+            .find("</metadata>")
+            .ok_or_else(|| anyhow!("No metadata in {}", package_path))?;
+        let package = format!("{}{}{}", &package[..end], elements, &package[end..]);
+        self.set(&package_path, package.into_bytes());
+        Ok(())
+    }
+}
+
+/// Converts `timestamp` (seconds since epoch) to a zip timestamp.
+pub(crate) fn zip_datetime(timestamp: i64) -> DateTime {
+    let datetime = ChronoDateTime::<Utc>::from_timestamp(timestamp, 0).unwrap_or_default();
+    // Zip timestamps can't represent dates before 1980
+    u16::try_from(datetime.year())
+        .ok()
+        .and_then(|year| {
+            DateTime::from_date_and_time(
+                year,
+                datetime.month() as u8,
+                datetime.day() as u8,
+                datetime.hour() as u8,
+                datetime.minute() as u8,
+                datetime.second() as u8,
+            )
+            .ok()
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the `<item>` elements of the package manifest.
+fn manifest_items(package: &str) -> impl Iterator<Item = &str> {
+    package.match_indices("<item ").map(|(start, _)| {
+        let end = package[start..]
+            .find('>')
+            .map_or(package.len(), |end| start + end + 1);
+        &package[start..end]
+    })
+}
+
+/// Returns whether the manifest `item` is a font.
+fn is_font(item: &str) -> bool {
+    let media_type = attribute(item, "media-type").unwrap_or_default();
+    let href = attribute(item, "href").unwrap_or_default().to_lowercase();
+    media_type.starts_with("font/")
+        || media_type.starts_with("application/font-")
+        || media_type.starts_with("application/x-font-")
+        || media_type == "application/vnd.ms-opentype"
+        || [".ttf", ".otf", ".woff", ".woff2"]
+            .iter()
+            .any(|extension| href.ends_with(extension))
+}
+
+/// Removes the `@font-face` rules of `css`.
+fn remove_font_faces(css: &str) -> String {
+    let mut css = css.to_owned();
+    while let Some(start) = css.find("@font-face") {
+        let end = css[start..]
+            .find('}')
+            .map_or(css.len(), |end| start + end + 1);
+        css.replace_range(start..end, "");
+    }
+    css
+}
+
+/// Replaces the extension of the file at `path`.
+fn with_extension(path: &str, extension: &str) -> String {
+    match path.rfind('.').filter(|&dot| !path[dot..].contains('/')) {
+        Some(dot) => format!("{}.{}", &path[..dot], extension),
+        None => format!("{path}.{extension}"),
+    }
+}
+
+/// Returns `path` with its extension replaced by `extension`, and `suffix` appended
+/// to its stem.
+fn renamed(path: &str, suffix: &str, extension: &str) -> String {
+    let path = with_extension(path, extension);
+    let dot = path.len() - extension.len() - 1;
+    format!("{}{}{}", &path[..dot], suffix, &path[dot..])
+}
+
+/// Replaces the values of the `url()` functions of `css` for which `replace` returns
+/// a new value.
+fn replace_urls(css: &str, mut replace: impl FnMut(&str) -> Option<String>) -> String {
+    let mut output = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("url(") {
+        let value_start = start + "url(".len();
+        let Some(end) = rest[value_start..].find(')').map(|end| value_start + end) else {
+            break;
+        };
+        let value = rest[value_start..end].trim();
+        let unquoted = value.trim_matches(|c| c == '"' || c == '\'');
+        output.push_str(&rest[..value_start]);
+        match replace(unquoted) {
+            Some(url) => output.push_str(&value.replacen(unquoted, &url, 1)),
+            None => output.push_str(&rest[value_start..end]),
+        }
+        rest = &rest[end..];
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Resolves `href` relative to the package document at `package_path`.
+fn package_relative(package_path: &str, href: &str) -> String {
+    match package_path.rfind('/') {
+        Some(end) => format!("{}/{}", &package_path[..end], href),
+        None => href.to_owned(),
+    }
+}
+
+/// Returns the value of attribute `name` of the first element in `xml`.
+pub(crate) fn attribute<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let element = &xml[..xml.find('>')?];
+    let start = element.find(&format!(" {}=", name))? + name.len() + 2;
+    let quote = element[start..].chars().next()?;
+    let value = &element[start + 1..];
+    Some(&value[..value.find(quote)?])
+}
+
+/// Replaces the content of the first element starting with `start_tag` in `xml`.
+/// Returns the updated document and the previous (escaped) content.
+fn replace_element_text(
+    xml: &str,
+    start_tag: &str,
+    end_tag: &str,
+    text: &str,
+) -> Option<(String, String)> {
+    let start = xml.find(start_tag)?;
+    let start = start + xml[start..].find('>')? + 1;
+    let end = start + xml[start..].find(end_tag)?;
+    let replaced = format!("{}{}{}", &xml[..start], escape_xml(text), &xml[end..]);
+    Some((replaced, xml[start..end].to_owned()))
+}
+
+/// Escapes `text` for use in XML content or attribute values.
+pub(crate) fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Formats a Dublin Core metadata element.
+pub(crate) fn dc_element(name: &str, value: &str) -> String {
+    format!("    <dc:{name}>{}</dc:{name}>\n", escape_xml(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTAINER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#;
+
+    const PACKAGE: &str =
+        r#"<package version="3.0"><metadata><dc:title>A</dc:title></metadata></package>"#;
+
+    const PACKAGE_WITH_ID: &str = r#"<package version="3.0" unique-identifier="id"><metadata>
+<dc:identifier id="id">urn:uuid:random</dc:identifier>
+<meta property="dcterms:modified">2024-01-01T00:00:00Z</meta>
+</metadata></package>"#;
+
+    fn new_epub() -> Epub {
+        let mut epub = Epub::default();
+        epub.set("mimetype", b"application/epub+zip".to_vec());
+        epub.set(CONTAINER_PATH, CONTAINER.as_bytes().to_vec());
+        epub.set("OEBPS/content.opf", PACKAGE.as_bytes().to_vec());
+        epub
+    }
+
+    #[test]
+    fn test_append_metadata() {
+        let mut epub = new_epub();
+        assert_eq!(epub.package_path().unwrap(), "OEBPS/content.opf");
+
+        epub.append_metadata(&dc_element("publisher", "R&D"))
+            .unwrap();
+        assert_eq!(
+            epub.get_str("OEBPS/content.opf").unwrap(),
+            "<package version=\"3.0\"><metadata><dc:title>A</dc:title>    \
+             <dc:publisher>R&amp;D</dc:publisher>\n</metadata></package>"
+        );
+    }
+
+    #[test]
+    fn test_set_identifier_and_modified() {
+        let mut epub = new_epub();
+        epub.set("OEBPS/content.opf", PACKAGE_WITH_ID.as_bytes().to_vec());
+        epub.set(
+            "OEBPS/toc.ncx",
+            br#"<meta name="dtb:uid" content="urn:uuid:random"/>"#.to_vec(),
+        );
+
+        epub.set_identifier("urn:uuid:stable").unwrap();
+        epub.set_modified(0).unwrap();
+        assert_eq!(
+            epub.get_str("OEBPS/content.opf").unwrap(),
+            r#"<package version="3.0" unique-identifier="id"><metadata>
+<dc:identifier id="id">urn:uuid:stable</dc:identifier>
+<meta property="dcterms:modified">1970-01-01T00:00:00Z</meta>
+</metadata></package>"#
+        );
+        assert_eq!(
+            epub.get_str("OEBPS/toc.ncx").unwrap(),
+            r#"<meta name="dtb:uid" content="urn:uuid:stable"/>"#
+        );
+
+        // Added when missing
+        let mut epub = new_epub();
+        epub.set_modified(0).unwrap();
+        assert!(epub
+            .get_str("OEBPS/content.opf")
+            .unwrap()
+            .contains("<meta property=\"dcterms:modified\">1970-01-01T00:00:00Z</meta>"));
+    }
+
+    #[test]
+    fn test_set_series() {
+        let mut epub = new_epub();
+        epub.set_series("Rust & Co", 2.5).unwrap();
+        let package = epub.get_str("OEBPS/content.opf").unwrap();
+        assert!(package.contains(
+            "<meta property=\"belongs-to-collection\" id=\"mdbookshelf-collection\">Rust &amp; Co</meta>"
+        ));
+        assert!(package.contains(
+            "<meta refines=\"#mdbookshelf-collection\" property=\"group-position\">2.5</meta>"
+        ));
+        assert!(package.contains("<meta name=\"calibre:series\" content=\"Rust &amp; Co\"/>"));
+        assert!(package.contains("<meta name=\"calibre:series_index\" content=\"2.5\"/>"));
+
+        let mut epub = new_epub();
+        epub.set(
+            "OEBPS/content.opf",
+            PACKAGE.replace("3.0", "2.0").into_bytes(),
+        );
+        epub.set_series("Rust", 1.0).unwrap();
+        let package = epub.get_str("OEBPS/content.opf").unwrap();
+        assert!(!package.contains("belongs-to-collection"));
+        assert!(package.contains("<meta name=\"calibre:series_index\" content=\"1\"/>"));
+    }
+
+    #[test]
+    fn test_set_cover() {
+        let mut epub = new_epub();
+        epub.set(
+            "OEBPS/content.opf",
+            br#"<package version="3.0"><metadata><meta name="cover" content="old"/></metadata><manifest>
+    <item id="old" href="old.png" media-type="image/png" properties="cover-image"/>
+</manifest></package>"#
+                .to_vec(),
+        );
+        assert!(epub.has_cover().unwrap());
+
+        epub.set_cover("cover.png", "image/png", b"png".to_vec())
+            .unwrap();
+
+        let package = epub.get_str("OEBPS/content.opf").unwrap();
+        assert_eq!(package.matches("cover-image").count(), 1);
+        assert_eq!(package.matches("<meta name=\"cover\"").count(), 1);
+        assert!(package.contains(
+            "<item id=\"mdbookshelf-cover\" href=\"cover.png\" media-type=\"image/png\" properties=\"cover-image\"/>"
+        ));
+        assert!(package.contains("<meta name=\"cover\" content=\"mdbookshelf-cover\"/>"));
+        assert_eq!(epub.get("OEBPS/cover.png").unwrap(), b"png");
+        assert_eq!(epub.cover().unwrap().unwrap(), b"png");
+        assert!(!new_epub().has_cover().unwrap());
+        assert!(new_epub().cover().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_replace_image() {
+        let mut epub = new_epub();
+        epub.set(
+            "OEBPS/content.opf",
+            br#"<package version="3.0"><manifest>
+    <item id="ch1" href="text/ch1.xhtml" media-type="application/xhtml+xml"/>
+    <item id="diagram" href="img/diagram.svg" media-type="image/svg+xml"/>
+</manifest></package>"#
+                .to_vec(),
+        );
+        epub.set(
+            "OEBPS/text/ch1.xhtml",
+            br#"<img src="../img/diagram.svg"/><img src="diagram.svg"/>"#.to_vec(),
+        );
+        epub.set("OEBPS/img/diagram.svg", b"<svg/>".to_vec());
+
+        epub.replace_image("OEBPS/img/diagram.svg", "png", "image/png", b"png".to_vec())
+            .unwrap();
+
+        let package = epub.get_str("OEBPS/content.opf").unwrap();
+        assert!(package
+            .contains(r#"<item id="diagram" href="img/diagram.png" media-type="image/png"/>"#));
+        assert_eq!(
+            epub.get_str("OEBPS/text/ch1.xhtml").unwrap(),
+            r#"<img src="../img/diagram.png"/><img src="diagram.svg"/>"#
+        );
+        assert!(epub.get("OEBPS/img/diagram.svg").is_none());
+        assert_eq!(epub.get("OEBPS/img/diagram.png").unwrap(), b"png");
+        assert_eq!(with_extension("a.b/c", "png"), "a.b/c.png");
+    }
+
+    #[test]
+    fn test_replace_image_with_existing_name() {
+        let mut epub = new_epub();
+        epub.set(
+            "OEBPS/content.opf",
+            br#"<package version="3.0"><manifest>
+    <item id="logo-png" href="logo.png" media-type="image/png"/>
+    <item id="logo-svg" href="logo.svg" media-type="image/svg+xml"/>
+    <item id="css" href="style/main.css" media-type="text/css"/>
+</manifest></package>"#
+                .to_vec(),
+        );
+        epub.set("OEBPS/logo.png", b"png".to_vec());
+        epub.set("OEBPS/logo.svg", b"<svg/>".to_vec());
+        epub.set(
+            "OEBPS/style/main.css",
+            b"h1 { background: url(\"../logo.svg\"); }\nh2 { background: url(../logo.png); }\n"
+                .to_vec(),
+        );
+
+        epub.replace_image("OEBPS/logo.svg", "png", "image/png", b"svg png".to_vec())
+            .unwrap();
+
+        let package = epub.get_str("OEBPS/content.opf").unwrap();
+        assert!(
+            package.contains(r#"<item id="logo-svg" href="logo-1.png" media-type="image/png"/>"#)
+        );
+        assert_eq!(epub.get("OEBPS/logo.png").unwrap(), b"png");
+        assert_eq!(epub.get("OEBPS/logo-1.png").unwrap(), b"svg png");
+        assert_eq!(
+            epub.get_str("OEBPS/style/main.css").unwrap(),
+            "h1 { background: url(\"../logo-1.png\"); }\nh2 { background: url(../logo.png); }\n"
+        );
+    }
+
+    #[test]
+    fn test_add_property() {
+        let mut epub = new_epub();
+        epub.set(
+            "OEBPS/content.opf",
+            br#"<package version="3.0"><manifest>
+    <item id="ch1" href="ch1.xhtml" media-type="application/xhtml+xml"/>
+    <item id="ch2" href="ch2.xhtml" media-type="application/xhtml+xml" properties="svg"/>
+    <item id="ch3" href="ch3.xhtml" media-type="application/xhtml+xml"/>
+</manifest></package>"#
+                .to_vec(),
+        );
+        epub.set(
+            "OEBPS/ch1.xhtml",
+            b"<p><math><mi>x</mi></math></p>".to_vec(),
+        );
+        epub.set("OEBPS/ch2.xhtml", b"<math display=\"block\"/>".to_vec());
+        epub.set("OEBPS/ch3.xhtml", b"<p>mathematics</p>".to_vec());
+
+        epub.add_property("math", "mathml").unwrap();
+
+        let package = epub.get_str("OEBPS/content.opf").unwrap();
+        assert!(package.contains(
+            r#"<item id="ch1" href="ch1.xhtml" media-type="application/xhtml+xml" properties="mathml"/>"#
+        ));
+        assert!(package.contains(r#"properties="svg mathml"/>"#));
+        assert!(package
+            .contains(r#"<item id="ch3" href="ch3.xhtml" media-type="application/xhtml+xml"/>"#));
+    }
+
+    #[test]
+    fn test_set_stylesheet() {
+        let mut epub = new_epub();
+        epub.set(
+            "OEBPS/content.opf",
+            br#"<package version="3.0"><metadata/><manifest>
+    <item id="cover" href="cover.png" media-type="image/png"/>
+    <item id="css" href="style/main.css" media-type="text/css"/>
+</manifest></package>"#
+                .to_vec(),
+        );
+        epub.set("OEBPS/style/main.css", b"body { margin: 0; }".to_vec());
+
+        epub.set_stylesheet("pre { white-space: pre-wrap; }\n", false)
+            .unwrap();
+        assert_eq!(
+            epub.get_str("OEBPS/style/main.css").unwrap(),
+            "body { margin: 0; }\npre { white-space: pre-wrap; }\n"
+        );
+
+        epub.set_stylesheet("p { margin: 0; }\n", true).unwrap();
+        assert_eq!(
+            epub.get_str("OEBPS/style/main.css").unwrap(),
+            "p { margin: 0; }\n"
+        );
+
+        assert!(new_epub().set_stylesheet("p {}", false).is_err());
+    }
+
+    #[test]
+    fn test_content_documents_and_remove_fonts() {
+        let mut epub = new_epub();
+        epub.set(
+            "OEBPS/content.opf",
+            br#"<package version="3.0"><metadata/><manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="c1" href="chapter_001.xhtml" media-type="application/xhtml+xml"/>
+    <item id="css" href="stylesheet.css" media-type="text/css"/>
+    <item id="font" href="fonts/FiraCode.woff2" media-type="font/woff2"/>
+</manifest></package>"#
+                .to_vec(),
+        );
+        epub.set(
+            "OEBPS/stylesheet.css",
+            b"@font-face { font-family: Fira; src: url(fonts/FiraCode.woff2); }\nbody { margin: 0; }\n"
+                .to_vec(),
+        );
+        epub.set("OEBPS/fonts/FiraCode.woff2", b"woff2".to_vec());
+
+        assert_eq!(
+            epub.content_documents().unwrap(),
+            vec![String::from("OEBPS/chapter_001.xhtml")]
+        );
+
+        epub.remove_fonts().unwrap();
+        let package = epub.get_str("OEBPS/content.opf").unwrap();
+        assert!(!package.contains("woff2"));
+        assert!(package.contains("stylesheet.css"));
+        assert!(epub.get("OEBPS/fonts/FiraCode.woff2").is_none());
+        assert_eq!(
+            epub.get_str("OEBPS/stylesheet.css").unwrap(),
+            "\nbody { margin: 0; }\n"
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("book.epub");
+        let mut epub = new_epub();
+        epub.set("OEBPS/b.xhtml", b"<html/>".to_vec());
+        epub.set("OEBPS/a.xhtml", b"<html/>".to_vec());
+        epub.normalize(1_700_000_000);
+        epub.save(&path).unwrap();
+
+        let mut archive = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let names: Vec<&str> = archive.file_names().collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names[0], "mimetype");
+        assert_eq!(names[1..], sorted[..sorted.len() - 1]);
+        let modified = archive.by_name("OEBPS/a.xhtml").unwrap().last_modified();
+        assert_eq!(
+            modified,
+            DateTime::from_date_and_time(2023, 11, 14, 22, 13, 20).ok()
+        );
+    }
+
+    #[test]
+    fn test_save_and_open() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("book.epub");
+        let mut epub = new_epub();
+        epub.set("OEBPS/chapter_1.xhtml", b"<html/>".to_vec());
+        epub.save(&path).unwrap();
+
+        let mut archive = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let mimetype = archive.by_index(0).unwrap();
+        assert_eq!(mimetype.name(), "mimetype");
+        assert_eq!(mimetype.compression(), CompressionMethod::Stored);
+        drop(mimetype);
+
+        let got = Epub::open(&path).unwrap();
+        assert_eq!(got.entries, epub.entries);
+    }
+}
@@ -0,0 +1,193 @@
+//! Additional formats generated next to the EPUBs.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[double]
+use crate::book::Book;
+use crate::book::BookOptions;
+use crate::epub::zip_datetime;
+use crate::kepub;
+use anyhow::{bail, Result};
+use mockall_double::double;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// A format a book can be exported to, besides EPUB.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Format {
+    /// EPUB with Kobo extensions (`.kepub.epub`)
+    Kepub,
+    /// The mdbook HTML site, zipped (`.html.zip`)
+    HtmlZip,
+    /// A single self-contained HTML file (`.html`)
+    Html,
+    /// The chapters concatenated in a single Markdown file (`.md`)
+    Markdown,
+    /// FictionBook 2 (`.fb2`)
+    Fb2,
+    /// FictionBook 2, zipped (`.fb2.zip`)
+    Fb2Zip,
+}
+
+impl Format {
+    /// All the formats, by name.
+    const ALL: &'static [Format] = &[
+        Format::Kepub,
+        Format::HtmlZip,
+        Format::Html,
+        Format::Markdown,
+        Format::Fb2,
+        Format::Fb2Zip,
+    ];
+
+    /// The name used in `bookshelf.toml` and in the manifest.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Format::Kepub => "kepub",
+            Format::HtmlZip => "html-zip",
+            Format::Html => "html",
+            Format::Markdown => "markdown",
+            Format::Fb2 => "fb2",
+            Format::Fb2Zip => "fb2-zip",
+        }
+    }
+
+    /// Whether the format is rendered from the book loaded to generate the EPUB,
+    /// by `Book::generate_epub`.
+    pub(crate) fn is_rendered_with_epub(self) -> bool {
+        matches!(
+            self,
+            Format::Html | Format::Markdown | Format::Fb2 | Format::Fb2Zip
+        )
+    }
+
+    /// Returns the format called `name`.
+    pub(crate) fn from_name(name: &str) -> Option<Format> {
+        Format::ALL
+            .iter()
+            .copied()
+            .find(|format| format.name() == name)
+    }
+
+    /// The path of the file exported from the EPUB at `epub_path`.
+    pub(crate) fn output_path(self, epub_path: &Path) -> PathBuf {
+        let stem = epub_path.with_extension("");
+        match self {
+            Format::Kepub => PathBuf::from(format!("{}.kepub.epub", stem.display())),
+            Format::HtmlZip => PathBuf::from(format!("{}.html.zip", stem.display())),
+            Format::Html => PathBuf::from(format!("{}.html", stem.display())),
+            Format::Markdown => PathBuf::from(format!("{}.md", stem.display())),
+            Format::Fb2 => PathBuf::from(format!("{}.fb2", stem.display())),
+            Format::Fb2Zip => PathBuf::from(format!("{}.fb2.zip", stem.display())),
+        }
+    }
+}
+
+/// Exports the book at `book_path`, already generated as the EPUB at `epub_file`,
+/// to `output_file` in `format`.
+pub(crate) fn export(
+    format: Format,
+    book_path: &Path,
+    options: &BookOptions,
+    epub_file: &Path,
+    output_file: &Path,
+) -> Result<()> {
+    match format {
+        Format::Kepub => kepub::convert(epub_file, output_file, options.cross_links.as_ref()),
+        Format::HtmlZip => Book::generate_html_zip(book_path, options, output_file),
+        Format::Html | Format::Markdown | Format::Fb2 | Format::Fb2Zip => {
+            bail!("{} is rendered while generating the EPUB", format.name())
+        }
+    }
+}
+
+/// Zips the content of `dir` into `output_file`, in a reproducible order.
+/// All entries get `source_date` (seconds since epoch) as modification time if set.
+pub(crate) fn zip_dir(dir: &Path, output_file: &Path, source_date: Option<i64>) -> Result<()> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let name = entry.path().strip_prefix(dir)?;
+        // Zip entries always use forward slashes
+        let name: Vec<_> = name.iter().map(|part| part.to_string_lossy()).collect();
+        files.push((name.join("/"), std::fs::read(entry.path())?));
+    }
+    zip_files(files, output_file, source_date)
+}
+
+/// Zips `files` (name, content) into `output_file`.
+/// All entries get `source_date` (seconds since epoch) as modification time if set.
+pub(crate) fn zip_files(
+    files: Vec<(String, Vec<u8>)>,
+    output_file: &Path,
+    source_date: Option<i64>,
+) -> Result<()> {
+    let mut zip = ZipWriter::new(File::create(output_file)?);
+    let mut options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    if let Some(timestamp) = source_date {
+        options = options.last_modified_time(zip_datetime(timestamp));
+    }
+    for (name, data) in files {
+        zip.start_file(name, options)?;
+        zip.write_all(&data)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zip_dir() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(dir.path().join("css")).unwrap();
+        std::fs::write(dir.path().join("index.html"), "<html/>").unwrap();
+        std::fs::write(dir.path().join("css").join("book.css"), "p {}").unwrap();
+        let output = tempfile::TempDir::new().unwrap();
+        let output_file = output.path().join("book.html.zip");
+
+        zip_dir(dir.path(), &output_file, Some(1_700_000_000)).unwrap();
+
+        let mut archive = zip::ZipArchive::new(File::open(&output_file).unwrap()).unwrap();
+        let names: Vec<&str> = archive.file_names().collect();
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&"css/book.css"));
+        let file = archive.by_name("index.html").unwrap();
+        assert_eq!(file.last_modified().unwrap().year(), 2023);
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(Format::from_name("kepub"), Some(Format::Kepub));
+        assert_eq!(Format::from_name("mobi"), None);
+        assert_eq!(
+            Format::Kepub.output_path(Path::new("Hello Rust.epub")),
+            Path::new("Hello Rust.kepub.epub")
+        );
+        assert_eq!(Format::from_name("html-zip"), Some(Format::HtmlZip));
+        assert_eq!(
+            Format::HtmlZip.output_path(Path::new("Hello Rust.epub")),
+            Path::new("Hello Rust.html.zip")
+        );
+        assert_eq!(
+            Format::Markdown.output_path(Path::new("Hello Rust.epub")),
+            Path::new("Hello Rust.md")
+        );
+        assert_eq!(
+            Format::Fb2Zip.output_path(Path::new("Hello Rust.epub")),
+            Path::new("Hello Rust.fb2.zip")
+        );
+        assert!(Format::Html.is_rendered_with_epub());
+        assert!(!Format::Kepub.is_rendered_with_epub());
+    }
+}
@@ -0,0 +1,229 @@
+//! EPUB-safe fallbacks of the HTML-only constructs of the chapters (embeds, videos,
+//! collapsible blocks and playground buttons), which e-readers don't display.
+
+use std::ops::Range;
+
+use mdbook::book::{Book, BookItem};
+use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+use mdbook::utils::new_cmark_parser;
+use pulldown_cmark::{CodeBlockKind, Event, Tag};
+use serde::{Deserialize, Serialize};
+
+use crate::epub::{attribute, escape_xml};
+
+/// Markup replacing the HTML-only constructs, `{url}` and `{title}` being substituted.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Fallbacks {
+    /// Replaces the `<iframe>` embeds
+    pub embed: String,
+    /// Replaces the `<video>` elements
+    pub video: String,
+    /// Follows the Rust code blocks which have a playground button in HTML
+    pub playground: String,
+}
+
+impl Default for Fallbacks {
+    fn default() -> Self {
+        Fallbacks {
+            embed: String::from(
+                r#"<p class="fallback fallback-embed">Embedded content: <a href="{url}">{title}</a></p>"#,
+            ),
+            video: String::from(
+                r#"<p class="fallback fallback-video">Video: <a href="{url}">{title}</a></p>"#,
+            ),
+            playground: String::from(concat!(
+                r#"<p class="fallback fallback-playground">This example can be run in the Rust Playground, "#,
+                r#"<a href="https://play.rust-lang.org/">play.rust-lang.org</a>.</p>"#
+            )),
+        }
+    }
+}
+
+/// mdbook preprocessor replacing the HTML-only constructs of the chapters of the EPUB.
+pub(crate) struct FallbacksPreprocessor {
+    pub fallbacks: Fallbacks,
+}
+
+impl Preprocessor for FallbacksPreprocessor {
+    fn name(&self) -> &str {
+        "mdbookshelf-fallbacks"
+    }
+
+    fn run(&self, _ctx: &PreprocessorContext, mut book: Book) -> mdbook::errors::Result<Book> {
+        book.for_each_mut(|item| {
+            if let BookItem::Chapter(chapter) = item {
+                let content = add_playground_notes(&chapter.content, &self.fallbacks.playground);
+                chapter.content = replace_elements(&content, &self.fallbacks);
+            }
+        });
+        Ok(book)
+    }
+
+    fn supports_renderer(&self, renderer: &str) -> bool {
+        renderer == "epub"
+    }
+}
+
+/// Code block attributes which disable the playground button.
+const NOT_RUNNABLE: &[&str] = &["ignore", "noplayground", "noplaypen"];
+
+/// Appends the `note` HTML block to the Rust code blocks of `content` mdbook adds
+/// a playground button to.
+fn add_playground_notes(content: &str, note: &str) -> String {
+    let mut output = String::with_capacity(content.len());
+    let mut copied = 0;
+    for (event, range) in new_cmark_parser(content, false).into_offset_iter() {
+        let Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) = event else {
+            continue;
+        };
+        let mut attributes = info.split([',', ' ', '\t']);
+        if attributes.next() != Some("rust")
+            || attributes.any(|attribute| NOT_RUNNABLE.contains(&attribute))
+        {
+            continue;
+        }
+        // Container markers (list indentation, `>`) must prefix every line
+        let line_start = content[..range.start].rfind('\n').map_or(0, |i| i + 1);
+        let prefix = &content[line_start..range.start];
+        let end = content[range.end..]
+            .strip_prefix('\n')
+            .map_or(range.end, |_| range.end + 1);
+        output.push_str(&content[copied..end]);
+        if !output.ends_with('\n') {
+            output.push('\n');
+        }
+        // Blank lines around the HTML block, so that it doesn't swallow the next paragraph
+        output.push_str(&format!(
+            "{blank}\n{prefix}{note}\n{blank}\n",
+            blank = prefix.trim_end()
+        ));
+        copied = end;
+    }
+    output.push_str(&content[copied..]);
+    output
+}
+
+/// Replaces the `<iframe>`, `<video>`, `<details>` and `<button>` elements of `content`,
+/// outside of code.
+fn replace_elements(content: &str, fallbacks: &Fallbacks) -> String {
+    let code: Vec<Range<usize>> = new_cmark_parser(content, false)
+        .into_offset_iter()
+        .filter(|(event, _)| matches!(event, Event::Code(_) | Event::Start(Tag::CodeBlock(_))))
+        .map(|(_, range)| range)
+        .collect();
+
+    let mut output = String::with_capacity(content.len());
+    let mut i = 0;
+    let mut copied = 0;
+    while let Some(start) = content[i..].find('<').map(|start| i + start) {
+        if let Some(range) = code.iter().find(|range| range.contains(&start)) {
+            i = range.end;
+            continue;
+        }
+        let rest = &content[start..];
+        let replacement = if let Some(end) = element_end(rest, "iframe") {
+            Some((end, link(&fallbacks.embed, &rest[..end], None)))
+        } else if let Some(end) = element_end(rest, "video") {
+            let source = rest[..end].find("<source").map(|source| &rest[source..end]);
+            Some((end, link(&fallbacks.video, &rest[..end], source)))
+        } else if let Some(end) = element_end(rest, "button") {
+            Some((end, String::new()))
+        } else if let Some(end) = start_tag_end(rest, "details") {
+            Some((end, String::from(r#"<div class="details">"#)))
+        } else if let Some(end) = start_tag_end(rest, "summary") {
+            Some((end, String::from(r#"<p class="summary"><strong>"#)))
+        } else if rest.starts_with("</details>") {
+            Some(("</details>".len(), String::from("</div>")))
+        } else if rest.starts_with("</summary>") {
+            Some(("</summary>".len(), String::from("</strong></p>")))
+        } else {
+            None
+        };
+        match replacement {
+            Some((end, replacement)) => {
+                output.push_str(&content[copied..start]);
+                output.push_str(&replacement);
+                i = start + end;
+                copied = i;
+            }
+            None => i = start + 1,
+        }
+    }
+    output.push_str(&content[copied..]);
+    output
+}
+
+/// Returns the length of the start tag of element `name` starting `html`, if any.
+fn start_tag_end(html: &str, name: &str) -> Option<usize> {
+    let rest = html.strip_prefix('<')?.strip_prefix(name)?;
+    if !rest.starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/') {
+        return None;
+    }
+    Some(html.find('>')? + 1)
+}
+
+/// Returns the length of the element `name` starting `html`, up to its end tag, if any.
+fn element_end(html: &str, name: &str) -> Option<usize> {
+    let start_end = start_tag_end(html, name)?;
+    if html[..start_end].ends_with("/>") {
+        return Some(start_end);
+    }
+    let end_tag = format!("</{name}>");
+    let end = start_end + html[start_end..].find(&end_tag)? + end_tag.len();
+    Some(end)
+}
+
+/// Renders `template` for the `element` linking to its `src` (or the `source` one),
+/// titled by its `title` attribute or its URL. Elements without URL are dropped.
+fn link(template: &str, element: &str, source: Option<&str>) -> String {
+    let Some(url) =
+        attribute(element, "src").or_else(|| source.and_then(|source| attribute(source, "src")))
+    else {
+        return String::new();
+    };
+    let url = url.replace("&amp;", "&");
+    let title = attribute(element, "title")
+        .map(|title| title.replace("&amp;", "&"))
+        .unwrap_or_else(|| url.clone());
+    template
+        .replace("{url}", &escape_xml(&url))
+        .replace("{title}", &escape_xml(&title))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_playground_notes() {
+        let content = "```rust\nfn main() {}\n```\nNext\n\n```rust,ignore\nfn main() {}\n```\n\n> ```rust,editable\n> fn main() {}\n> ```\n";
+        assert_eq!(
+            add_playground_notes(content, "<p>Note</p>"),
+            "```rust\nfn main() {}\n```\n\n<p>Note</p>\n\nNext\n\n```rust,ignore\nfn main() {}\n```\n\n> ```rust,editable\n> fn main() {}\n> ```\n>\n> <p>Note</p>\n>\n"
+        );
+    }
+
+    #[test]
+    fn test_replace_elements() {
+        let fallbacks = Fallbacks {
+            embed: String::from(r#"<a href="{url}">{title}</a>"#),
+            video: String::from(r#"<a class="video" href="{url}">{title}</a>"#),
+            ..Default::default()
+        };
+        let content = concat!(
+            "<iframe src=\"https://play.rust-lang.org/?code=a&amp;b\" title=\"Playground\"></iframe>\n\n",
+            "<video controls><source src=\"demo.mp4\" type=\"video/mp4\"/>No video</video>\n\n",
+            "<details open>\n<summary>More</summary>\n\nText <button class=\"play\">Run</button>\n</details>\n\n",
+            "`<iframe src=\"code\"></iframe>`\n\n<iframes/>\n"
+        );
+        assert_eq!(
+            replace_elements(content, &fallbacks),
+            concat!(
+                "<a href=\"https://play.rust-lang.org/?code=a&amp;b\">Playground</a>\n\n",
+                "<a class=\"video\" href=\"demo.mp4\">demo.mp4</a>\n\n",
+                "<div class=\"details\">\n<p class=\"summary\"><strong>More</strong></p>\n\nText \n</div>\n\n",
+                "`<iframe src=\"code\"></iframe>`\n\n<iframes/>\n"
+            )
+        );
+    }
+}
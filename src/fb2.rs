@@ -0,0 +1,420 @@
+//! Rendering of books to FictionBook 2 (FB2), the format of many e-reader apps.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use chrono::{DateTime, Utc};
+use mdbook::book::{BookItem, Chapter};
+use mdbook::MDBook;
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+
+use crate::epub::escape_xml;
+use crate::single_file;
+
+/// Renders the chapters of `md` to an FB2 document, as nested sections following
+/// `SUMMARY.md`, with the images embedded as binaries.
+pub(crate) fn render(md: &MDBook, identifier: Option<&str>, date: DateTime<Utc>) -> Result<String> {
+    let (book, _) = md.preprocess_book(&Fb2Renderer)?;
+    let mut writer = Writer {
+        source_dir: md.source_dir(),
+        ..Default::default()
+    };
+    for item in &book.sections {
+        writer.item(item);
+    }
+
+    let config = &md.config.book;
+    let title = config.title.as_deref().unwrap_or_default();
+    let mut authors: String = config.authors.iter().map(|name| author(name)).collect();
+    if authors.is_empty() {
+        authors = author("Unknown");
+    }
+    let binaries: String = writer
+        .binaries
+        .iter()
+        .map(|(id, media_type, data)| {
+            format!(
+                "<binary id=\"{id}\" content-type=\"{media_type}\">{}</binary>\n",
+                STANDARD.encode(data)
+            )
+        })
+        .collect();
+    let date = date.format("%Y-%m-%d");
+
+    Ok(format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <FictionBook xmlns=\"http://www.gribuser.ru/xml/fictionbook/2.0\" \
+         xmlns:l=\"http://www.w3.org/1999/xlink\">\n\
+         <description>\n<title-info>\n<genre>computers</genre>\n{authors}\
+         <book-title>{title}</book-title>\n{annotation}<lang>{lang}</lang>\n</title-info>\n\
+         <document-info>\n{authors}<program-used>mdbookshelf {version}</program-used>\n\
+         <date value=\"{date}\">{date}</date>\n<id>{id}</id>\n<version>1.0</version>\n\
+         </document-info>\n</description>\n\
+         <body>\n<title><p>{title}</p></title>\n{body}</body>\n{binaries}</FictionBook>\n",
+        title = escape_xml(title),
+        annotation = config
+            .description
+            .as_deref()
+            .map(|description| format!(
+                "<annotation><p>{}</p></annotation>\n",
+                escape_xml(description)
+            ))
+            .unwrap_or_default(),
+        lang = escape_xml(config.language.as_deref().unwrap_or("en")),
+        version = env!("CARGO_PKG_VERSION"),
+        id = escape_xml(identifier.unwrap_or(title)),
+        body = writer.output,
+    ))
+}
+
+/// The renderer name preprocessors see while rendering FB2.
+struct Fb2Renderer;
+
+impl mdbook::Renderer for Fb2Renderer {
+    fn name(&self) -> &str {
+        "fb2"
+    }
+
+    fn render(&self, _ctx: &mdbook::renderer::RenderContext) -> mdbook::errors::Result<()> {
+        Ok(())
+    }
+}
+
+/// Formats an FB2 `<author>`, splitting `name` in first and last names.
+fn author(name: &str) -> String {
+    match name.trim().rsplit_once(' ') {
+        Some((first, last)) => format!(
+            "<author><first-name>{}</first-name><last-name>{}</last-name></author>\n",
+            escape_xml(first.trim()),
+            escape_xml(last)
+        ),
+        None => format!(
+            "<author><nickname>{}</nickname></author>\n",
+            escape_xml(name)
+        ),
+    }
+}
+
+/// Writes the FB2 body of a book.
+#[derive(Default)]
+struct Writer {
+    /// The book source directory, to load the images from
+    source_dir: PathBuf,
+    /// The FB2 body
+    output: String,
+    /// The embedded images (id, media type, data)
+    binaries: Vec<(String, &'static str, Vec<u8>)>,
+    /// The binary ids of the images, by path
+    image_ids: HashMap<PathBuf, String>,
+    /// A paragraph-like element is open
+    paragraph: bool,
+    /// A code block is being written
+    code: bool,
+    /// The alt text of an embedded image is being skipped
+    skip_text: bool,
+    /// The first cell of the current table row is being written
+    first_cell: bool,
+    /// The numbers of the next items of the open lists (`None` if unordered)
+    lists: Vec<Option<u64>>,
+}
+
+impl Writer {
+    /// Writes a chapter, or a part title, and its sub chapters as nested sections.
+    fn item(&mut self, item: &BookItem) {
+        match item {
+            BookItem::Chapter(chapter) => self.chapter(chapter),
+            BookItem::PartTitle(title) => self.output.push_str(&format!(
+                "<section>\n<title><p>{}</p></title>\n<empty-line/>\n</section>\n",
+                escape_xml(title)
+            )),
+            BookItem::Separator => {}
+        }
+    }
+
+    fn chapter(&mut self, chapter: &Chapter) {
+        self.output.push_str(&format!(
+            "<section>\n<title><p>{}</p></title>\n",
+            escape_xml(&chapter.name)
+        ));
+        // Sections contain either content or other sections
+        let nested = !chapter.sub_items.is_empty();
+        if nested {
+            self.output.push_str("<section>\n");
+        }
+        let dir = chapter
+            .path
+            .as_deref()
+            .and_then(Path::parent)
+            .map(|dir| self.source_dir.join(dir))
+            .unwrap_or_else(|| self.source_dir.clone());
+        let length = self.output.len();
+        self.markdown(&chapter.content, &chapter.name, &dir);
+        if self.output.len() == length {
+            self.output.push_str("<empty-line/>\n");
+        }
+        if nested {
+            self.output.push_str("</section>\n");
+        }
+        for item in &chapter.sub_items {
+            self.item(item);
+        }
+        self.output.push_str("</section>\n");
+    }
+
+    /// Writes the Markdown `content` of the chapter `name`, loading images from `dir`.
+    fn markdown(&mut self, content: &str, name: &str, dir: &Path) {
+        let mut events = Parser::new_ext(
+            content,
+            Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH,
+        )
+        .peekable();
+
+        // The chapter name is already the section title
+        if let Some(Event::Start(Tag::Heading { .. })) = events.peek() {
+            let heading: Vec<_> = events
+                .by_ref()
+                .take_while(|event| !matches!(event, Event::End(TagEnd::Heading(_))))
+                .collect();
+            let text: String = heading
+                .iter()
+                .filter_map(|event| match event {
+                    Event::Text(text) | Event::Code(text) => Some(text.as_ref()),
+                    _ => None,
+                })
+                .collect();
+            if text.trim() != name.trim() {
+                for event in heading {
+                    self.event(event, dir);
+                }
+                self.close_paragraph("</subtitle>");
+            }
+        }
+        for event in events {
+            self.event(event, dir);
+        }
+        self.close_paragraph("</p>");
+    }
+
+    fn event(&mut self, event: Event, dir: &Path) {
+        match event {
+            Event::Start(tag) => self.start(tag, dir),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) if self.code => {
+                for line in text.lines() {
+                    self.code_line(line);
+                }
+            }
+            Event::Text(text) => {
+                if !self.skip_text {
+                    self.text(&escape_xml(&text));
+                }
+            }
+            Event::Code(code) => self.text(&format!("<code>{}</code>", escape_xml(&code))),
+            Event::SoftBreak => self.text(" "),
+            Event::HardBreak => {
+                self.close_paragraph("</p>");
+                self.open_paragraph();
+            }
+            Event::Rule => {
+                self.close_paragraph("</p>");
+                self.output.push_str("<empty-line/>\n");
+            }
+            Event::TaskListMarker(checked) => self.text(if checked { "[x] " } else { "[ ] " }),
+            Event::Html(_) | Event::InlineHtml(_) | Event::FootnoteReference(_) => {}
+        }
+    }
+
+    fn start(&mut self, tag: Tag, dir: &Path) {
+        match tag {
+            Tag::Paragraph => self.open_paragraph(),
+            Tag::Heading { .. } => {
+                self.close_paragraph("</p>");
+                self.output.push_str("<subtitle>");
+                self.paragraph = true;
+            }
+            Tag::BlockQuote => {
+                self.close_paragraph("</p>");
+                self.output.push_str("<cite>\n");
+            }
+            Tag::CodeBlock(_) => {
+                self.close_paragraph("</p>");
+                self.code = true;
+            }
+            Tag::List(start) => {
+                self.close_paragraph("</p>");
+                self.lists.push(start);
+            }
+            Tag::Item => {
+                self.close_paragraph("</p>");
+                self.open_paragraph();
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => String::from("• "),
+                };
+                self.output.push_str(&marker);
+            }
+            Tag::TableHead | Tag::TableRow => {
+                self.open_paragraph();
+                self.first_cell = true;
+            }
+            Tag::TableCell => {
+                if !self.first_cell {
+                    self.output.push_str(" | ");
+                }
+                self.first_cell = false;
+            }
+            Tag::Emphasis => self.text("<emphasis>"),
+            Tag::Strong => self.text("<strong>"),
+            Tag::Strikethrough => self.text("<strikethrough>"),
+            Tag::Link { dest_url, .. } => {
+                // Links to other chapters can't be followed in a single document
+                let href = if dest_url.contains("://") || dest_url.starts_with("mailto:") {
+                    escape_xml(&dest_url)
+                } else {
+                    String::new()
+                };
+                self.text(&format!("<a l:href=\"{href}\">"));
+            }
+            Tag::Image { dest_url, .. } => {
+                if let Some(id) = self.image(&dest_url, dir) {
+                    self.text(&format!("<image l:href=\"#{id}\"/>"));
+                    self.skip_text = true;
+                }
+            }
+            Tag::HtmlBlock | Tag::FootnoteDefinition(_) | Tag::Table(_) | Tag::MetadataBlock(_) => {
+            }
+        }
+    }
+
+    fn end(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Paragraph | TagEnd::Item | TagEnd::TableHead | TagEnd::TableRow => {
+                self.close_paragraph("</p>")
+            }
+            TagEnd::Heading(_) => self.close_paragraph("</subtitle>"),
+            TagEnd::BlockQuote => {
+                self.close_paragraph("</p>");
+                self.output.push_str("</cite>\n");
+            }
+            TagEnd::List(_) => {
+                self.lists.pop();
+            }
+            TagEnd::Emphasis => self.text("</emphasis>"),
+            TagEnd::Strong => self.text("</strong>"),
+            TagEnd::Strikethrough => self.text("</strikethrough>"),
+            TagEnd::Link => self.text("</a>"),
+            TagEnd::Image => self.skip_text = false,
+            TagEnd::CodeBlock => self.code = false,
+            TagEnd::HtmlBlock
+            | TagEnd::FootnoteDefinition
+            | TagEnd::Table
+            | TagEnd::TableCell
+            | TagEnd::MetadataBlock(_) => {}
+        }
+    }
+
+    /// Opens a paragraph, unless one is already open.
+    fn open_paragraph(&mut self) {
+        if !self.paragraph {
+            self.output.push_str("<p>");
+            self.paragraph = true;
+        }
+    }
+
+    /// Closes the open paragraph-like element with `end`.
+    fn close_paragraph(&mut self, end: &str) {
+        if self.paragraph {
+            self.output.push_str(end);
+            self.output.push('\n');
+            self.paragraph = false;
+        }
+    }
+
+    /// Writes inline `xml`, opening a paragraph if needed.
+    fn text(&mut self, xml: &str) {
+        self.open_paragraph();
+        self.output.push_str(xml);
+    }
+
+    /// Writes a line of a code block as a paragraph, keeping its indentation.
+    fn code_line(&mut self, line: &str) {
+        if line.trim().is_empty() {
+            self.output.push_str("<empty-line/>\n");
+            return;
+        }
+        let content = line.trim_start();
+        let indent = "\u{a0}".repeat(line.len() - content.len());
+        self.output.push_str(&format!(
+            "<p><code>{indent}{}</code></p>\n",
+            escape_xml(content)
+        ));
+    }
+
+    /// Embeds the image at `url` (relative to `dir`) and returns its binary id.
+    /// Returns `None` for remote, missing or unsupported images.
+    fn image(&mut self, url: &str, dir: &Path) -> Option<String> {
+        if url.contains("://") {
+            return None;
+        }
+        let path = dir.join(url);
+        if let Some(id) = self.image_ids.get(&path) {
+            return Some(id.to_owned());
+        }
+        let media_type = single_file::media_type(url)
+            .filter(|media_type| ["image/png", "image/jpeg", "image/gif"].contains(media_type))?;
+        let data = std::fs::read(&path).ok()?;
+        let extension = path.extension()?.to_string_lossy().to_lowercase();
+        let id = format!("image{}.{extension}", self.binaries.len() + 1);
+        self.binaries.push((id.clone(), media_type, data));
+        self.image_ids.insert(path, id.clone());
+        Some(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_markdown(content: &str, name: &str) -> String {
+        let mut writer = Writer::default();
+        writer.markdown(content, name, Path::new("."));
+        writer.output
+    }
+
+    #[test]
+    fn test_author() {
+        assert_eq!(
+            author("Steve Klabnik"),
+            "<author><first-name>Steve</first-name><last-name>Klabnik</last-name></author>\n"
+        );
+        assert_eq!(
+            author("rams3s"),
+            "<author><nickname>rams3s</nickname></author>\n"
+        );
+    }
+
+    #[test]
+    fn test_markdown() {
+        let content = "# Intro\n\nSome *text* and `code`.\n\n## Next\n\n- one\n- two\n\n```rust\nfn main() {\n    ok();\n}\n```\n";
+
+        assert_eq!(
+            render_markdown(content, "Intro"),
+            concat!(
+                "<p>Some <emphasis>text</emphasis> and <code>code</code>.</p>\n",
+                "<subtitle>Next</subtitle>\n",
+                "<p>• one</p>\n",
+                "<p>• two</p>\n",
+                "<p><code>fn main() {</code></p>\n",
+                "<p><code>\u{a0}\u{a0}\u{a0}\u{a0}ok();</code></p>\n",
+                "<p><code>}</code></p>\n",
+            )
+        );
+        assert!(render_markdown(content, "Other").starts_with("<subtitle>Intro</subtitle>\n"));
+    }
+}
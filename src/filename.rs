@@ -0,0 +1,160 @@
+//! Output filename patterns.
+
+/// Default pattern, matching the filename chosen by mdbook-epub.
+pub(crate) const DEFAULT_PATTERN: &str = "{title}.epub";
+
+/// Values substituted to the placeholders of an output filename pattern.
+#[derive(Debug, Default)]
+pub(crate) struct Placeholders<'a> {
+    /// `{title}`
+    pub title: &'a str,
+    /// `{version}`
+    pub version: &'a str,
+    /// `{sha}`
+    pub sha: &'a str,
+    /// `{profile}`
+    pub profile: &'a str,
+}
+
+/// Expands the placeholders of `pattern` and sanitizes the result:
+/// `{title}`, `{slug}` (lowercase title with dashes), `{version}`,
+/// `{sha}`, `{short_sha}` (first 7 characters of the commit sha) and `{profile}`.
+pub(crate) fn expand(pattern: &str, placeholders: &Placeholders) -> String {
+    let short_sha = &placeholders.sha[..placeholders.sha.len().min(7)];
+    // A single pass, so that placeholders in the substituted values are kept as is
+    let mut filename = String::with_capacity(pattern.len());
+    let mut rest = pattern;
+    while let Some(open) = rest.find('{') {
+        filename.push_str(&rest[..open]);
+        rest = &rest[open..];
+        let Some(close) = rest.find('}') else {
+            break;
+        };
+        match &rest[1..close] {
+            "title" => filename.push_str(placeholders.title),
+            "slug" => filename.push_str(&slugify(placeholders.title)),
+            "version" => filename.push_str(placeholders.version),
+            "short_sha" => filename.push_str(short_sha),
+            "sha" => filename.push_str(placeholders.sha),
+            "profile" => filename.push_str(placeholders.profile),
+            _ => {
+                filename.push('{');
+                rest = &rest[1..];
+                continue;
+            }
+        }
+        rest = &rest[close + 1..];
+    }
+    filename.push_str(rest);
+    sanitize(&filename)
+}
+
+/// Adds the `{profile}` placeholder to `pattern`, before its `.epub` extension.
+pub(crate) fn with_profile(pattern: &str) -> String {
+    match pattern.strip_suffix(".epub") {
+        Some(stem) => format!("{stem}-{{profile}}.epub"),
+        None => format!("{pattern}-{{profile}}"),
+    }
+}
+
+/// Removes the `{profile}` placeholder from `pattern`, with the separator before it.
+pub(crate) fn without_profile(pattern: &str) -> String {
+    ["-{profile}", "_{profile}", ".{profile}", "{profile}"]
+        .iter()
+        .fold(pattern.to_owned(), |pattern, placeholder| {
+            pattern.replace(placeholder, "")
+        })
+}
+
+/// Replaces characters which are not allowed in filenames on common platforms.
+pub(crate) fn sanitize(filename: &str) -> String {
+    let sanitized: String = filename
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let sanitized = sanitized.trim_matches(|c: char| c == '.' || c.is_whitespace());
+    if sanitized.is_empty() {
+        String::from("book")
+    } else {
+        sanitized.to_owned()
+    }
+}
+
+/// Lowercases `text` and replaces every run of non alphanumeric characters with a dash.
+pub(crate) fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let placeholders = Placeholders {
+            title: "Rust: By Example",
+            version: "v1.0",
+            sha: "0123456789abcdef",
+            profile: "kobo",
+        };
+        assert_eq!(
+            expand(DEFAULT_PATTERN, &placeholders),
+            "Rust_ By Example.epub"
+        );
+        assert_eq!(
+            expand("{slug}-{version}-{short_sha}.epub", &placeholders),
+            "rust-by-example-v1.0-0123456.epub"
+        );
+        assert_eq!(
+            expand(&with_profile("{slug}.epub"), &placeholders),
+            "rust-by-example-kobo.epub"
+        );
+        assert_eq!(expand("{title}", &Placeholders::default()), "book");
+
+        let placeholders = Placeholders {
+            title: "Intro to {sha}",
+            sha: "0123456789abcdef",
+            ..Default::default()
+        };
+        assert_eq!(
+            expand("{title} {short_sha}.epub", &placeholders),
+            "Intro to {sha} 0123456.epub"
+        );
+        assert_eq!(expand("{unknown}.epub", &placeholders), "{unknown}.epub");
+    }
+
+    #[test]
+    fn test_with_profile() {
+        assert_eq!(with_profile("{title}.epub"), "{title}-{profile}.epub");
+        assert_eq!(with_profile("{title}"), "{title}-{profile}");
+    }
+
+    #[test]
+    fn test_without_profile() {
+        assert_eq!(without_profile("{title}-{profile}.epub"), "{title}.epub");
+        assert_eq!(without_profile("{slug}_{profile}.epub"), "{slug}.epub");
+        assert_eq!(without_profile("{title}.epub"), "{title}.epub");
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(
+            sanitize("a/b\\c:d*e?f\"g<h>i|j.epub"),
+            "a_b_c_d_e_f_g_h_i_j.epub"
+        );
+        assert_eq!(sanitize("../secret"), "_secret");
+        assert_eq!(sanitize(" .. "), "book");
+    }
+}
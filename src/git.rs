@@ -1,7 +1,7 @@
 use std::path::{Path, PathBuf};
 
 use chrono::{TimeZone, Utc};
-use git2::Repository;
+use git2::{DescribeFormatOptions, DescribeOptions, Repository};
 use log::{info, trace};
 #[cfg(test)]
 use mockall::automock;
@@ -22,10 +22,11 @@ impl GitOp for Repo {
 
 pub(crate) trait GitOp {
     /// Clones or fetches the repo at `entry.repo_url` inside `working_dir`.
+    /// Returns the repo path, the commit sha, its datetime and the version described by git.
     fn clone_or_fetch_repo(
         url: &str,
         working_dir: &Path,
-    ) -> anyhow::Result<(PathBuf, String, String)> {
+    ) -> anyhow::Result<(PathBuf, String, String, String)> {
         let repo_path = if let Ok(parsed_url) = Url::parse(url) {
             trace!("Repo url parsed: {}", parsed_url);
             // skip initial `/` in path
@@ -64,8 +65,17 @@ pub(crate) trait GitOp {
         let commit_seconds = commit.time().seconds();
         let commit_sha = commit.id().to_string();
         let last_modified = Utc.timestamp_opt(commit_seconds, 0).unwrap().to_rfc3339();
-
-        Ok((dest, commit_sha, last_modified))
+        // Most recent tag, falling back to the abbreviated commit sha
+        let version = repo
+            .describe(
+                DescribeOptions::new()
+                    .describe_tags()
+                    .show_commit_oid_as_fallback(true),
+            )
+            .and_then(|d| d.format(Some(DescribeFormatOptions::new().abbreviated_size(7))))
+            .unwrap_or_else(|_| commit_sha[..7].to_owned());
+
+        Ok((dest, commit_sha, last_modified, version))
     }
 
     fn open(path: PathBuf) -> Result<Repository, git2::Error>;
@@ -98,7 +108,7 @@ mod tests {
             }
         }
 
-        let (got_dest, _, _) = RepoTest::clone_or_fetch_repo(url, dest.path()).unwrap();
+        let (got_dest, _, _, _) = RepoTest::clone_or_fetch_repo(url, dest.path()).unwrap();
         assert_eq!(got_dest, expect_repo_dir);
     }
 
@@ -121,6 +131,27 @@ mod tests {
         assert_cloned_repo_dir(src, dest.path(), &expect_repo_dir);
     }
 
+    #[test]
+    fn test_describe_version() {
+        struct RepoTest;
+        impl GitOp for RepoTest {
+            fn open(_path: PathBuf) -> Result<Repository, git2::Error> {
+                Err(git2::Error::from_str("YOU SHALL NOT OPEN"))
+            }
+            fn clone(_url: &str, _into: PathBuf) -> Result<Repository, git2::Error> {
+                let repo = crate::tests::repo_init(&_into)?;
+                {
+                    let head = repo.head()?.peel_to_commit()?;
+                    repo.tag_lightweight("v1.0.0", head.as_object(), false)?;
+                }
+                Ok(repo)
+            }
+        }
+        let dest = TempDir::new().unwrap();
+        let (_, _, _, version) = RepoTest::clone_or_fetch_repo("tagged", dest.path()).unwrap();
+        assert_eq!(version, "v1.0.0");
+    }
+
     fn assert_cloned_repo_dir(src: &str, dest: &Path, expect_repo_dir: &Path) {
         struct RepoTest;
         impl GitOp for RepoTest {
@@ -131,7 +162,7 @@ mod tests {
                 crate::tests::repo_init(&_into)
             }
         }
-        let (got_dest, _sha, _date) = RepoTest::clone_or_fetch_repo(src, dest).unwrap();
+        let (got_dest, _sha, _date, _version) = RepoTest::clone_or_fetch_repo(src, dest).unwrap();
         assert_eq!(got_dest, expect_repo_dir);
     }
 }
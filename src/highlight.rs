@@ -0,0 +1,267 @@
+//! Syntax highlighting of the code blocks, which e-readers can't run highlight.js for.
+
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Result};
+use mdbook::book::{Book, BookItem};
+use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+use mdbook::utils::new_cmark_parser;
+use pulldown_cmark::{CodeBlockKind, Event, Tag, TagEnd};
+use serde::{Deserialize, Serialize};
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::html::{ClassStyle, ClassedHTMLGenerator};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+/// Theme used when neither the shelf nor the profile chooses one.
+pub(crate) const DEFAULT_THEME: &str = "InspiredGitHub";
+
+/// Prefix of the CSS classes, so that they don't clash with the book ones.
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+/// Settings of the code highlighting.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Highlight {
+    /// The syntect theme name
+    pub theme: String,
+    /// Style the code with CSS classes (see [`Highlight::css`]) instead of inline styles
+    pub classes: bool,
+}
+
+impl Highlight {
+    /// The stylesheet of the theme, when styling with CSS classes.
+    pub(crate) fn css(&self) -> Result<String> {
+        Ok(syntect::html::css_for_theme_with_class_style(
+            theme(&self.theme)?,
+            CLASS_STYLE,
+        )?)
+    }
+
+    /// Renders the fenced code blocks of the Markdown `content` to highlighted HTML.
+    fn highlight(&self, content: &str) -> Result<String> {
+        let theme = theme(&self.theme)?;
+        let syntax_set = syntax_set();
+        replace_code_blocks(content, |lang, code| {
+            let syntax = syntax_set
+                .find_syntax_by_token(lang)
+                .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+            if self.classes {
+                let mut generator =
+                    ClassedHTMLGenerator::new_with_class_style(syntax, syntax_set, CLASS_STYLE);
+                for line in LinesWithEndings::from(code) {
+                    generator.parse_html_for_line_which_includes_newline(line)?;
+                }
+                Ok(format!(
+                    "<pre class=\"hl-code\"><code>{}</code></pre>",
+                    generator.finalize()
+                ))
+            } else {
+                let html =
+                    syntect::html::highlighted_html_for_string(code, syntax_set, syntax, theme)?;
+                // XHTML keeps the newline following `<pre>`, unlike HTML
+                Ok(html.trim_end().replacen(";\">\n", ";\">", 1))
+            }
+        })
+    }
+}
+
+/// Returns whether syntect knows the theme called `name`.
+pub(crate) fn has_theme(name: &str) -> bool {
+    theme(name).is_ok()
+}
+
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme(name: &str) -> Result<&'static Theme> {
+    static THEME_SET: OnceLock<ThemeSet> = OnceLock::new();
+    THEME_SET
+        .get_or_init(ThemeSet::load_defaults)
+        .themes
+        .get(name)
+        .ok_or_else(|| anyhow!("Unknown highlight theme {}", name))
+}
+
+/// mdbook preprocessor highlighting the code blocks for the HTML based renderers,
+/// and stripping the hidden lines of the Rust code blocks for the others.
+pub(crate) struct HighlightPreprocessor {
+    pub highlight: Highlight,
+}
+
+impl Preprocessor for HighlightPreprocessor {
+    fn name(&self) -> &str {
+        "mdbookshelf-highlight"
+    }
+
+    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> mdbook::errors::Result<Book> {
+        let html = matches!(ctx.renderer.as_str(), "epub" | "html-single");
+        let mut result = Ok(());
+        book.for_each_mut(|item| {
+            let BookItem::Chapter(chapter) = item else {
+                return;
+            };
+            let content = if html {
+                self.highlight.highlight(&chapter.content)
+            } else {
+                replace_code_blocks(&chapter.content, |_, code| Ok(code.to_owned()))
+            };
+            match content {
+                Ok(content) => chapter.content = content,
+                Err(e) => result = Err(e),
+            }
+        });
+        result.map(|_| book)
+    }
+
+    fn supports_renderer(&self, renderer: &str) -> bool {
+        // Not the mdbook site ("html"), which highlights with highlight.js
+        matches!(renderer, "epub" | "html-single" | "fb2" | "markdown")
+    }
+}
+
+/// Replaces the fenced code blocks of `content` by `render(lang, code)`, the hidden
+/// lines of the Rust code being stripped. Blocks rendered to the same code are kept as is.
+fn replace_code_blocks(
+    content: &str,
+    render: impl Fn(&str, &str) -> Result<String>,
+) -> Result<String> {
+    let mut output = String::with_capacity(content.len());
+    let mut end = 0;
+    let mut block: Option<(usize, String, String)> = None;
+    for (event, range) in new_cmark_parser(content, false).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                let lang = info
+                    .split([',', ' ', '\t'])
+                    .next()
+                    .unwrap_or_default()
+                    .to_owned();
+                block = Some((range.start, lang, String::new()));
+            }
+            Event::Text(text) => {
+                if let Some((_, _, code)) = &mut block {
+                    code.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                let Some((start, lang, code)) = block.take() else {
+                    continue;
+                };
+                let stripped = if lang == "rust" {
+                    strip_hidden_lines(&code)
+                } else {
+                    code.clone()
+                };
+                let rendered = render(&lang, &stripped)?;
+                if rendered == code {
+                    continue;
+                }
+                // Container markers (list indentation, `>`) must prefix every line
+                let line_start = content[..start].rfind('\n').map_or(0, |i| i + 1);
+                let prefix = &content[line_start..start];
+                let source = &content[start..range.end];
+                output.push_str(&content[end..start]);
+                if rendered == stripped {
+                    // Keep a fenced block, with the opening and closing fence lines
+                    let opening = source.lines().next().unwrap_or_default();
+                    let fence: String = opening
+                        .chars()
+                        .take_while(|&c| c == opening.chars().next().unwrap_or('`'))
+                        .collect();
+                    output.push_str(opening);
+                    output.push('\n');
+                    for line in stripped.lines() {
+                        output.push_str(prefix);
+                        output.push_str(line);
+                        output.push('\n');
+                    }
+                    output.push_str(prefix);
+                    output.push_str(&fence);
+                } else {
+                    output.push_str(&rendered.replace('\n', &format!("\n{prefix}")));
+                }
+                if source.ends_with('\n') {
+                    output.push('\n');
+                }
+                end = range.end;
+            }
+            _ => {}
+        }
+    }
+    output.push_str(&content[end..]);
+    Ok(output)
+}
+
+/// Removes the lines mdbook hides in Rust code blocks (starting with `# `),
+/// and unescapes the lines starting with `##`.
+fn strip_hidden_lines(code: &str) -> String {
+    let mut stripped = String::with_capacity(code.len());
+    for line in LinesWithEndings::from(code) {
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+        if trimmed.starts_with("##") {
+            stripped.push_str(indent);
+            stripped.push_str(&trimmed[1..]);
+        } else if !trimmed.starts_with("# ") && trimmed.trim_end() != "#" {
+            stripped.push_str(line);
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAPTER: &str = "# Hello\n\n```rust,editable\n# use std::io;\nfn main() {\n    ## not hidden\n}\n```\n\n- item\n\n  ```toml\n  a = 1\n  ```\n";
+
+    #[test]
+    fn test_strip_hidden_lines() {
+        assert_eq!(
+            strip_hidden_lines(
+                "# use std::io;\n#\n#![allow(unused)]\n    # hidden\n  ##[derive]\n"
+            ),
+            "#![allow(unused)]\n  #[derive]\n"
+        );
+    }
+
+    #[test]
+    fn test_replace_code_blocks() {
+        let stripped = replace_code_blocks(CHAPTER, |_, code| Ok(code.to_owned())).unwrap();
+        assert_eq!(
+            stripped,
+            "# Hello\n\n```rust,editable\nfn main() {\n    # not hidden\n}\n```\n\n- item\n\n  ```toml\n  a = 1\n  ```\n"
+        );
+
+        let replaced = replace_code_blocks(CHAPTER, |lang, code| {
+            Ok(format!("<pre>{lang}\n{code}</pre>"))
+        })
+        .unwrap();
+        assert!(replaced.contains("<pre>rust\nfn main() {\n    # not hidden\n}\n</pre>\n"));
+        assert!(replaced.ends_with("- item\n\n  <pre>toml\n  a = 1\n  </pre>\n"));
+    }
+
+    #[test]
+    fn test_highlight() {
+        let highlight = Highlight {
+            theme: String::from(DEFAULT_THEME),
+            classes: false,
+        };
+        let html = highlight.highlight(CHAPTER).unwrap();
+        assert!(html.contains("<pre style=\"background-color:#ffffff;\"><span"));
+        assert!(!html.contains("use std::io"));
+        assert!(!html.contains("```"));
+
+        let highlight = Highlight {
+            classes: true,
+            ..highlight
+        };
+        let html = highlight.highlight(CHAPTER).unwrap();
+        assert!(html.contains("<pre class=\"hl-code\"><code><span class=\"hl-source hl-rust\">"));
+        assert!(highlight.css().unwrap().contains(".hl-code"));
+        assert!(has_theme("Solarized (light)"));
+        assert!(!has_theme("Monokai"));
+    }
+}
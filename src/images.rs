@@ -0,0 +1,118 @@
+//! Processing of the images embedded in the generated EPUBs.
+
+use std::io::Cursor;
+
+use anyhow::Result;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder};
+use image::imageops::FilterType;
+use image::ImageFormat;
+
+/// Downscales the PNG or JPEG image `data` to fit in `max_size` (width, height),
+/// keeping its format and aspect ratio. Returns `None` if the image already fits
+/// or has another format.
+pub(crate) fn fit(data: &[u8], max_size: (u32, u32)) -> Result<Option<Vec<u8>>> {
+    let format = match image::guess_format(data) {
+        Ok(format @ (ImageFormat::Png | ImageFormat::Jpeg)) => format,
+        _ => return Ok(None),
+    };
+    let image = image::load_from_memory_with_format(data, format)?;
+    let (max_width, max_height) = max_size;
+    if image.width() <= max_width && image.height() <= max_height {
+        return Ok(None);
+    }
+
+    let resized = image.resize(max_width, max_height, FilterType::Lanczos3);
+    let mut output = Cursor::new(Vec::new());
+    resized.write_to(&mut output, format)?;
+    Ok(Some(output.into_inner()))
+}
+
+/// Re-encodes the PNG image `data` with the best compression, or the JPEG image `data`
+/// at `jpeg_quality` if set. Returns `None` if the result is not smaller or the image
+/// has another format.
+pub(crate) fn recompress(data: &[u8], jpeg_quality: Option<u8>) -> Result<Option<Vec<u8>>> {
+    let format = match image::guess_format(data) {
+        Ok(format @ (ImageFormat::Png | ImageFormat::Jpeg)) => format,
+        _ => return Ok(None),
+    };
+    let image = image::load_from_memory_with_format(data, format)?;
+    let mut output = Vec::new();
+    if format == ImageFormat::Png {
+        image.write_with_encoder(PngEncoder::new_with_quality(
+            &mut output,
+            CompressionType::Best,
+            PngFilterType::Adaptive,
+        ))?;
+    } else if let Some(quality) = jpeg_quality {
+        image.write_with_encoder(JpegEncoder::new_with_quality(&mut output, quality))?;
+    } else {
+        return Ok(None);
+    }
+    Ok((output.len() < data.len()).then_some(output))
+}
+
+/// Returns whether the entry `name` of an EPUB is an SVG image.
+pub(crate) fn is_svg(name: &str) -> bool {
+    name.to_lowercase().ends_with(".svg")
+}
+
+/// Returns whether the entry `name` of an EPUB is a PNG or JPEG image.
+pub(crate) fn is_raster_image(name: &str) -> bool {
+    let name = name.to_lowercase();
+    [".png", ".jpg", ".jpeg"]
+        .iter()
+        .any(|extension| name.ends_with(extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Cursor::new(Vec::new());
+        image::RgbImage::new(width, height)
+            .write_to(&mut data, ImageFormat::Png)
+            .unwrap();
+        data.into_inner()
+    }
+
+    #[test]
+    fn test_fit() {
+        assert!(fit(&png(100, 50), (100, 100)).unwrap().is_none());
+        assert!(fit(b"<svg/>", (10, 10)).unwrap().is_none());
+
+        let resized = fit(&png(400, 200), (100, 100)).unwrap().unwrap();
+        let image = image::load_from_memory(&resized).unwrap();
+        assert_eq!((image.width(), image.height()), (100, 50));
+        assert_eq!(image::guess_format(&resized).unwrap(), ImageFormat::Png);
+    }
+
+    #[test]
+    fn test_recompress() {
+        let mut data = Cursor::new(Vec::new());
+        image::RgbImage::new(200, 200)
+            .write_with_encoder(PngEncoder::new_with_quality(
+                &mut data,
+                CompressionType::Fast,
+                PngFilterType::NoFilter,
+            ))
+            .unwrap();
+        let data = data.into_inner();
+
+        let recompressed = recompress(&data, None).unwrap().unwrap();
+        assert!(recompressed.len() < data.len());
+        let image = image::load_from_memory(&recompressed).unwrap();
+        assert_eq!((image.width(), image.height()), (200, 200));
+        assert!(recompress(&recompressed, None).unwrap().is_none());
+        assert!(recompress(b"<svg/>", Some(80)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_is_raster_image() {
+        assert!(is_raster_image("OEBPS/img/a.PNG"));
+        assert!(is_raster_image("OEBPS/b.jpeg"));
+        assert!(!is_raster_image("OEBPS/c.svg"));
+        assert!(is_svg("OEBPS/c.SVG"));
+    }
+}
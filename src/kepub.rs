@@ -0,0 +1,208 @@
+//! Conversion of EPUBs to KEPUBs, the EPUB flavour Kobo readers compute reading
+//! statistics and page turns with.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::cross_links::CrossLinks;
+use crate::epub::Epub;
+
+/// Styles added by Kobo's own conversion, so that the book columns don't get extra margins.
+const KOBO_STYLES: &str = r#"<style type="text/css" id="kobostylehacks">div#book-inner { margin-top: 0; margin-bottom: 0; }</style>"#;
+
+/// Elements starting a new paragraph in the `koboSpan` ids.
+const BLOCK_ELEMENTS: &[&str] = &[
+    "blockquote",
+    "dd",
+    "div",
+    "dt",
+    "figcaption",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "li",
+    "p",
+    "pre",
+    "td",
+    "th",
+];
+
+/// Elements whose text must not be wrapped.
+const RAW_ELEMENTS: &[&str] = &["math", "script", "style", "svg"];
+
+/// Converts the EPUB at `epub_file` into a KEPUB at `output_file`, pointing the
+/// `cross_links` to the other books to their KEPUB when they have one.
+pub(crate) fn convert(
+    epub_file: &Path,
+    output_file: &Path,
+    cross_links: Option<&CrossLinks>,
+) -> Result<()> {
+    let mut epub = Epub::open(epub_file)?;
+    for name in epub.content_documents()? {
+        let mut xhtml = koboize(epub.get_str(&name)?);
+        if let Some(cross_links) = cross_links {
+            xhtml = cross_links.to_kepub(&xhtml);
+        }
+        epub.set(&name, xhtml.into_bytes());
+    }
+    log::info!("Generated kepub into {}", output_file.display());
+    epub.save(output_file)
+}
+
+/// Wraps the sentences of an XHTML document in `koboSpan`s, wraps its body in
+/// the Kobo book columns and adds the Kobo styles.
+pub(crate) fn koboize(xhtml: &str) -> String {
+    let Some(body_start) = xhtml.find("<body") else {
+        return xhtml.to_owned();
+    };
+    let Some(content_start) = xhtml[body_start..]
+        .find('>')
+        .map(|end| body_start + end + 1)
+    else {
+        return xhtml.to_owned();
+    };
+    let content_end = xhtml
+        .rfind("</body>")
+        .unwrap_or(xhtml.len())
+        .max(content_start);
+
+    let head = xhtml[..content_start].replacen("</head>", &format!("{KOBO_STYLES}</head>"), 1);
+    format!(
+        "{}<div id=\"book-columns\"><div id=\"book-inner\">{}</div></div>{}",
+        head,
+        add_spans(&xhtml[content_start..content_end]),
+        &xhtml[content_end..]
+    )
+}
+
+/// Wraps each sentence of the text of `content` in a `koboSpan`,
+/// numbered by paragraph and by sentence.
+fn add_spans(content: &str) -> String {
+    let mut output = String::with_capacity(content.len() * 2);
+    let mut paragraph = 0;
+    let mut sentence = 0;
+    let mut raw_depth = 0;
+    let mut rest = content;
+
+    while !rest.is_empty() {
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").map_or(rest.len(), |end| end + 3);
+            output.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else if rest.starts_with('<') {
+            let end = rest.find('>').map_or(rest.len(), |end| end + 1);
+            let tag = &rest[..end];
+            let closing = tag.starts_with("</");
+            let name: String = tag[if closing { 2 } else { 1 }..]
+                .chars()
+                .take_while(|c| c.is_alphanumeric() || *c == ':' || *c == '-')
+                .collect::<String>()
+                .to_lowercase();
+            let self_closing = tag.ends_with("/>");
+            if RAW_ELEMENTS.contains(&name.as_str()) && !self_closing {
+                if closing {
+                    raw_depth -= 1;
+                } else {
+                    raw_depth += 1;
+                }
+            } else if !closing && BLOCK_ELEMENTS.contains(&name.as_str()) {
+                paragraph += 1;
+                sentence = 0;
+            }
+            output.push_str(tag);
+            rest = &rest[end..];
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            let text = &rest[..end];
+            if raw_depth > 0 || text.trim().is_empty() {
+                output.push_str(text);
+            } else {
+                for segment in sentences(text) {
+                    if segment.trim().is_empty() {
+                        output.push_str(segment);
+                        continue;
+                    }
+                    sentence += 1;
+                    output.push_str(&format!(
+                        "<span class=\"koboSpan\" id=\"kobo.{paragraph}.{sentence}\">{segment}</span>"
+                    ));
+                }
+            }
+            rest = &rest[end..];
+        }
+    }
+    output
+}
+
+/// Splits `text` after each sentence ending punctuation followed by whitespace,
+/// keeping the whitespace with the sentence.
+fn sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        if !matches!(c, '.' | '!' | '?' | '…') {
+            continue;
+        }
+        let mut end = None;
+        while let Some(&(i, next)) = chars.peek() {
+            if !next.is_whitespace() {
+                break;
+            }
+            chars.next();
+            end = Some(i + next.len_utf8());
+        }
+        if let Some(end) = end {
+            sentences.push(&text[start..end]);
+            start = end;
+        }
+    }
+    if start < text.len() {
+        sentences.push(&text[start..]);
+    }
+    sentences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sentences() {
+        assert_eq!(
+            sentences("Hello Rust. Is it you? Yes!"),
+            vec!["Hello Rust. ", "Is it you? ", "Yes!"]
+        );
+        assert_eq!(sentences("v1.0 is out"), vec!["v1.0 is out"]);
+    }
+
+    #[test]
+    fn test_koboize() {
+        let xhtml = concat!(
+            "<html><head><title>A</title></head><body class=\"x\">",
+            "<h1>Title</h1>\n<p>One. <em>Two</em> three.</p>",
+            "<pre><code>let a = 1;</code></pre><style>p { }</style>",
+            "</body></html>"
+        );
+
+        assert_eq!(
+            koboize(xhtml),
+            concat!(
+                "<html><head><title>A</title>",
+                r#"<style type="text/css" id="kobostylehacks">div#book-inner { margin-top: 0; margin-bottom: 0; }</style>"#,
+                "</head><body class=\"x\"><div id=\"book-columns\"><div id=\"book-inner\">",
+                "<h1><span class=\"koboSpan\" id=\"kobo.1.1\">Title</span></h1>\n",
+                "<p><span class=\"koboSpan\" id=\"kobo.2.1\">One. </span>",
+                "<em><span class=\"koboSpan\" id=\"kobo.2.2\">Two</span></em>",
+                "<span class=\"koboSpan\" id=\"kobo.2.3\"> three.</span></p>",
+                "<pre><code><span class=\"koboSpan\" id=\"kobo.3.1\">let a = 1;</span></code></pre>",
+                "<style>p { }</style>",
+                "</div></div></body></html>"
+            )
+        );
+    }
+}
@@ -1,46 +1,193 @@
+mod admonitions;
 #[allow(dead_code)]
 mod book;
+mod cache;
+mod checksum;
+mod colophon;
 pub mod config;
+mod cover;
+mod cross_links;
+mod epub;
+mod export;
+mod fallbacks;
+mod fb2;
+mod filename;
 mod git;
+mod highlight;
+mod images;
+mod kepub;
+mod links;
+mod math;
+mod profile;
+mod remote_images;
+mod single_file;
+mod thumbnail;
+mod validate;
 
 #[cfg(test)]
 mod tests;
 
-use anyhow::{anyhow, Ok, Result};
+use anyhow::{anyhow, bail, Context as _, Ok, Result};
 #[double]
 use book::Book;
-use chrono::Utc;
+use book::{BookOptions, Series, Stylesheet};
+use cache::{BuildCache, CacheKey};
+use chrono::{DateTime, Utc};
+use colophon::Colophon;
 use config::{BookRepoConfig, Config};
+use cover::Cover;
+use cross_links::{CrossLinks, LinkTarget, ShelfBook};
+use export::Format;
+use fallbacks::Fallbacks;
+use filename::Placeholders;
 use git::GitOp;
 #[double]
 use git::Repo;
-use log::{debug, info, trace, warn};
+use highlight::Highlight;
+use log::{debug, error, info, trace, warn};
+use math::MathOutput;
 use mockall_double::double;
+use profile::Profile;
+use remote_images::Localization;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use tera::Context;
 use toml::Value;
+use uuid::Uuid;
 use walkdir::WalkDir;
 
 /// A manifest entry for the generated EPUB
-#[derive(Default, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ManifestEntry {
+    /// All the files generated for the book (one EPUB per profile, and the other formats)
+    pub artifacts: Vec<Artifact>,
+    /// The book authors
+    pub authors: Vec<String>,
+    /// The BLAKE3 digest of the EPUB, if enabled
+    pub blake3: Option<String>,
     /// The commit sha
     pub commit_sha: String,
+    /// The book description
+    pub description: Option<String>,
     /// The size of the EPUB in bytes
     pub epub_size: u64,
+    /// The problems found by the EPUB validator, if enabled
+    pub findings: Vec<Finding>,
+    /// The remote images which couldn't be embedded in the EPUBs, if enabled
+    pub image_failures: Vec<ImageFailure>,
+    /// The EPUB sizes before and after the image optimization, if enabled (see the
+    /// artifacts for the EPUBs of the other profiles)
+    pub image_optimization: Option<ImageOptimization>,
+    /// The book language
+    pub language: Option<String>,
     /// The last modified date of the book (i.e. the datetime of the last commit)
     pub last_modified: String,
+    /// The broken links and missing assets of the book, if enabled (see the artifacts
+    /// for the reports next to the EPUBs of the other profiles)
+    pub link_report: Option<LinkReport>,
     /// The path to the generated EPUB
     pub path: PathBuf,
+    /// The e-reader profile of the EPUB, if any
+    pub profile: Option<String>,
+    /// The book publisher
+    pub publisher: Option<String>,
     /// The book repository URL
     pub repo_url: String,
+    /// The book copyright statement
+    pub rights: Option<String>,
+    /// The SHA-256 digest of the EPUB
+    pub sha256: String,
+    /// The book subject tags
+    pub subject: Vec<String>,
+    /// The cover thumbnails of all the EPUBs, if enabled
+    pub thumbnails: Vec<Thumbnail>,
     /// The book title
     pub title: String,
     /// The book online version URL
     pub url: String,
+    /// The book unique identifier, used as EPUB `dc:identifier`
+    pub uuid: String,
+    /// The book version (i.e. the most recent tag, or the abbreviated commit sha)
+    pub version: String,
+}
+
+/// A file generated for a book
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Artifact {
+    /// The file format (e.g. `epub` or `kepub`)
+    pub format: String,
+    /// The e-reader profile, if any
+    pub profile: Option<String>,
+    /// The path to the file
+    pub path: PathBuf,
+    /// The size of the file in bytes
+    pub size: u64,
+    /// The SHA-256 digest of the file
+    pub sha256: String,
+    /// The BLAKE3 digest of the file, if enabled
+    pub blake3: Option<String>,
+    /// The broken links and missing assets report of an EPUB, if enabled
+    pub link_report: Option<LinkReport>,
+    /// The sizes of an EPUB before and after the image optimization, if enabled
+    pub image_optimization: Option<ImageOptimization>,
+}
+
+/// A cover thumbnail written next to the EPUB
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Thumbnail {
+    /// The path to the PNG thumbnail
+    pub path: PathBuf,
+    /// The thumbnail width in pixels
+    pub width: u32,
+    /// The thumbnail height in pixels
+    pub height: u32,
+}
+
+/// The broken links and missing assets found in a book before rendering it
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LinkReport {
+    /// The path to the JSON report
+    pub path: PathBuf,
+    /// The number of links to files which don't exist
+    pub broken_links: usize,
+    /// The number of links to anchors which don't exist
+    pub missing_anchors: usize,
+    /// The number of images which don't exist
+    pub missing_images: usize,
+    /// The number of included files which don't exist
+    pub missing_includes: usize,
+}
+
+/// A remote image which couldn't be embedded in a book
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ImageFailure {
+    /// The image URL
+    pub url: String,
+    /// Why the image couldn't be downloaded or embedded
+    pub reason: String,
+}
+
+/// The sizes of an EPUB before and after optimizing its images
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ImageOptimization {
+    /// The size in bytes of the EPUB generated by mdbook-epub
+    pub size_before: u64,
+    /// The size in bytes of the post-processed EPUB
+    pub size_after: u64,
+}
+
+/// A problem found in a generated EPUB
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Finding {
+    /// The path to the EPUB
+    pub path: PathBuf,
+    /// The EPUB entry the problem was found in
+    pub entry: String,
+    /// The problem description
+    pub message: String,
 }
 
 /// A Manifest contains the information about all EPUBs built
@@ -59,8 +206,9 @@ pub fn run(config: &Config) -> Result<Manifest> {
     let working_dir = config.working_dir.as_ref().unwrap();
 
     check_or_create_dir(dest.as_path())?;
-    let entries = generate(&config.book_repo_configs, working_dir, dest)
-        .ok_or_else(|| anyhow!("Something bad happened."))?;
+    let mut cache = BuildCache::load(working_dir);
+    let entries = generate(config, &mut cache).ok_or_else(|| anyhow!("Something bad happened."))?;
+    checksum::write_sha256sums(dest, &entries)?;
     let manifest = Manifest {
         entries,
         timestamp: Utc::now().to_rfc3339(),
@@ -84,21 +232,643 @@ fn check_or_create_dir(dest: &Path) -> io::Result<()> {
     }
 }
 
+/// A book repository checked out at the commit to generate.
+struct Checkout {
+    /// The book unique identifier
+    uuid: Uuid,
+    /// The book root directory
+    book_path: PathBuf,
+    commit_sha: String,
+    last_modified: String,
+    version: String,
+    /// One per e-reader profile
+    variants: Vec<Variant>,
+    /// The path of the zipped HTML site, shared by all the variants
+    site_path: PathBuf,
+    /// The options the HTML site is rendered with, without the EPUB and profile transforms
+    site_options: BookOptions,
+}
+
+/// A version of a book generated for an e-reader profile.
+struct Variant {
+    /// Identifies the variant inside the shelf
+    id: String,
+    profile: Option<String>,
+    options: BookOptions,
+}
+
+fn checkout_book(
+    config: &Config,
+    repo_config: &BookRepoConfig,
+    position: usize,
+) -> Option<Checkout> {
+    trace!("{:#?}", repo_config);
+    let repo_url = repo_config.repo_url.as_str();
+    let working_dir = config.working_dir.as_ref().unwrap();
+
+    let (mut book_path, commit_sha, last_modified, version) =
+        Repo::clone_or_fetch_repo(repo_url, working_dir).ok()?;
+
+    if let Some(repo_folder) = &repo_config.folder {
+        book_path = book_path.join(repo_folder);
+    }
+
+    let mut options = book_options(config, repo_config);
+
+    let title = repo_config
+        .title
+        .to_owned()
+        .or_else(|| book::read_title(&book_path, &options.env_var))
+        .unwrap_or_else(|| String::from("book"));
+    let id = match &repo_config.folder {
+        Some(folder) => format!("{}#{}", repo_url, folder.display()),
+        None => repo_url.to_owned(),
+    };
+
+    // Stable across builds, so that e-readers replace the previous version of the book
+    let uuid = repo_config
+        .uuid
+        .unwrap_or_else(|| Uuid::new_v5(&Uuid::NAMESPACE_URL, id.as_bytes()));
+    options.identifier = Some(uuid.urn().to_string());
+
+    if config.series && config.title.trim().is_empty() {
+        warn!("Not adding {} to a series, the shelf has no title", id);
+    } else if config.series {
+        // Follow the shelf order unless an explicit index is set
+        options.series = Some(Series {
+            title: config.title.to_owned(),
+            index: repo_config.series_index.unwrap_or((position + 1) as f64),
+        });
+    }
+
+    if config.colophon {
+        options.colophon = Some(Colophon {
+            template: read_template(
+                config.colophon_template.as_deref(),
+                colophon::DEFAULT_TEMPLATE,
+            )?,
+            qr_code: config.colophon_qr_code,
+            repo_url: repo_url.to_owned(),
+            url: repo_config.url.to_owned(),
+            commit_sha: commit_sha.to_owned(),
+            last_modified: last_modified.to_owned(),
+            version: version.to_owned(),
+        });
+    }
+
+    options.cover = match &repo_config.cover {
+        Some(path) => Some(Cover::Image(path.to_owned())),
+        None if config.generate_covers => Some(generated_cover(config)?),
+        None => None,
+    };
+    let formats = repo_config
+        .extra_formats
+        .as_ref()
+        .unwrap_or(&config.extra_formats);
+    for name in formats {
+        let Some(format) = Format::from_name(name) else {
+            error!("Unknown format {} for {}", name, id);
+            return None;
+        };
+        options.extra_formats.push(format);
+    }
+    options.stylesheet = stylesheet(config, repo_config)
+        .inspect_err(|e| error!("{:#}", e))
+        .ok()?;
+    if config.admonitions {
+        options.admonitions = true;
+        Stylesheet::prepend(&mut options.stylesheet, &admonitions::css());
+    }
+    if !config.thumbnail_widths.is_empty() {
+        options.thumbnail_widths = config.thumbnail_widths.to_owned();
+        if options.cover.is_none() {
+            options.thumbnail_cover = Some(generated_cover(config)?);
+        }
+    }
+
+    options.link_report = config.link_report;
+    if repo_config.math.unwrap_or(config.math) {
+        options.math = Some(MathOutput::MathMl);
+    }
+    options.optimize_images = config.optimize_images;
+    options.rasterize_svg = config.rasterize_svg;
+    if config.localize_images {
+        options.remote_images = Some(Localization {
+            allowlist: config.image_allowlist.to_owned(),
+            max_size: config.image_max_size.unwrap_or(5 * 1024 * 1024),
+            max_total_size: config.image_max_total_size.unwrap_or(50 * 1024 * 1024),
+            proxy: config.image_proxy.to_owned(),
+            cache_dir: config
+                .image_cache_dir
+                .to_owned()
+                .or_else(|| config.working_dir.as_ref().map(|dir| dir.join(".images"))),
+        });
+    }
+    options.validate = config.validate || config.strict_validation;
+
+    if config.reproducible || config.verify_reproducible {
+        let commit_time = DateTime::parse_from_rfc3339(&last_modified)
+            .map(|datetime| datetime.timestamp())
+            .unwrap_or_default();
+        options.source_date = Some(source_date_epoch().unwrap_or(commit_time));
+    }
+
+    let mut pattern = repo_config
+        .output_filename
+        .as_deref()
+        .or(config.output_filename.as_deref())
+        .unwrap_or(filename::DEFAULT_PATTERN)
+        .to_owned();
+    let profiles = repo_config.profiles.as_ref().unwrap_or(&config.profiles);
+    if profiles.len() > 1 && !pattern.contains("{profile}") {
+        pattern = filename::with_profile(&pattern);
+    }
+    // The HTML site is rendered once per book, only with the mdbook config overrides
+    let site_options = BookOptions {
+        env_var: options.env_var.clone(),
+        source_date: options.source_date,
+        ..Default::default()
+    };
+    let site_placeholders = Placeholders {
+        title: &title,
+        version: &version,
+        sha: &commit_sha,
+        profile: "",
+    };
+    let site_path = Format::HtmlZip.output_path(Path::new(&filename::expand(
+        &filename::without_profile(&pattern),
+        &site_placeholders,
+    )));
+    let variant = |profile: Option<&String>| {
+        let mut options = options.clone();
+        let profile_settings = match profile {
+            Some(name) => {
+                let Some(profile) = profile::find(name) else {
+                    error!("Unknown profile {} for {}", name, id);
+                    return None;
+                };
+                options.apply_profile(profile);
+                Some(profile)
+            }
+            None => None,
+        };
+        if config.html_fallbacks {
+            options.fallbacks = Some(fallbacks(config, profile_settings));
+        }
+        if config.highlight_code {
+            let highlight = highlight(config, profile_settings);
+            if !highlight::has_theme(&highlight.theme) {
+                error!("Unknown highlight theme {} for {}", highlight.theme, id);
+                return None;
+            }
+            if highlight.classes {
+                let css = highlight.css().inspect_err(|e| error!("{:#}", e)).ok()?;
+                Stylesheet::prepend(&mut options.stylesheet, &css);
+            }
+            options.highlight = Some(highlight);
+        }
+        let placeholders = Placeholders {
+            title: &title,
+            version: &version,
+            sha: &commit_sha,
+            profile: profile.map_or("", String::as_str),
+        };
+        options.output_filename = Some(PathBuf::from(filename::expand(&pattern, &placeholders)));
+        Some(Variant {
+            id: match profile {
+                Some(name) => format!("{} [{}]", id, name),
+                None => id.to_owned(),
+            },
+            profile: profile.cloned(),
+            options,
+        })
+    };
+    let variants = if profiles.is_empty() {
+        vec![variant(None)?]
+    } else {
+        profiles
+            .iter()
+            .map(|profile| variant(Some(profile)))
+            .collect::<Option<Vec<_>>>()?
+    };
+
+    Some(Checkout {
+        uuid,
+        book_path,
+        commit_sha,
+        last_modified,
+        version,
+        variants,
+        site_path,
+        site_options,
+    })
+}
+
+/// The fallback markup of the books generated for `profile`, overridden by the `default`
+/// then the profile settings of the shelf.
+fn fallbacks(config: &Config, profile: Option<&Profile>) -> Fallbacks {
+    let mut fallbacks = Fallbacks::default();
+    let overrides = std::iter::once("default")
+        .chain(profile.map(|profile| profile.name))
+        .filter_map(|key| config.fallback_markup.get(key));
+    for markup in overrides {
+        if let Some(embed) = &markup.embed {
+            fallbacks.embed = embed.clone();
+        }
+        if let Some(video) = &markup.video {
+            fallbacks.video = video.clone();
+        }
+        if let Some(playground) = &markup.playground {
+            fallbacks.playground = playground.clone();
+        }
+    }
+    fallbacks
+}
+
+/// The code highlighting settings of the books generated for `profile`.
+fn highlight(config: &Config, profile: Option<&Profile>) -> Highlight {
+    let theme = profile
+        .and_then(|profile| config.highlight_themes.get(profile.name))
+        .or(config.highlight_theme.as_ref())
+        .map_or_else(
+            || {
+                profile
+                    .map_or(highlight::DEFAULT_THEME, |profile| profile.highlight_theme)
+                    .to_owned()
+            },
+            String::to_owned,
+        );
+    Highlight {
+        theme,
+        classes: config.highlight_classes,
+    }
+}
+
+/// Combines the shelf and book CSS, the book one coming last so that it takes precedence.
+fn stylesheet(config: &Config, repo_config: &BookRepoConfig) -> Result<Option<Stylesheet>> {
+    let replace = repo_config
+        .replace_stylesheet
+        .unwrap_or(config.replace_stylesheet);
+    let paths: Vec<&PathBuf> = config
+        .stylesheet
+        .iter()
+        .chain(repo_config.stylesheet.iter())
+        .collect();
+    if paths.is_empty() && !replace {
+        return Ok(None);
+    }
+
+    let mut css = String::new();
+    for path in paths {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read stylesheet {}", path.display()))?;
+        css.push_str(&content);
+        if !css.ends_with('\n') {
+            css.push('\n');
+        }
+    }
+    Ok(Some(Stylesheet { css, replace }))
+}
+
+/// The cover rendered from the shelf cover template.
+fn generated_cover(config: &Config) -> Option<Cover> {
+    Some(Cover::Generated {
+        template: read_template(config.cover_template.as_deref(), cover::DEFAULT_TEMPLATE)?,
+        shelf: config.title.to_owned(),
+        accent_color: config
+            .cover_accent_color
+            .to_owned()
+            .unwrap_or_else(|| String::from(cover::DEFAULT_ACCENT_COLOR)),
+    })
+}
+
+/// Reads the template at `path`, or returns `default` if not set.
+fn read_template(path: Option<&Path>, default: &str) -> Option<String> {
+    match path {
+        Some(path) => std::fs::read_to_string(path)
+            .inspect_err(|e| error!("Could not read template {}: {}", path.display(), e))
+            .ok(),
+        None => Some(default.to_owned()),
+    }
+}
+
+/// Returns an error if several books would write the same file: EPUB, exported format,
+/// thumbnail or link report.
+fn check_output_paths(checkouts: &[Checkout]) -> Result<()> {
+    let mut paths: HashMap<String, &str> = HashMap::with_capacity(checkouts.len());
+    let variants = checkouts.iter().flat_map(|checkout| {
+        let site = &checkout.site_path;
+        checkout
+            .variants
+            .iter()
+            .enumerate()
+            .map(move |(i, variant)| (variant, (i == 0).then_some(site)))
+    });
+    for (variant, site) in variants {
+        let Some(epub_path) = &variant.options.output_filename else {
+            continue;
+        };
+        let options = &variant.options;
+        let files = std::iter::once(epub_path.to_owned())
+            .chain(options.extra_formats.iter().filter_map(|&format| {
+                // The first variant writes the HTML site of the book
+                match format {
+                    Format::HtmlZip => site.cloned(),
+                    format => Some(format.output_path(epub_path)),
+                }
+            }))
+            .chain(
+                options
+                    .thumbnail_widths
+                    .iter()
+                    .map(|&width| thumbnail::thumbnail_path(epub_path, width)),
+            )
+            .chain(options.link_report.then(|| links::report_path(epub_path)));
+        let mut own_paths = HashSet::new();
+        for path in files {
+            // Some filesystems are case insensitive
+            let key = path.to_string_lossy().to_lowercase();
+            if !own_paths.insert(key.clone()) {
+                continue;
+            }
+            if let Some(other) = paths.insert(key, &variant.id) {
+                bail!(
+                    "Books {} and {} would both be written to {}",
+                    other,
+                    variant.id,
+                    path.display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
 fn generate_book(
     repo_config: &BookRepoConfig,
-    working_dir: &Path,
+    checkout: &Checkout,
+    variant: &Variant,
     dest: &Path,
+    cache: &mut BuildCache,
+    force: bool,
 ) -> Option<ManifestEntry> {
-    trace!("{:#?}", repo_config);
-    let repo_url = repo_config.repo_url.to_owned();
+    let key = CacheKey::new(&checkout.commit_sha, &variant.options);
+    if !force {
+        if let Some(entry) = cache.lookup(&variant.id, &key, dest) {
+            info!("Skipping {}, unchanged since last build", entry.title);
+            return Some(ManifestEntry {
+                url: repo_config.url.to_owned(),
+                ..entry.clone()
+            });
+        }
+    }
 
-    let (mut repo_path, commit_sha, last_modified) =
-        Repo::clone_or_fetch_repo(repo_url.as_str(), working_dir).ok()?;
+    let output = Book::generate_epub(checkout.book_path.as_path(), &variant.options, dest).ok()?;
 
-    if let Some(repo_folder) = &repo_config.folder {
-        repo_path = repo_path.join(repo_folder);
+    let title = repo_config
+        .title
+        .to_owned()
+        .or(output.title)
+        .unwrap_or_default();
+
+    let findings = if variant.options.validate {
+        validate_book(variant, &output.path, dest)?
+    } else {
+        Vec::new()
+    };
+
+    let mut artifacts = vec![Artifact {
+        format: String::from("epub"),
+        profile: variant.profile.to_owned(),
+        path: output.path.to_owned(),
+        size: output.epub_size,
+        sha256: output.digests.sha256.to_owned(),
+        blake3: output.digests.blake3.to_owned(),
+        link_report: output.link_report.to_owned(),
+        image_optimization: output.image_optimization.to_owned(),
+    }];
+    for &format in &variant.options.extra_formats {
+        // The HTML site doesn't depend on the profile, it is built with the first variant
+        if format == Format::HtmlZip && variant.id != checkout.variants[0].id {
+            continue;
+        }
+        let artifact = export_book(format, checkout, variant, &output.path, dest)
+            .inspect_err(|e| {
+                error!(
+                    "Could not export {} to {}: {:#}",
+                    variant.id,
+                    format.name(),
+                    e
+                )
+            })
+            .ok()?;
+        artifacts.push(artifact);
+    }
+    let entry = ManifestEntry {
+        artifacts,
+        authors: output.authors,
+        blake3: output.digests.blake3,
+        commit_sha: checkout.commit_sha.to_owned(),
+        description: output.description,
+        epub_size: output.epub_size,
+        findings,
+        image_failures: output.image_failures,
+        image_optimization: output.image_optimization,
+        language: output.language,
+        last_modified: checkout.last_modified.to_owned(),
+        link_report: output.link_report,
+        path: output.path,
+        profile: variant.profile.to_owned(),
+        publisher: repo_config.publisher.to_owned(),
+        repo_url: repo_config.repo_url.to_owned(),
+        rights: repo_config.rights.to_owned(),
+        sha256: output.digests.sha256,
+        subject: repo_config.subject.to_owned().unwrap_or_default(),
+        thumbnails: output.thumbnails,
+        title,
+        url: repo_config.url.to_owned(),
+        uuid: checkout.uuid.to_string(),
+        version: checkout.version.to_owned(),
+    };
+    cache.insert(&variant.id, key, &entry);
+    // Saved after each book, so that a later failure doesn't discard it
+    if let Err(e) = cache.save() {
+        warn!("Could not save the build cache: {:#}", e);
+    }
+    Some(entry)
+}
+
+/// Exports a book, already generated as the EPUB at `epub_path` (relative to `dest`), to `format`.
+fn export_book(
+    format: Format,
+    checkout: &Checkout,
+    variant: &Variant,
+    epub_path: &Path,
+    dest: &Path,
+) -> Result<Artifact> {
+    let (path, options, profile) = match format {
+        Format::HtmlZip => (checkout.site_path.to_owned(), &checkout.site_options, None),
+        format => (
+            format.output_path(epub_path),
+            &variant.options,
+            variant.profile.to_owned(),
+        ),
+    };
+    let output_file = dest.join(&path);
+    if !format.is_rendered_with_epub() {
+        export::export(
+            format,
+            &checkout.book_path,
+            options,
+            &dest.join(epub_path),
+            &output_file,
+        )?;
+    }
+    let digests = checksum::digest_file(&output_file, variant.options.blake3)?;
+    Ok(Artifact {
+        format: format.name().to_owned(),
+        profile,
+        path,
+        size: std::fs::metadata(&output_file)?.len(),
+        sha256: digests.sha256,
+        blake3: digests.blake3,
+        ..Default::default()
+    })
+}
+
+/// Sets the links to rewrite in every book: the other books of the shelf
+/// with an online version, linked to their EPUB of the same profile if any.
+fn add_cross_links(
+    book_repo_configs: &[BookRepoConfig],
+    checkouts: &mut [Checkout],
+    target: LinkTarget,
+) {
+    let shelf: Vec<_> = book_repo_configs
+        .iter()
+        .zip(checkouts.iter())
+        .map(|(repo_config, checkout)| {
+            let epubs: Vec<_> = checkout
+                .variants
+                .iter()
+                .map(|variant| {
+                    (
+                        variant.profile.to_owned(),
+                        variant.options.output_filename.to_owned(),
+                        variant.options.extra_formats.contains(&Format::Kepub),
+                    )
+                })
+                .collect();
+            (repo_config.url.to_owned(), epubs)
+        })
+        .collect();
+    for (position, (repo_config, checkout)) in book_repo_configs.iter().zip(checkouts).enumerate() {
+        for variant in &mut checkout.variants {
+            let books = shelf
+                .iter()
+                .enumerate()
+                .filter(|(other, (url, _))| *other != position && !url.is_empty())
+                .filter_map(|(_, (url, epubs))| {
+                    let (_, epub, kepub) = epubs
+                        .iter()
+                        .find(|(profile, _, _)| *profile == variant.profile)
+                        .or(epubs.first())?;
+                    Some(ShelfBook {
+                        url: url.to_owned(),
+                        epub: epub.to_owned()?,
+                        kepub: *kepub,
+                    })
+                })
+                .collect();
+            variant.options.cross_links = Some(CrossLinks {
+                url: repo_config.url.to_owned(),
+                target,
+                books,
+            });
+        }
+    }
+}
+
+/// Validates the EPUB at `path` (relative to `dest`), logging the problems found.
+fn validate_book(variant: &Variant, path: &Path, dest: &Path) -> Option<Vec<Finding>> {
+    let findings = validate::validate(&dest.join(path), path)
+        .inspect_err(|e| error!("Could not validate {}: {:#}", variant.id, e))
+        .ok()?;
+    for finding in &findings {
+        warn!("{}: {}: {}", path.display(), finding.entry, finding.message);
+    }
+    if findings.is_empty() {
+        info!("{} is valid", variant.id);
     }
+    Some(findings)
+}
+
+/// Merges the entries of the variants of a book: the first one is the main entry,
+/// the files, findings, thumbnails and image failures of the others are added to it.
+fn merge_variants(mut entries: Vec<ManifestEntry>) -> Option<ManifestEntry> {
+    let artifacts = entries
+        .iter_mut()
+        .flat_map(|entry| std::mem::take(&mut entry.artifacts))
+        .collect();
+    let findings = entries
+        .iter_mut()
+        .flat_map(|entry| std::mem::take(&mut entry.findings))
+        .collect();
+    let thumbnails = entries
+        .iter_mut()
+        .flat_map(|entry| std::mem::take(&mut entry.thumbnails))
+        .collect();
+    // The variants download the same images
+    let mut image_failures: Vec<ImageFailure> = Vec::new();
+    for failure in entries
+        .iter_mut()
+        .flat_map(|entry| std::mem::take(&mut entry.image_failures))
+    {
+        if !image_failures.iter().any(|other| other.url == failure.url) {
+            image_failures.push(failure);
+        }
+    }
+    let mut entry = entries.into_iter().next()?;
+    entry.artifacts = artifacts;
+    entry.findings = findings;
+    entry.thumbnails = thumbnails;
+    entry.image_failures = image_failures;
+    Some(entry)
+}
 
+/// Rebuilds a book in a scratch directory and checks the EPUB is identical to `entry`.
+fn verify_reproducible(
+    checkout: &Checkout,
+    variant: &Variant,
+    entry: &ManifestEntry,
+    working_dir: &Path,
+) -> Option<()> {
+    let scratch = working_dir.join(".reproducible");
+    let output = Book::generate_epub(checkout.book_path.as_path(), &variant.options, &scratch);
+    if let Err(e) = std::fs::remove_dir_all(&scratch) {
+        debug!("Could not remove {}: {}", scratch.display(), e);
+    }
+    let sha256 = output.ok()?.digests.sha256;
+    if sha256 == entry.sha256 {
+        info!("{} is reproducible", variant.id);
+        Some(())
+    } else {
+        error!(
+            "{} is not reproducible: rebuilt EPUB digest {} differs from {}",
+            variant.id, sha256, entry.sha256
+        );
+        None
+    }
+}
+
+/// Returns the `SOURCE_DATE_EPOCH` timestamp, if set.
+/// See <https://reproducible-builds.org/specs/source-date-epoch/>.
+fn source_date_epoch() -> Option<i64> {
+    std::env::var("SOURCE_DATE_EPOCH").ok()?.trim().parse().ok()
+}
+
+/// Collects the shelf-side overrides of a book: mdBook config is changed through
+/// environment variables, other metadata is added to the generated EPUB.
+fn book_options(config: &Config, repo_config: &BookRepoConfig) -> BookOptions {
     let mut vars: Vec<(String, Option<String>)> = if let Some(mapping) = &repo_config.env_var {
         let to_owned_kv = |(k, v): (&String, &Value)| (k.to_owned(), Some(v.to_string()));
         mapping.iter().map(to_owned_kv).collect()
@@ -111,40 +881,90 @@ fn generate_book(
             Some(new_filename.to_owned()),
         ));
     }
-    let (book_title, path, epub_size) =
-        Book::generate_epub(repo_path.as_path(), vars, dest).ok()?;
+    // Values are JSON encoded so that mdbook doesn't reinterpret them
+    if let Some(authors) = &repo_config.authors {
+        vars.push((
+            String::from("MDBOOK_BOOK__AUTHORS"),
+            serde_json::to_string(authors).ok(),
+        ));
+    }
+    if let Some(language) = &repo_config.language {
+        vars.push((
+            String::from("MDBOOK_BOOK__LANGUAGE"),
+            serde_json::to_string(language).ok(),
+        ));
+    }
+    if let Some(description) = &repo_config.description {
+        vars.push((
+            String::from("MDBOOK_BOOK__DESCRIPTION"),
+            serde_json::to_string(description).ok(),
+        ));
+    }
 
-    let title = repo_config
-        .title
-        .to_owned()
-        .or(book_title)
-        .unwrap_or_default();
+    let mut metadata = Vec::new();
+    if let Some(publisher) = &repo_config.publisher {
+        metadata.push((String::from("publisher"), publisher.to_owned()));
+    }
+    for subject in repo_config.subject.iter().flatten() {
+        metadata.push((String::from("subject"), subject.to_owned()));
+    }
+    if let Some(rights) = &repo_config.rights {
+        metadata.push((String::from("rights"), rights.to_owned()));
+    }
 
-    Some(ManifestEntry {
-        commit_sha,
-        epub_size,
-        last_modified,
-        path,
-        repo_url,
-        title,
-        url: repo_config.url.to_owned(),
-    })
+    BookOptions {
+        env_var: vars,
+        metadata,
+        blake3: config.blake3,
+        ..Default::default()
+    }
 }
 
-fn generate(
-    book_repo_configs: &Vec<config::BookRepoConfig>,
-    working_dir: &Path,
-    dest: &Path,
-) -> Option<Vec<ManifestEntry>> {
+fn generate(config: &Config, cache: &mut BuildCache) -> Option<Vec<ManifestEntry>> {
+    let book_repo_configs = &config.book_repo_configs;
+    let dest = config.destination_dir.as_ref().unwrap();
+
     let mut shelf = if book_repo_configs.is_empty() {
         warn!("No book to generate");
         Vec::new()
     } else {
         Vec::with_capacity(book_repo_configs.len())
     };
-    for repo_config in book_repo_configs {
-        let entry = generate_book(repo_config, working_dir, dest)?;
-        shelf.push(entry);
+    // Check out all books first to detect conflicts before generating anything
+    let mut checkouts = Vec::with_capacity(book_repo_configs.len());
+    for (position, repo_config) in book_repo_configs.iter().enumerate() {
+        checkouts.push(checkout_book(config, repo_config, position)?);
+    }
+    if let Err(e) = check_output_paths(&checkouts) {
+        error!("{}", e);
+        return None;
+    }
+    if let Some(name) = &config.cross_book_links {
+        let Some(target) = LinkTarget::from_name(name) else {
+            error!("Unknown cross-book link target {}", name);
+            return None;
+        };
+        add_cross_links(book_repo_configs, &mut checkouts, target);
+    }
+    for (repo_config, checkout) in book_repo_configs.iter().zip(&checkouts) {
+        let mut entries = Vec::with_capacity(checkout.variants.len());
+        for variant in &checkout.variants {
+            let entry = generate_book(repo_config, checkout, variant, dest, cache, config.force)?;
+            if config.verify_reproducible {
+                let working_dir = config.working_dir.as_ref().unwrap();
+                verify_reproducible(checkout, variant, &entry, working_dir)?;
+            }
+            if config.strict_validation && !entry.findings.is_empty() {
+                error!(
+                    "{} has {} validation problem(s), failing the build",
+                    variant.id,
+                    entry.findings.len()
+                );
+                return None;
+            }
+            entries.push(entry);
+        }
+        shelf.push(merge_variants(entries)?);
     }
     Some(shelf)
 }
@@ -0,0 +1,304 @@
+//! Detection of the broken links and missing assets of a book, before rendering it.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use mdbook::book::Chapter;
+use mdbook::utils::{new_cmark_parser, unique_id_from_content};
+use mdbook::{BookItem, MDBook};
+use percent_encoding::percent_decode_str;
+use pulldown_cmark::{Event, Tag, TagEnd};
+use serde::{Deserialize, Serialize};
+
+use crate::single_file::normalize_path;
+use crate::LinkReport;
+
+/// The kind of a problem found in a chapter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ProblemKind {
+    /// A link to a file which doesn't exist
+    BrokenLink,
+    /// A link to an anchor which doesn't exist
+    MissingAnchor,
+    /// An image which doesn't exist
+    MissingImage,
+    /// An `{{#include}}` of a file which doesn't exist
+    MissingInclude,
+}
+
+/// A broken link or missing asset.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Problem {
+    /// The chapter, relative to the book source directory
+    pub chapter: PathBuf,
+    pub kind: ProblemKind,
+    /// The link, image or included path, as written in the chapter
+    pub target: String,
+}
+
+/// The broken links and missing assets of a book.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Report {
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    /// The number of problems of `kind`.
+    pub(crate) fn count(&self, kind: ProblemKind) -> usize {
+        self.problems
+            .iter()
+            .filter(|problem| problem.kind == kind)
+            .count()
+    }
+}
+
+/// Checks the links, anchors, images and includes of the chapters of `md`,
+/// as written in the sources (before preprocessing). The anchors of the chapters
+/// including other files are not checked, they may come from the included files.
+pub(crate) fn check(md: &MDBook) -> Report {
+    let source_dir = md.source_dir();
+    let chapters: Vec<(&Path, &Chapter)> = md
+        .iter()
+        .filter_map(|item| match item {
+            BookItem::Chapter(chapter) => Some((chapter.path.as_deref()?, chapter)),
+            _ => None,
+        })
+        .collect();
+    let anchors: HashMap<&Path, HashSet<String>> = chapters
+        .iter()
+        .map(|(path, chapter)| (*path, anchors(&chapter.content)))
+        .collect();
+    // The headings and ids of the included files are not known before preprocessing
+    let with_includes: HashSet<&Path> = chapters
+        .iter()
+        .filter(|(_, chapter)| !includes(&chapter.content).is_empty())
+        .map(|(path, _)| *path)
+        .collect();
+
+    let mut problems = Vec::new();
+    for (path, chapter) in &chapters {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut problem = |kind, target: &str| {
+            problems.push(Problem {
+                chapter: path.to_path_buf(),
+                kind,
+                target: target.to_owned(),
+            })
+        };
+
+        for target in includes(&chapter.content) {
+            if !source_dir.join(dir).join(target).is_file() {
+                problem(ProblemKind::MissingInclude, target);
+            }
+        }
+
+        for event in new_cmark_parser(&chapter.content, false) {
+            match event {
+                Event::Start(Tag::Link { dest_url, .. }) if !is_external(&dest_url) => {
+                    let (file, fragment) = dest_url.split_once('#').unwrap_or((&dest_url, ""));
+                    // Drop the query, and decode the path like the EPUB validator
+                    let file = file.split_once('?').map_or(file, |(file, _)| file);
+                    let file = percent_decode_str(file).decode_utf8_lossy();
+                    let fragment = percent_decode_str(fragment).decode_utf8_lossy();
+                    let target = if file.is_empty() {
+                        path.to_path_buf()
+                    } else {
+                        normalize_path(&dir.join(&*file))
+                    };
+                    // mdbook rewrites links to chapters to their HTML page
+                    let chapter = if target.extension().is_some_and(|ext| ext == "html") {
+                        target.with_extension("md")
+                    } else {
+                        target.clone()
+                    };
+                    match anchors.get(chapter.as_path()) {
+                        Some(ids) => {
+                            if !fragment.is_empty()
+                                && !ids.contains(&*fragment)
+                                && !with_includes.contains(chapter.as_path())
+                            {
+                                problem(ProblemKind::MissingAnchor, &dest_url);
+                            }
+                        }
+                        None => {
+                            if !source_dir.join(&target).exists() {
+                                problem(ProblemKind::BrokenLink, &dest_url);
+                            }
+                        }
+                    }
+                }
+                Event::Start(Tag::Image { dest_url, .. })
+                    if !is_external(&dest_url)
+                        && !source_dir
+                            .join(dir)
+                            .join(&*percent_decode_str(&dest_url).decode_utf8_lossy())
+                            .is_file() =>
+                {
+                    problem(ProblemKind::MissingImage, &dest_url);
+                }
+                _ => {}
+            }
+        }
+    }
+    Report { problems }
+}
+
+/// The path of the report of the EPUB at `epub_path`.
+pub(crate) fn report_path(epub_path: &Path) -> PathBuf {
+    PathBuf::from(format!(
+        "{}.links.json",
+        epub_path.with_extension("").display()
+    ))
+}
+
+/// Writes `report` as JSON next to the EPUB at `epub_path` (relative to `dest`)
+/// and logs a summary.
+pub(crate) fn write_report(report: &Report, dest: &Path, epub_path: &Path) -> Result<LinkReport> {
+    let path = report_path(epub_path);
+    std::fs::write(dest.join(&path), serde_json::to_string_pretty(report)?)?;
+
+    let link_report = LinkReport {
+        path,
+        broken_links: report.count(ProblemKind::BrokenLink),
+        missing_anchors: report.count(ProblemKind::MissingAnchor),
+        missing_images: report.count(ProblemKind::MissingImage),
+        missing_includes: report.count(ProblemKind::MissingInclude),
+    };
+    if report.problems.is_empty() {
+        log::info!("No broken links in {}", epub_path.display());
+    } else {
+        log::warn!(
+            "{}: {} broken links, {} missing anchors, {} missing images, {} missing includes (see {})",
+            epub_path.display(),
+            link_report.broken_links,
+            link_report.missing_anchors,
+            link_report.missing_images,
+            link_report.missing_includes,
+            link_report.path.display()
+        );
+        for problem in &report.problems {
+            log::debug!(
+                "{}: {:?} {}",
+                problem.chapter.display(),
+                problem.kind,
+                problem.target
+            );
+        }
+    }
+    Ok(link_report)
+}
+
+/// Returns whether `url` points outside of the book.
+fn is_external(url: &str) -> bool {
+    url.contains(':') || url.starts_with('/')
+}
+
+/// The ids mdbook gives to the headings of `content`, and the ids of its HTML elements.
+fn anchors(content: &str) -> HashSet<String> {
+    let mut anchors = HashSet::new();
+    let mut counter = HashMap::new();
+    let mut heading: Option<(Option<String>, String)> = None;
+    for event in new_cmark_parser(content, false) {
+        match event {
+            Event::Start(Tag::Heading { id, .. }) => {
+                heading = Some((id.map(|id| id.to_string()), String::new()));
+            }
+            Event::End(TagEnd::Heading(_)) => {
+                if let Some((id, text)) = heading.take() {
+                    anchors
+                        .insert(id.unwrap_or_else(|| unique_id_from_content(&text, &mut counter)));
+                }
+            }
+            Event::Text(text) | Event::Code(text) => {
+                if let Some((_, heading)) = &mut heading {
+                    heading.push_str(&text);
+                }
+            }
+            Event::Html(html) | Event::InlineHtml(html) => {
+                for (start, _) in html.match_indices(" id=\"") {
+                    let value = &html[start + 5..];
+                    if let Some(end) = value.find('"') {
+                        anchors.insert(value[..end].to_owned());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    anchors
+}
+
+/// The files included in `content` by `{{#include}}`, `{{#rustdoc_include}}`
+/// and `{{#playground}}`, without their anchors or line ranges.
+fn includes(content: &str) -> Vec<&str> {
+    let mut includes = Vec::new();
+    for (start, _) in content.match_indices("{{#") {
+        // Escaped links are left as is by mdbook
+        if content[..start].ends_with('\\') {
+            continue;
+        }
+        let Some(end) = content[start..].find("}}") else {
+            continue;
+        };
+        let mut args = content[start + 3..start + end].split_whitespace();
+        if !matches!(
+            args.next(),
+            Some("include" | "rustdoc_include" | "playground" | "playpen")
+        ) {
+            continue;
+        }
+        if let Some(target) = args.next() {
+            includes.push(target.split(':').next().unwrap_or(target));
+        }
+    }
+    includes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anchors() {
+        let anchors = anchors(
+            "# Hello `Rust`\n\n## Hello Rust\n\n## Custom {#custom}\n\n<a id=\"raw\"></a>\n",
+        );
+        let mut anchors: Vec<_> = anchors.into_iter().collect();
+        anchors.sort();
+        assert_eq!(anchors, vec!["custom", "hello-rust", "hello-rust-1", "raw"]);
+    }
+
+    #[test]
+    fn test_check() {
+        let md = MDBook::load(Path::new("tests").join("links")).unwrap();
+
+        let report = check(&md);
+
+        let problems: Vec<_> = report
+            .problems
+            .iter()
+            .map(|problem| (problem.kind, problem.target.as_str()))
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                (ProblemKind::MissingInclude, "missing.rs"),
+                (ProblemKind::MissingAnchor, "chapter_2.md#nowhere"),
+                (ProblemKind::BrokenLink, "chapter_3.md"),
+                (ProblemKind::MissingImage, "img/missing.png"),
+                (ProblemKind::MissingAnchor, "#nowhere"),
+            ]
+        );
+        assert_eq!(report.count(ProblemKind::MissingAnchor), 2);
+    }
+
+    #[test]
+    fn test_includes() {
+        assert_eq!(
+            includes("{{#include ../listings/a.rs:main}}\n\\{{#include b.rs}}\n{{#title T}}\n{{#playground c.rs editable}}"),
+            vec!["../listings/a.rs", "c.rs"]
+        );
+    }
+}
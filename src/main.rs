@@ -2,7 +2,7 @@ use std::path::PathBuf;
 use std::process;
 
 use anyhow::{bail, Result};
-use clap::{crate_version, value_parser, Arg, ArgMatches, Command};
+use clap::{crate_version, value_parser, Arg, ArgAction, ArgMatches, Command};
 use env_logger::{Builder, Env};
 use log::{error, info};
 use mdbookshelf::{config::Config, Manifest};
@@ -44,6 +44,19 @@ fn cmd() -> Command {
                 .help("Sets the path of the bookshelf.toml config file")
                 .value_parser(value_parser!(PathBuf)),
         )
+        .arg(
+            Arg::new("force")
+                .short('f')
+                .long("force")
+                .help("Regenerates all books, even the ones unchanged since the last run")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("verify_reproducible")
+                .long("verify_reproducible")
+                .help("Rebuilds every book and fails if the EPUB differs (implies reproducible builds)")
+                .action(ArgAction::SetTrue),
+        )
 }
 
 fn cfg(matches: ArgMatches) -> Result<Config> {
@@ -108,6 +121,28 @@ fn cfg(matches: ArgMatches) -> Result<Config> {
         Some(templates_dir) => info!("Using templates in {}", templates_dir.display()),
         None => info!("No templates dir provided"),
     }
+
+    // Relative to bookshelf.toml
+    let paths = config
+        .colophon_template
+        .iter_mut()
+        .chain(config.cover_template.iter_mut())
+        .chain(config.image_cache_dir.iter_mut())
+        .chain(config.stylesheet.iter_mut())
+        .chain(
+            config
+                .book_repo_configs
+                .iter_mut()
+                .flat_map(|book| book.cover.iter_mut().chain(book.stylesheet.iter_mut())),
+        );
+    for path in paths {
+        if path.is_relative() {
+            *path = base.join(&path);
+        }
+    }
+
+    config.force = matches.get_flag("force");
+    config.verify_reproducible = matches.get_flag("verify_reproducible");
     Ok(config)
 }
 
@@ -328,6 +363,30 @@ templates-dir = "{}"
         Ok(())
     }
 
+    #[test]
+    fn test_force_option() -> Result<(), Box<dyn Error>> {
+        let dest = tempfile::tempdir()?;
+        let d = &dest.path().as_os_str().to_string_lossy();
+
+        let arg_matches = super::cmd().get_matches_from(vec!["mdbookshelf", "-d", d]);
+        assert!(!super::cfg(arg_matches).unwrap().force);
+        let arg_matches = super::cmd().get_matches_from(vec!["mdbookshelf", "-d", d, "--force"]);
+        assert!(super::cfg(arg_matches).unwrap().force);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_reproducible_option() -> Result<(), Box<dyn Error>> {
+        let dest = tempfile::tempdir()?;
+        let d = &dest.path().as_os_str().to_string_lossy();
+
+        let args = vec!["mdbookshelf", "-d", d, "--verify_reproducible"];
+        let config = super::cfg(super::cmd().get_matches_from(args)).unwrap();
+        assert!(config.verify_reproducible);
+        assert!(!config.reproducible);
+        Ok(())
+    }
+
     const CONFIG_TITLE: &str = "title = \"shelf\"\n";
     const CONFIG_BOOK: &str = r#"
 [[book]]
@@ -0,0 +1,975 @@
+//! Conversion of the TeX formulas written for mdbook's MathJax support, which e-readers
+//! don't run, to MathML or to plain text.
+
+use std::ops::Range;
+
+use mdbook::book::{Book, BookItem};
+use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+use mdbook::utils::new_cmark_parser;
+use pulldown_cmark::{Event, Tag};
+use serde::{Deserialize, Serialize};
+
+use crate::epub::escape_xml;
+
+/// What the formulas are rendered to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum MathOutput {
+    /// MathML, supported by EPUB3 readers
+    MathMl,
+    /// Plain text with Unicode symbols, for EPUB2 readers
+    Text,
+}
+
+/// mdbook preprocessor rendering the formulas of the chapters.
+pub(crate) struct MathPreprocessor {
+    pub output: MathOutput,
+}
+
+impl Preprocessor for MathPreprocessor {
+    fn name(&self) -> &str {
+        "mdbookshelf-math"
+    }
+
+    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> mdbook::errors::Result<Book> {
+        // FictionBook has no MathML
+        let output = match ctx.renderer.as_str() {
+            "fb2" => MathOutput::Text,
+            _ => self.output,
+        };
+        book.for_each_mut(|item| {
+            if let BookItem::Chapter(chapter) = item {
+                chapter.content = replace_formulas(&chapter.content, |tex, display| {
+                    render(tex, display, output)
+                });
+            }
+        });
+        Ok(book)
+    }
+
+    fn supports_renderer(&self, renderer: &str) -> bool {
+        renderer != "markdown"
+    }
+}
+
+/// Renders the TeX formula `tex` as inline HTML.
+pub(crate) fn render(tex: &str, display: bool, output: MathOutput) -> String {
+    let nodes = Parser::new(tex).formula();
+    match output {
+        MathOutput::MathMl => {
+            let mut mathml = String::new();
+            for node in &nodes {
+                node.mathml(&mut mathml, None);
+            }
+            format!(
+                r#"<math xmlns="http://www.w3.org/1998/Math/MathML"{} alttext="{}">{}</math>"#,
+                if display { r#" display="block""# } else { "" },
+                escape_xml(tex.trim()),
+                mathml
+            )
+        }
+        MathOutput::Text => {
+            let text: String = nodes.iter().map(Node::text).collect();
+            let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+            format!(
+                r#"<span class="{}">{}</span>"#,
+                if display { "math math-display" } else { "math" },
+                escape_xml(&text)
+            )
+        }
+    }
+}
+
+/// Replaces the formulas of the Markdown `content` (between `\\(` and `\\)`, `\\[` and `\\]`
+/// or `$$`) by `render(tex, display)`, leaving the code and the HTML blocks alone.
+fn replace_formulas(content: &str, render: impl Fn(&str, bool) -> String) -> String {
+    let code: Vec<Range<usize>> = new_cmark_parser(content, false)
+        .into_offset_iter()
+        .filter(|(event, _)| {
+            matches!(
+                event,
+                Event::Code(_) | Event::Html(_) | Event::Start(Tag::CodeBlock(_))
+            )
+        })
+        .map(|(_, range)| range)
+        .collect();
+
+    let mut output = String::with_capacity(content.len());
+    let mut i = 0;
+    let mut copied = 0;
+    // The ranges come in document order: `next` is the first one not ending before `i`
+    let mut next = 0;
+    while i < content.len() {
+        while code.get(next).is_some_and(|range| range.end <= i) {
+            next += 1;
+        }
+        if let Some(range) = code.get(next).filter(|range| range.start <= i) {
+            i = range.end;
+            continue;
+        }
+        let rest = &content[i..];
+        let delimiters = [
+            (r"\\(", r"\\)", false),
+            (r"\\[", r"\\]", true),
+            ("$$", "$$", true),
+        ];
+        let Some((open, close, display)) = delimiters
+            .into_iter()
+            .find(|(open, _, _)| rest.starts_with(open))
+        else {
+            i += rest.chars().next().map_or(1, char::len_utf8);
+            continue;
+        };
+        let start = i + open.len();
+        let Some(end) = content[start..].find(close).map(|end| start + end) else {
+            // Unmatched opening delimiter
+            i = start;
+            continue;
+        };
+        // The next code starts inside the formula
+        if code.get(next).is_some_and(|range| range.start < end) {
+            i = start;
+            continue;
+        }
+        output.push_str(&content[copied..i]);
+        output.push_str(&render(&unescape_markdown(&content[start..end]), display));
+        i = end + close.len();
+        copied = i;
+    }
+    output.push_str(&content[copied..]);
+    output
+}
+
+/// Removes the backslashes escaping ASCII punctuation, like Markdown does.
+fn unescape_markdown(tex: &str) -> String {
+    let mut unescaped = String::with_capacity(tex.len());
+    let mut chars = tex.chars().peekable();
+    while let Some(c) = chars.next() {
+        match chars.peek() {
+            Some(&next) if c == '\\' && next.is_ascii_punctuation() => {
+                unescaped.push(next);
+                chars.next();
+            }
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// A parsed formula element.
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    Identifier(String),
+    Number(String),
+    Operator(String),
+    Text(String),
+    Space(f32),
+    Row(Vec<Node>),
+    Fraction(Box<Node>, Box<Node>),
+    Root(Option<Box<Node>>, Box<Node>),
+    Scripts(Box<Node>, Option<Box<Node>>, Option<Box<Node>>),
+    Fenced(String, Box<Node>, String),
+    Variant(&'static str, Box<Node>),
+    Table(Vec<Vec<Node>>),
+}
+
+impl Node {
+    fn mathml(&self, output: &mut String, variant: Option<&str>) {
+        match self {
+            Node::Identifier(name) => match variant {
+                Some(variant) => output.push_str(&format!(
+                    r#"<mi mathvariant="{variant}">{}</mi>"#,
+                    escape_xml(name)
+                )),
+                None => output.push_str(&format!("<mi>{}</mi>", escape_xml(name))),
+            },
+            Node::Number(number) => output.push_str(&format!("<mn>{}</mn>", number)),
+            Node::Operator(operator) => {
+                output.push_str(&format!("<mo>{}</mo>", escape_xml(operator)))
+            }
+            Node::Text(text) => output.push_str(&format!("<mtext>{}</mtext>", escape_xml(text))),
+            Node::Space(width) => output.push_str(&format!(r#"<mspace width="{width}em"/>"#)),
+            Node::Row(nodes) => {
+                output.push_str("<mrow>");
+                for node in nodes {
+                    node.mathml(output, variant);
+                }
+                output.push_str("</mrow>");
+            }
+            Node::Fraction(numerator, denominator) => {
+                output.push_str("<mfrac>");
+                numerator.mathml(output, variant);
+                denominator.mathml(output, variant);
+                output.push_str("</mfrac>");
+            }
+            Node::Root(None, radicand) => {
+                output.push_str("<msqrt>");
+                radicand.mathml(output, variant);
+                output.push_str("</msqrt>");
+            }
+            Node::Root(Some(index), radicand) => {
+                output.push_str("<mroot>");
+                radicand.mathml(output, variant);
+                index.mathml(output, variant);
+                output.push_str("</mroot>");
+            }
+            Node::Scripts(base, sub, sup) => {
+                let tag = match (sub, sup) {
+                    (Some(_), Some(_)) => "msubsup",
+                    (Some(_), None) => "msub",
+                    _ => "msup",
+                };
+                output.push_str(&format!("<{tag}>"));
+                base.mathml(output, variant);
+                for script in [sub, sup].into_iter().flatten() {
+                    script.mathml(output, variant);
+                }
+                output.push_str(&format!("</{tag}>"));
+            }
+            Node::Fenced(left, content, right) => {
+                output.push_str("<mrow>");
+                if !left.is_empty() {
+                    output.push_str(&format!("<mo>{}</mo>", escape_xml(left)));
+                }
+                content.mathml(output, variant);
+                if !right.is_empty() {
+                    output.push_str(&format!("<mo>{}</mo>", escape_xml(right)));
+                }
+                output.push_str("</mrow>");
+            }
+            Node::Variant(variant, content) => content.mathml(output, Some(variant)),
+            Node::Table(rows) => {
+                output.push_str("<mtable>");
+                for row in rows {
+                    output.push_str("<mtr>");
+                    for cell in row {
+                        output.push_str("<mtd>");
+                        cell.mathml(output, variant);
+                        output.push_str("</mtd>");
+                    }
+                    output.push_str("</mtr>");
+                }
+                output.push_str("</mtable>");
+            }
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            Node::Identifier(name) | Node::Number(name) | Node::Text(name) => name.to_owned(),
+            Node::Operator(operator) if is_spaced(operator) => format!(" {operator} "),
+            Node::Operator(operator) if is_large(operator) => format!("{operator} "),
+            Node::Operator(operator) => operator.to_owned(),
+            Node::Space(_) => String::from(" "),
+            Node::Row(nodes) => nodes.iter().map(Node::text).collect(),
+            Node::Fraction(numerator, denominator) => {
+                format!("{}/{}", parenthesize(numerator), parenthesize(denominator))
+            }
+            Node::Root(index, radicand) => {
+                let sign = match index.as_ref().map(|index| index.text()).as_deref() {
+                    None => String::from("√"),
+                    Some("3") => String::from("∛"),
+                    Some("4") => String::from("∜"),
+                    Some(index) => format!("{}√", to_script(index, SUPERSCRIPTS, "^")),
+                };
+                format!("{sign}{}", parenthesize(radicand))
+            }
+            Node::Scripts(base, sub, sup) => {
+                let mut text = base.text().trim_end().to_owned();
+                if let Some(sub) = sub {
+                    text.push_str(&to_script(&sub.text(), SUBSCRIPTS, "_"));
+                }
+                if let Some(sup) = sup {
+                    text.push_str(&to_script(&sup.text(), SUPERSCRIPTS, "^"));
+                }
+                if matches!(base.as_ref(), Node::Operator(operator) if is_large(operator)) {
+                    text.push(' ');
+                }
+                text
+            }
+            Node::Fenced(left, content, right) => format!("{left}{}{right}", content.text()),
+            Node::Variant("double-struck", content) => content
+                .text()
+                .chars()
+                .map(|c| match c {
+                    'C' => 'ℂ',
+                    'N' => 'ℕ',
+                    'P' => 'ℙ',
+                    'Q' => 'ℚ',
+                    'R' => 'ℝ',
+                    'Z' => 'ℤ',
+                    c => c,
+                })
+                .collect(),
+            Node::Variant(_, content) => content.text(),
+            Node::Table(rows) => rows
+                .iter()
+                .map(|row| row.iter().map(Node::text).collect::<Vec<_>>().join(", "))
+                .collect::<Vec<_>>()
+                .join("; "),
+        }
+    }
+}
+
+/// Returns whether `operator` is surrounded by spaces in plain text.
+fn is_spaced(operator: &str) -> bool {
+    matches!(
+        operator,
+        "+" | "-"
+            | "−"
+            | "="
+            | "<"
+            | ">"
+            | "±"
+            | "∓"
+            | "×"
+            | "÷"
+            | "≤"
+            | "≥"
+            | "≠"
+            | "≈"
+            | "≡"
+            | "∼"
+            | "∝"
+            | "→"
+            | "←"
+            | "⇒"
+            | "⇐"
+            | "↔"
+            | "⇔"
+            | "↦"
+            | "⟹"
+            | "∈"
+            | "∉"
+            | "⊂"
+            | "⊆"
+            | "⊃"
+            | "⊇"
+            | "∪"
+            | "∩"
+            | "∧"
+            | "∨"
+            | "≅"
+            | "≔"
+            | "≪"
+            | "≫"
+    )
+}
+
+/// Returns whether `operator` is a large operator, followed by a space in plain text.
+fn is_large(operator: &str) -> bool {
+    matches!(
+        operator,
+        "∑" | "∏" | "∐" | "∫" | "∬" | "∭" | "∮" | "⋃" | "⋂"
+    )
+}
+
+/// Wraps the plain text of `node` in parentheses, unless it is a single term.
+fn parenthesize(node: &Node) -> String {
+    let text = node.text().trim().to_owned();
+    if text.chars().count() <= 1
+        || text.chars().all(char::is_alphanumeric)
+        || matches!(node, Node::Fenced(..))
+    {
+        text
+    } else {
+        format!("({text})")
+    }
+}
+
+const SUPERSCRIPTS: &[(char, char)] = &[
+    ('0', '⁰'),
+    ('1', '¹'),
+    ('2', '²'),
+    ('3', '³'),
+    ('4', '⁴'),
+    ('5', '⁵'),
+    ('6', '⁶'),
+    ('7', '⁷'),
+    ('8', '⁸'),
+    ('9', '⁹'),
+    ('+', '⁺'),
+    ('-', '⁻'),
+    ('−', '⁻'),
+    ('=', '⁼'),
+    ('(', '⁽'),
+    (')', '⁾'),
+    ('i', 'ⁱ'),
+    ('n', 'ⁿ'),
+    ('′', '′'),
+];
+
+const SUBSCRIPTS: &[(char, char)] = &[
+    ('0', '₀'),
+    ('1', '₁'),
+    ('2', '₂'),
+    ('3', '₃'),
+    ('4', '₄'),
+    ('5', '₅'),
+    ('6', '₆'),
+    ('7', '₇'),
+    ('8', '₈'),
+    ('9', '₉'),
+    ('+', '₊'),
+    ('-', '₋'),
+    ('−', '₋'),
+    ('=', '₌'),
+    ('(', '₍'),
+    (')', '₎'),
+    ('a', 'ₐ'),
+    ('e', 'ₑ'),
+    ('i', 'ᵢ'),
+    ('j', 'ⱼ'),
+    ('k', 'ₖ'),
+    ('n', 'ₙ'),
+    ('x', 'ₓ'),
+];
+
+/// Writes `text` with the Unicode `scripts`, or after `marker` if some character has none.
+fn to_script(text: &str, scripts: &[(char, char)], marker: &str) -> String {
+    let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let script: Option<String> = text
+        .chars()
+        .map(|c| {
+            scripts
+                .iter()
+                .find(|(from, _)| *from == c)
+                .map(|(_, to)| *to)
+        })
+        .collect();
+    match script {
+        Some(script) => script,
+        None if text.chars().count() == 1 => format!("{marker}{text}"),
+        None => format!("{marker}({text})"),
+    }
+}
+
+/// Recursive descent parser of the TeX math subset supported by MathJax.
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(tex: &str) -> Parser {
+        Parser {
+            chars: tex.chars().collect(),
+            pos: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    /// Returns whether the next command is `name`, without consuming it.
+    fn at_command(&self, name: &str) -> bool {
+        let rest: String = self.chars[self.pos..].iter().take(name.len() + 2).collect();
+        rest.strip_prefix('\\')
+            .and_then(|rest| rest.strip_prefix(name))
+            .is_some_and(|after| !after.starts_with(|c: char| c.is_ascii_alphabetic()))
+    }
+
+    /// Parses the whole formula, skipping the unbalanced closing tokens.
+    fn formula(&mut self) -> Vec<Node> {
+        let mut nodes = self.row();
+        while let Some(c) = self.peek() {
+            if c == '\\' && self.chars.get(self.pos + 1) == Some(&'\\') {
+                self.pos += 2;
+            } else if c == '\\' {
+                self.pos += 1;
+                self.command();
+            } else {
+                self.pos += 1;
+            }
+            nodes.extend(self.row());
+        }
+        nodes
+    }
+
+    /// Parses nodes until the end of the formula, of the group or of the table cell.
+    fn row(&mut self) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None | Some('}') | Some('&') => break,
+                _ if self.at_command("right") || self.at_command("end") => break,
+                Some('\\') if self.chars.get(self.pos + 1) == Some(&'\\') => break,
+                _ => nodes.push(self.scripted()),
+            }
+        }
+        nodes
+    }
+
+    /// Parses a node and its subscript and superscript.
+    fn scripted(&mut self) -> Node {
+        let base = match self.peek() {
+            Some('^' | '_') => Node::Row(Vec::new()),
+            _ => self.primary(),
+        };
+        let (mut sub, mut sup) = (None, None);
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some('_') if sub.is_none() => {
+                    self.pos += 1;
+                    sub = Some(Box::new(self.argument()));
+                }
+                Some('^') if sup.is_none() => {
+                    self.pos += 1;
+                    sup = Some(Box::new(self.argument()));
+                }
+                Some('\'') if sup.is_none() => {
+                    let mut primes = String::new();
+                    while self.peek() == Some('\'') {
+                        primes.push('′');
+                        self.pos += 1;
+                    }
+                    sup = Some(Box::new(Node::Operator(primes)));
+                }
+                _ => break,
+            }
+        }
+        if sub.is_none() && sup.is_none() {
+            base
+        } else {
+            Node::Scripts(Box::new(base), sub, sup)
+        }
+    }
+
+    /// Parses a command argument: a group or a single token.
+    fn argument(&mut self) -> Node {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.primary(),
+            Some(c) if c.is_ascii_digit() => {
+                // `x^23` only raises the 2
+                self.pos += 1;
+                Node::Number(c.to_string())
+            }
+            Some(_) => self.primary(),
+            None => Node::Row(Vec::new()),
+        }
+    }
+
+    fn primary(&mut self) -> Node {
+        let Some(c) = self.peek() else {
+            return Node::Row(Vec::new());
+        };
+        self.pos += 1;
+        match c {
+            '{' => {
+                let nodes = self.row();
+                if self.peek() == Some('}') {
+                    self.pos += 1;
+                }
+                Node::Row(nodes)
+            }
+            '\\' => self.command(),
+            c if c.is_ascii_digit() || c == '.' => {
+                let mut number = c.to_string();
+                while let Some(c) = self.peek().filter(|c| c.is_ascii_digit() || *c == '.') {
+                    number.push(c);
+                    self.pos += 1;
+                }
+                Node::Number(number)
+            }
+            c if c.is_alphabetic() => Node::Identifier(c.to_string()),
+            '-' => Node::Operator(String::from("−")),
+            c => Node::Operator(c.to_string()),
+        }
+    }
+
+    /// Parses the command following a backslash.
+    fn command(&mut self) -> Node {
+        let Some(first) = self.peek() else {
+            return Node::Operator(String::from("\\"));
+        };
+        if !first.is_ascii_alphabetic() {
+            self.pos += 1;
+            return match first {
+                ',' => Node::Space(0.167),
+                ':' | '>' => Node::Space(0.222),
+                ';' => Node::Space(0.278),
+                ' ' => Node::Space(0.25),
+                '!' => Node::Row(Vec::new()),
+                '|' => Node::Operator(String::from("‖")),
+                c => Node::Operator(c.to_string()),
+            };
+        }
+        let mut name = String::new();
+        while let Some(c) = self.peek().filter(char::is_ascii_alphabetic) {
+            name.push(c);
+            self.pos += 1;
+        }
+        match name.as_str() {
+            "frac" | "dfrac" | "tfrac" | "cfrac" => {
+                let numerator = self.argument();
+                let denominator = self.argument();
+                Node::Fraction(Box::new(numerator), Box::new(denominator))
+            }
+            "binom" => {
+                let n = self.argument();
+                let k = self.argument();
+                let table = Node::Table(vec![vec![n], vec![k]]);
+                Node::Fenced(String::from("("), Box::new(table), String::from(")"))
+            }
+            "sqrt" => {
+                self.skip_whitespace();
+                let index = if self.peek() == Some('[') {
+                    self.pos += 1;
+                    let start = self.pos;
+                    while self.peek().is_some_and(|c| c != ']') {
+                        self.pos += 1;
+                    }
+                    let index: String = self.chars[start..self.pos].iter().collect();
+                    self.pos += 1;
+                    Some(Box::new(Node::Row(Parser::new(&index).row())))
+                } else {
+                    None
+                };
+                Node::Root(index, Box::new(self.argument()))
+            }
+            "left" => {
+                let left = self.delimiter();
+                let content = self.row();
+                let right = if self.at_command("right") {
+                    self.pos += "right".len() + 1;
+                    self.delimiter()
+                } else {
+                    String::new()
+                };
+                Node::Fenced(left, Box::new(Node::Row(content)), right)
+            }
+            "right" => Node::Row(Vec::new()),
+            "end" => {
+                self.raw_argument();
+                Node::Row(Vec::new())
+            }
+            "text" | "textrm" | "textit" | "textbf" | "mbox" => Node::Text(self.raw_argument()),
+            "mathrm" | "operatorname" => Node::Identifier(self.raw_argument()),
+            "mathbb" => Node::Variant("double-struck", Box::new(self.argument())),
+            "mathbf" | "boldsymbol" => Node::Variant("bold", Box::new(self.argument())),
+            "mathit" => Node::Variant("italic", Box::new(self.argument())),
+            "mathcal" => Node::Variant("script", Box::new(self.argument())),
+            "mathfrak" => Node::Variant("fraktur", Box::new(self.argument())),
+            "quad" => Node::Space(1.0),
+            "qquad" => Node::Space(2.0),
+            "begin" => self.environment(),
+            "displaystyle" | "textstyle" | "limits" | "nolimits" | "big" | "Big" | "bigg"
+            | "Bigg" => Node::Row(Vec::new()),
+            name => match symbol(name) {
+                Some((true, symbol)) => Node::Operator(symbol.to_owned()),
+                Some((false, symbol)) => Node::Identifier(symbol.to_owned()),
+                None => Node::Text(format!("\\{name}")),
+            },
+        }
+    }
+
+    /// Parses the delimiter following `\left` or `\right`.
+    fn delimiter(&mut self) -> String {
+        self.skip_whitespace();
+        match self.primary() {
+            Node::Operator(operator) if operator == "." => String::new(),
+            Node::Operator(operator) | Node::Identifier(operator) => operator,
+            _ => String::new(),
+        }
+    }
+
+    /// Reads a braced argument as text, without parsing it.
+    fn raw_argument(&mut self) -> String {
+        self.skip_whitespace();
+        if self.peek() != Some('{') {
+            return self
+                .peek()
+                .map(|c| {
+                    self.pos += 1;
+                    c.to_string()
+                })
+                .unwrap_or_default();
+        }
+        self.pos += 1;
+        let mut depth = 0;
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                '{' => depth += 1,
+                '}' if depth == 0 => break,
+                '}' => depth -= 1,
+                _ => {}
+            }
+            text.push(c);
+        }
+        text
+    }
+
+    /// Parses a `\begin{...}` matrix-like environment, until its `\end{...}`.
+    fn environment(&mut self) -> Node {
+        let name = self.raw_argument();
+        let mut rows = Vec::new();
+        let mut row = Vec::new();
+        loop {
+            row.push(Node::Row(self.row()));
+            self.skip_whitespace();
+            match self.peek() {
+                Some('&') => self.pos += 1,
+                Some('\\') if self.chars.get(self.pos + 1) == Some(&'\\') => {
+                    self.pos += 2;
+                    rows.push(std::mem::take(&mut row));
+                }
+                Some('}') => self.pos += 1,
+                _ => {
+                    if self.at_command("end") {
+                        self.pos += "end".len() + 1;
+                        self.raw_argument();
+                    }
+                    break;
+                }
+            }
+        }
+        if row.iter().any(|cell| cell != &Node::Row(Vec::new())) {
+            rows.push(row);
+        }
+        let table = Box::new(Node::Table(rows));
+        let (left, right) = match name.trim_end_matches('*') {
+            "pmatrix" => ("(", ")"),
+            "bmatrix" => ("[", "]"),
+            "Bmatrix" => ("{", "}"),
+            "vmatrix" => ("|", "|"),
+            "Vmatrix" => ("‖", "‖"),
+            "cases" => ("{", ""),
+            _ => return *table,
+        };
+        Node::Fenced(left.to_owned(), table, right.to_owned())
+    }
+}
+
+/// The character of the symbol command `name`, and whether it is an operator.
+fn symbol(name: &str) -> Option<(bool, &'static str)> {
+    // Function names are written upright, like multi-letter identifiers
+    if let Some(function) = FUNCTIONS.iter().find(|function| **function == name) {
+        return Some((false, function));
+    }
+    let identifier = match name {
+        "alpha" => "α",
+        "beta" => "β",
+        "gamma" => "γ",
+        "delta" => "δ",
+        "epsilon" => "ϵ",
+        "varepsilon" => "ε",
+        "zeta" => "ζ",
+        "eta" => "η",
+        "theta" => "θ",
+        "vartheta" => "ϑ",
+        "iota" => "ι",
+        "kappa" => "κ",
+        "lambda" => "λ",
+        "mu" => "μ",
+        "nu" => "ν",
+        "xi" => "ξ",
+        "pi" => "π",
+        "varpi" => "ϖ",
+        "rho" => "ρ",
+        "varrho" => "ϱ",
+        "sigma" => "σ",
+        "varsigma" => "ς",
+        "tau" => "τ",
+        "upsilon" => "υ",
+        "phi" => "ϕ",
+        "varphi" => "φ",
+        "chi" => "χ",
+        "psi" => "ψ",
+        "omega" => "ω",
+        "Gamma" => "Γ",
+        "Delta" => "Δ",
+        "Theta" => "Θ",
+        "Lambda" => "Λ",
+        "Xi" => "Ξ",
+        "Pi" => "Π",
+        "Sigma" => "Σ",
+        "Upsilon" => "Υ",
+        "Phi" => "Φ",
+        "Psi" => "Ψ",
+        "Omega" => "Ω",
+        "infty" => "∞",
+        "partial" => "∂",
+        "nabla" => "∇",
+        "emptyset" | "varnothing" => "∅",
+        "ell" => "ℓ",
+        "hbar" => "ℏ",
+        "aleph" => "ℵ",
+        "Re" => "ℜ",
+        "Im" => "ℑ",
+        _ => "",
+    };
+    if !identifier.is_empty() {
+        return Some((false, identifier));
+    }
+    let operator = match name {
+        "sum" => "∑",
+        "prod" => "∏",
+        "coprod" => "∐",
+        "int" => "∫",
+        "iint" => "∬",
+        "iiint" => "∭",
+        "oint" => "∮",
+        "bigcup" => "⋃",
+        "bigcap" => "⋂",
+        "pm" => "±",
+        "mp" => "∓",
+        "times" => "×",
+        "div" => "÷",
+        "cdot" => "⋅",
+        "ast" => "∗",
+        "star" => "⋆",
+        "circ" => "∘",
+        "bullet" => "∙",
+        "le" | "leq" => "≤",
+        "ge" | "geq" => "≥",
+        "ne" | "neq" => "≠",
+        "approx" => "≈",
+        "equiv" => "≡",
+        "sim" => "∼",
+        "simeq" => "≃",
+        "cong" => "≅",
+        "propto" => "∝",
+        "ll" => "≪",
+        "gg" => "≫",
+        "coloneqq" => "≔",
+        "to" | "rightarrow" => "→",
+        "gets" | "leftarrow" => "←",
+        "Rightarrow" => "⇒",
+        "Leftarrow" => "⇐",
+        "leftrightarrow" => "↔",
+        "Leftrightarrow" | "iff" => "⇔",
+        "implies" => "⟹",
+        "mapsto" => "↦",
+        "in" => "∈",
+        "notin" => "∉",
+        "ni" => "∋",
+        "subset" => "⊂",
+        "subseteq" => "⊆",
+        "supset" => "⊃",
+        "supseteq" => "⊇",
+        "cup" => "∪",
+        "cap" => "∩",
+        "setminus" => "∖",
+        "forall" => "∀",
+        "exists" => "∃",
+        "neg" | "lnot" => "¬",
+        "land" | "wedge" => "∧",
+        "lor" | "vee" => "∨",
+        "oplus" => "⊕",
+        "otimes" => "⊗",
+        "ldots" | "dots" => "…",
+        "cdots" => "⋯",
+        "vdots" => "⋮",
+        "ddots" => "⋱",
+        "langle" => "⟨",
+        "rangle" => "⟩",
+        "lfloor" => "⌊",
+        "rfloor" => "⌋",
+        "lceil" => "⌈",
+        "rceil" => "⌉",
+        "lvert" | "rvert" | "vert" | "mid" => "|",
+        "lVert" | "rVert" | "Vert" | "parallel" => "‖",
+        "perp" => "⊥",
+        "angle" => "∠",
+        "prime" => "′",
+        "lbrace" => "{",
+        "rbrace" => "}",
+        "bmod" | "mod" => "mod",
+        _ => return None,
+    };
+    Some((true, operator))
+}
+
+const FUNCTIONS: &[&str] = &[
+    "sin", "cos", "tan", "cot", "sec", "csc", "arcsin", "arccos", "arctan", "sinh", "cosh", "tanh",
+    "log", "ln", "lg", "exp", "lim", "limsup", "liminf", "max", "min", "sup", "inf", "det", "dim",
+    "ker", "deg", "gcd", "arg", "Pr", "hom",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mathml(tex: &str) -> String {
+        let math = render(tex, false, MathOutput::MathMl);
+        let start = math.find("\">").unwrap() + 2;
+        math[start..math.len() - "</math>".len()].to_owned()
+    }
+
+    fn text(tex: &str) -> String {
+        let span = render(tex, false, MathOutput::Text);
+        span["<span class=\"math\">".len()..span.len() - "</span>".len()].to_owned()
+    }
+
+    #[test]
+    fn test_mathml() {
+        assert_eq!(
+            mathml(r"x^2 + \frac{1}{n}"),
+            "<msup><mi>x</mi><mn>2</mn></msup><mo>+</mo><mfrac><mrow><mn>1</mn></mrow><mrow><mi>n</mi></mrow></mfrac>"
+        );
+        assert_eq!(
+            mathml(r"\sum_{i=1}^n a_i"),
+            "<msubsup><mo>∑</mo><mrow><mi>i</mi><mo>=</mo><mn>1</mn></mrow><mi>n</mi></msubsup><msub><mi>a</mi><mi>i</mi></msub>"
+        );
+        assert_eq!(
+            mathml(r"\sqrt[3]{x} \le \left( \alpha \right)"),
+            "<mroot><mrow><mi>x</mi></mrow><mrow><mn>3</mn></mrow></mroot><mo>≤</mo><mrow><mo>(</mo><mrow><mi>α</mi></mrow><mo>)</mo></mrow>"
+        );
+        assert_eq!(
+            mathml(r"\mathbb{R} \text{ if } \sin x"),
+            r#"<mrow><mi mathvariant="double-struck">R</mi></mrow><mtext> if </mtext><mi>sin</mi><mi>x</mi>"#
+        );
+        assert_eq!(
+            mathml(r"\begin{pmatrix} a & b \\ c & d \end{pmatrix}"),
+            "<mrow><mo>(</mo><mtable><mtr><mtd><mrow><mi>a</mi></mrow></mtd><mtd><mrow><mi>b</mi></mrow></mtd></mtr><mtr><mtd><mrow><mi>c</mi></mrow></mtd><mtd><mrow><mi>d</mi></mrow></mtd></mtr></mtable><mo>)</mo></mrow>"
+        );
+        assert!(render("a < b", true, MathOutput::MathMl).starts_with(
+            r#"<math xmlns="http://www.w3.org/1998/Math/MathML" display="block" alttext="a &lt; b"><mi>a</mi><mo>&lt;</mo>"#
+        ));
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(text(r"x^2 + \frac{1}{n+1}"), "x² + 1/(n + 1)");
+        assert_eq!(text(r"\sum_{i=1}^{n} a_i \le \infty"), "∑ᵢ₌₁ⁿ aᵢ ≤ ∞");
+        assert_eq!(text(r"\sqrt{b^2 - 4ac}"), "√(b² − 4ac)");
+        assert_eq!(text(r"x \in \mathbb{R}"), "x ∈ ℝ");
+        assert_eq!(text(r"e^{i\pi}"), "e^(iπ)");
+    }
+
+    #[test]
+    fn test_replace_formulas() {
+        let content = concat!(
+            "Inline \\\\( a_1 \\\\) and `\\\\( code \\\\)`.\n\n",
+            "\\\\[ \\\\{ x \\\\} \\\\]\n\n$$ y $$\n\n",
+            "```\n$$ z $$\n```\n"
+        );
+        let replaced = replace_formulas(content, |tex, display| format!("[{tex}|{display}]"));
+        assert_eq!(
+            replaced,
+            concat!(
+                "Inline [ a_1 |false] and `\\\\( code \\\\)`.\n\n",
+                "[ \\{ x \\} |true]\n\n[ y |true]\n\n",
+                "```\n$$ z $$\n```\n"
+            )
+        );
+
+        let content = "<pre>echo $$</pre>\n\nUnmatched \\\\( then $$ w $$\n";
+        assert_eq!(
+            replace_formulas(content, |tex, display| format!("[{tex}|{display}]")),
+            "<pre>echo $$</pre>\n\nUnmatched \\\\( then [ w |true]\n"
+        );
+    }
+}
@@ -0,0 +1,131 @@
+//! E-reader output profiles.
+
+/// Settings bundled for a family of e-readers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Profile {
+    /// The name used in `bookshelf.toml` and in the manifest
+    pub name: &'static str,
+    /// CSS prepended to the stylesheet, before the shelf and book CSS
+    pub css: &'static str,
+    /// Larger images are downscaled to fit (width, height)
+    pub max_image_size: Option<(u32, u32)>,
+    /// The EPUB version (2 or 3)
+    pub epub_version: u8,
+    /// Keep the fonts embedded in the book
+    pub embed_fonts: bool,
+    /// The reader supports HTML5 elements, otherwise they are replaced by `div`s
+    pub html5: bool,
+    /// Quality of the JPEG images re-encoded by the image optimization
+    pub jpeg_quality: u8,
+    /// The reader can't display SVG images, which the image optimization rasterizes to PNG
+    pub rasterize_svg: bool,
+    /// The syntect theme of the highlighted code blocks
+    pub highlight_theme: &'static str,
+}
+
+/// The built-in profiles.
+pub(crate) const PROFILES: &[Profile] = &[
+    Profile {
+        name: "kindle",
+        css: "pre, code { white-space: pre-wrap; word-wrap: break-word; }\n\
+              img { max-width: 100%; height: auto; }\n",
+        max_image_size: Some((1600, 2560)),
+        epub_version: 3,
+        embed_fonts: false,
+        html5: true,
+        jpeg_quality: 80,
+        rasterize_svg: true,
+        highlight_theme: "InspiredGitHub",
+    },
+    Profile {
+        name: "kobo",
+        css: "pre { white-space: pre-wrap; word-wrap: break-word; font-size: 0.8em; }\n\
+              img { max-width: 100%; height: auto; }\n",
+        max_image_size: Some((1264, 1680)),
+        epub_version: 3,
+        embed_fonts: true,
+        html5: true,
+        jpeg_quality: 85,
+        rasterize_svg: false,
+        highlight_theme: "InspiredGitHub",
+    },
+    Profile {
+        name: "generic-epub3",
+        css: "",
+        max_image_size: None,
+        epub_version: 3,
+        embed_fonts: true,
+        html5: true,
+        jpeg_quality: 90,
+        rasterize_svg: false,
+        highlight_theme: "Solarized (light)",
+    },
+    Profile {
+        name: "epub2-compat",
+        css: "pre { white-space: pre-wrap; }\nimg { max-width: 100%; }\n",
+        max_image_size: Some((1200, 1600)),
+        epub_version: 2,
+        embed_fonts: true,
+        html5: false,
+        jpeg_quality: 75,
+        rasterize_svg: true,
+        highlight_theme: "InspiredGitHub",
+    },
+];
+
+/// Returns the built-in profile called `name`.
+pub(crate) fn find(name: &str) -> Option<&'static Profile> {
+    PROFILES.iter().find(|profile| profile.name == name)
+}
+
+/// HTML5 elements which EPUB2 readers don't know about.
+const HTML5_ELEMENTS: &[&str] = &[
+    "article",
+    "aside",
+    "details",
+    "figcaption",
+    "figure",
+    "footer",
+    "header",
+    "main",
+    "nav",
+    "section",
+    "summary",
+];
+
+/// Replaces the HTML5 elements of `xhtml` by `div`s, keeping their attributes.
+pub(crate) fn downgrade_html5(xhtml: &str) -> String {
+    let mut xhtml = xhtml.to_owned();
+    for element in HTML5_ELEMENTS {
+        for (from, to) in [
+            (format!("<{element}>"), String::from("<div>")),
+            (format!("<{element} "), String::from("<div ")),
+            (format!("</{element}>"), String::from("</div>")),
+        ] {
+            xhtml = xhtml.replace(&from, &to);
+        }
+    }
+    xhtml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        assert_eq!(find("kobo").unwrap().name, "kobo");
+        assert_eq!(find("epub2-compat").unwrap().epub_version, 2);
+        assert!(find("nook").is_none());
+    }
+
+    #[test]
+    fn test_downgrade_html5() {
+        assert_eq!(
+            downgrade_html5(
+                r#"<section id="a"><header>A</header><p>b</p><summary>c</summary><sectioned/></section>"#
+            ),
+            r#"<div id="a"><div>A</div><p>b</p><div>c</div><sectioned/></div>"#
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//! Localization of the remote images of the generated EPUBs, which most e-readers won't load.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::epub::Epub;
+use crate::single_file::replace_element_attribute;
+use crate::ImageFailure;
+
+/// Directory of the localized images, next to the package document.
+const IMAGES_DIR: &str = "remote-images";
+
+/// Settings of the localization of remote images.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Localization {
+    /// Hosts (and their subdomains) images may be downloaded from, any if empty
+    pub allowlist: Vec<String>,
+    /// Maximum size of an image, in bytes
+    pub max_size: u64,
+    /// Maximum size of all the images localized in a book, in bytes
+    pub max_total_size: u64,
+    /// Base URL of a stand-in server the images are requested from instead
+    pub proxy: Option<String>,
+    /// Directory the downloaded images are cached in
+    pub cache_dir: Option<PathBuf>,
+}
+
+/// Downloads the remote images of the content documents of `epub`, embeds them
+/// and rewrites their references. Returns the images which couldn't be localized.
+pub(crate) fn localize(epub: &mut Epub, localization: &Localization) -> Result<Vec<ImageFailure>> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(30))
+        .user_agent(concat!("mdbookshelf/", env!("CARGO_PKG_VERSION")))
+        .build();
+    let package_path = epub.package_path()?;
+    let package_dir = package_path
+        .rfind('/')
+        .map_or("", |end| &package_path[..=end]);
+
+    let mut localized: HashMap<String, String> = HashMap::new();
+    let mut failed = HashSet::new();
+    let mut failures = Vec::new();
+    let mut total_size = 0;
+    for name in epub.content_documents()? {
+        let document = name.strip_prefix(package_dir).unwrap_or(&name);
+        let prefix = "../".repeat(document.matches('/').count());
+        let mut images = Vec::new();
+        let mut localize_image = |src: &str| {
+            if !src.starts_with("http://") && !src.starts_with("https://") {
+                return None;
+            }
+            let url = src.replace("&amp;", "&");
+            if let Some(href) = localized.get(&url) {
+                return Some(format!("{prefix}{href}"));
+            }
+            if failed.contains(&url) {
+                return None;
+            }
+            let image = download(&agent, localization, &url).and_then(|(data, extension)| {
+                if total_size + data.len() as u64 > localization.max_total_size {
+                    bail!(
+                        "The images of the book exceed {} bytes",
+                        localization.max_total_size
+                    );
+                }
+                Ok((data, extension))
+            });
+            match image {
+                Ok((data, (media_type, extension))) => {
+                    total_size += data.len() as u64;
+                    let digest = format!("{:x}", Sha256::digest(url.as_bytes()));
+                    let href = format!("{IMAGES_DIR}/{}.{extension}", &digest[..16]);
+                    images.push((
+                        format!("remote-{}", &digest[..16]),
+                        href.clone(),
+                        media_type,
+                        data,
+                    ));
+                    localized.insert(url, href.clone());
+                    Some(format!("{prefix}{href}"))
+                }
+                Err(e) => {
+                    log::warn!("Could not localize {}: {:#}", url, e);
+                    failures.push(ImageFailure {
+                        url: url.clone(),
+                        reason: format!("{e:#}"),
+                    });
+                    failed.insert(url);
+                    None
+                }
+            }
+        };
+        // Only the images: scripts, embeds and videos are left as is
+        let mut xhtml = epub.get_str(&name)?.to_owned();
+        for (element, attribute) in [("img", "src"), ("image", "xlink:href"), ("image", "href")] {
+            xhtml = replace_element_attribute(&xhtml, element, attribute, &mut localize_image);
+        }
+        epub.set(&name, xhtml.into_bytes());
+        for (id, href, media_type, data) in images {
+            epub.add_item(&id, &href, media_type, None, data)?;
+        }
+    }
+    if !localized.is_empty() {
+        log::info!("Localized {} remote images", localized.len());
+    }
+    Ok(failures)
+}
+
+/// Downloads the image at `url`, returning its data, media type and extension.
+fn download(
+    agent: &ureq::Agent,
+    localization: &Localization,
+    url: &str,
+) -> Result<(Vec<u8>, (&'static str, &'static str))> {
+    let parsed = Url::parse(url)?;
+    let host = parsed.host_str().unwrap_or_default();
+    if !localization.allowlist.is_empty()
+        && !localization
+            .allowlist
+            .iter()
+            .any(|allowed| host == allowed || host.ends_with(&format!(".{allowed}")))
+    {
+        bail!("{} is not in the allowlist", host);
+    }
+
+    let cache_file = localization
+        .cache_dir
+        .as_ref()
+        .map(|dir| dir.join(format!("{:x}", Sha256::digest(url.as_bytes()))));
+    let data = match cache_file.as_ref().filter(|file| file.is_file()) {
+        Some(file) => std::fs::read(file)?,
+        None => {
+            let data = fetch(agent, localization, &parsed)?;
+            if let Some(file) = &cache_file {
+                std::fs::create_dir_all(file.parent().unwrap_or(file))?;
+                std::fs::write(file, &data)?;
+            }
+            data
+        }
+    };
+    if data.len() as u64 > localization.max_size {
+        bail!("The image is larger than {} bytes", localization.max_size);
+    }
+    let Some(media_type) = media_type(&data) else {
+        bail!("Not a PNG, JPEG, GIF, WebP or SVG image");
+    };
+    Ok((data, media_type))
+}
+
+/// Requests `url`, through the stand-in server if set.
+fn fetch(agent: &ureq::Agent, localization: &Localization, url: &Url) -> Result<Vec<u8>> {
+    let request_url = match &localization.proxy {
+        // The stand-in serves the images under their host and path
+        Some(proxy) => format!(
+            "{}/{}{}",
+            proxy.trim_end_matches('/'),
+            url.host_str().unwrap_or_default(),
+            &url[url::Position::BeforePath..]
+        ),
+        None => url.to_string(),
+    };
+    let response = agent.get(&request_url).call()?;
+    let length = response
+        .header("Content-Length")
+        .and_then(|length| length.parse::<u64>().ok());
+    if length.is_some_and(|length| length > localization.max_size) {
+        bail!("The image is larger than {} bytes", localization.max_size);
+    }
+    let mut data = Vec::new();
+    response
+        .into_reader()
+        .take(localization.max_size + 1)
+        .read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// The media type and extension of the image `data`.
+fn media_type(data: &[u8]) -> Option<(&'static str, &'static str)> {
+    match image::guess_format(data) {
+        Ok(ImageFormat::Png) => Some(("image/png", "png")),
+        Ok(ImageFormat::Jpeg) => Some(("image/jpeg", "jpg")),
+        Ok(ImageFormat::Gif) => Some(("image/gif", "gif")),
+        Ok(ImageFormat::WebP) => Some(("image/webp", "webp")),
+        _ => {
+            let start = String::from_utf8_lossy(&data[..data.len().min(1024)]).into_owned();
+            start.contains("<svg").then_some(("image/svg+xml", "svg"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SVG: &str = r#"<svg xmlns="http://www.w3.org/2000/svg" width="90" height="20"/>"#;
+
+    fn remote_epub() -> Epub {
+        let mut epub = Epub::default();
+        epub.set(
+            "META-INF/container.xml",
+            br#"<container><rootfiles><rootfile full-path="OEBPS/content.opf"/></rootfiles></container>"#.to_vec(),
+        );
+        epub.set(
+            "OEBPS/content.opf",
+            concat!(
+                r#"<package version="3.0"><manifest>"#,
+                r#"<item id="ch1" href="text/ch1.xhtml" media-type="application/xhtml+xml"/>"#,
+                r#"</manifest></package>"#
+            )
+            .as_bytes()
+            .to_vec(),
+        );
+        epub.set(
+            "OEBPS/text/ch1.xhtml",
+            concat!(
+                r#"<p><img src="https://img.shields.io/badge.svg?a=1&amp;b=2"/>"#,
+                r#"<img src="https://example.org/diagram.png"/><img src="local.png"/>"#,
+                r#"<img src="https://img.shields.io/badge.svg?a=1&amp;b=2"/></p>"#,
+                r#"<iframe src="https://img.shields.io/embed.html"></iframe>"#,
+                r#"<svg><image xlink:href="https://img.shields.io/badge.svg?a=1&amp;b=2"/></svg>"#
+            )
+            .as_bytes()
+            .to_vec(),
+        );
+        epub
+    }
+
+    #[test]
+    fn test_localize() {
+        let cache_dir = tempfile::TempDir::new().unwrap();
+        let url = "https://img.shields.io/badge.svg?a=1&b=2";
+        let cache_file = cache_dir
+            .path()
+            .join(format!("{:x}", Sha256::digest(url.as_bytes())));
+        std::fs::write(cache_file, SVG).unwrap();
+        let localization = Localization {
+            allowlist: vec![String::from("shields.io")],
+            max_size: 1024,
+            max_total_size: 4096,
+            proxy: None,
+            cache_dir: Some(cache_dir.path().to_path_buf()),
+        };
+        let mut epub = remote_epub();
+
+        let failures = localize(&mut epub, &localization).unwrap();
+
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].url, "https://example.org/diagram.png");
+        assert_eq!(failures[0].reason, "example.org is not in the allowlist");
+        let digest = format!("{:x}", Sha256::digest(url.as_bytes()));
+        let href = format!("remote-images/{}.svg", &digest[..16]);
+        let xhtml = epub.get_str("OEBPS/text/ch1.xhtml").unwrap();
+        assert_eq!(xhtml.matches(&format!("src=\"../{href}\"")).count(), 2);
+        assert!(xhtml.contains("src=\"local.png\""));
+        assert!(xhtml.contains(r#"<iframe src="https://img.shields.io/embed.html"></iframe>"#));
+        assert!(xhtml.contains(&format!("<image xlink:href=\"../{href}\"/>")));
+        assert_eq!(epub.get(&format!("OEBPS/{href}")).unwrap(), SVG.as_bytes());
+        let package = epub.get_str("OEBPS/content.opf").unwrap();
+        assert!(package.contains(&format!("href=\"{href}\" media-type=\"image/svg+xml\"")));
+
+        let localization = Localization {
+            max_size: 10,
+            ..localization
+        };
+        let failures = localize(&mut remote_epub(), &localization).unwrap();
+        assert_eq!(failures[0].reason, "The image is larger than 10 bytes");
+    }
+}
@@ -0,0 +1,254 @@
+//! Single-file exports of a book: one self-contained HTML file and one Markdown file.
+
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use mdbook::book::{Book, BookItem, Chapter};
+use mdbook::renderer::{MarkdownRenderer, RenderContext, Renderer};
+use mdbook::utils::{normalize_id, render_markdown};
+use mdbook::MDBook;
+
+use crate::epub::escape_xml;
+
+/// Minimal styles of the single-file HTML export, before the shelf and book CSS.
+const DEFAULT_CSS: &str =
+    "body { max-width: 45em; margin: 0 auto; padding: 1em; line-height: 1.5; }\n\
+                           pre { overflow-x: auto; }\n\
+                           img { max-width: 100%; }\n";
+
+/// Concatenates the preprocessed chapters of `md` in `SUMMARY.md` order.
+pub(crate) fn markdown(md: &MDBook) -> Result<String> {
+    let (book, _) = md.preprocess_book(&MarkdownRenderer::new())?;
+    let mut parts = Vec::new();
+    for item in book.iter() {
+        match item {
+            BookItem::Chapter(chapter) if !chapter.content.trim().is_empty() => {
+                parts.push(chapter.content.trim().to_owned())
+            }
+            BookItem::PartTitle(title) => parts.push(format!("# {title}")),
+            _ => {}
+        }
+    }
+    Ok(parts.join("\n\n") + "\n")
+}
+
+/// The renderer name preprocessors see while rendering the single-file HTML, distinct
+/// from the `html` one of the mdbook site.
+struct SingleFileRenderer;
+
+impl Renderer for SingleFileRenderer {
+    fn name(&self) -> &str {
+        "html-single"
+    }
+
+    fn render(&self, _ctx: &RenderContext) -> mdbook::errors::Result<()> {
+        Ok(())
+    }
+}
+
+/// Renders the preprocessed chapters of `md` in a single HTML document, with `css`
+/// and the images of the book inlined.
+pub(crate) fn html(md: &MDBook, css: Option<&str>) -> Result<String> {
+    let (book, _) = md.preprocess_book(&SingleFileRenderer)?;
+    let smart_punctuation = md
+        .config
+        .html_config()
+        .unwrap_or_default()
+        .smart_punctuation();
+    let source_dir = md.source_dir();
+
+    let mut body = String::new();
+    for chapter in chapters(&book) {
+        let Some(path) = &chapter.path else {
+            continue;
+        };
+        let html = render_markdown(&chapter.content, smart_punctuation);
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let html = inline_images(&html, &source_dir.join(dir));
+        let html = rewrite_links(&html, dir);
+        body.push_str(&format!(
+            "<section id=\"{}\">\n{}</section>\n",
+            chapter_id(path),
+            html
+        ));
+    }
+
+    let book_config = &md.config.book;
+    Ok(format!(
+        "<!DOCTYPE html>\n<html lang=\"{}\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>{}</title>\n<style>\n{}{}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+        escape_xml(book_config.language.as_deref().unwrap_or("en")),
+        escape_xml(book_config.title.as_deref().unwrap_or_default()),
+        DEFAULT_CSS,
+        css.unwrap_or_default(),
+        body
+    ))
+}
+
+/// The chapters of `book`, in `SUMMARY.md` order.
+fn chapters(book: &Book) -> impl Iterator<Item = &Chapter> {
+    book.iter().filter_map(|item| match item {
+        BookItem::Chapter(chapter) => Some(chapter),
+        _ => None,
+    })
+}
+
+/// The id of the section of the chapter at `path` (relative to the source directory).
+fn chapter_id(path: &Path) -> String {
+    normalize_id(
+        &path
+            .with_extension("")
+            .to_string_lossy()
+            .replace(['/', '\\'], "-"),
+    )
+}
+
+/// Returns whether `url` points outside of the book.
+fn is_external(url: &str) -> bool {
+    url.contains("://") || url.starts_with("data:") || url.starts_with("mailto:")
+}
+
+/// Replaces the relative `src` of the images of `html` by data URIs,
+/// reading them from `dir`. Missing images are left untouched.
+fn inline_images(html: &str, dir: &Path) -> String {
+    replace_attribute(html, "src", |src| {
+        if is_external(src) {
+            return None;
+        }
+        let media_type = media_type(src)?;
+        let data = std::fs::read(dir.join(src)).ok()?;
+        Some(format!(
+            "data:{media_type};base64,{}",
+            STANDARD.encode(data)
+        ))
+    })
+}
+
+/// Points the links of `html` to other chapters (relative to `dir`) to their section.
+fn rewrite_links(html: &str, dir: &Path) -> String {
+    replace_attribute(html, "href", |href| {
+        if is_external(href) {
+            return None;
+        }
+        let path = href.split('#').next().unwrap_or_default();
+        if !path.ends_with(".md") {
+            return None;
+        }
+        Some(format!("#{}", chapter_id(&normalize_path(&dir.join(path)))))
+    })
+}
+
+/// Resolves the `.` and `..` components of the relative `path`.
+pub(crate) fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Replaces the values of the `name` attributes of `html` for which `replace` returns a value.
+pub(crate) fn replace_attribute(
+    html: &str,
+    name: &str,
+    mut replace: impl FnMut(&str) -> Option<String>,
+) -> String {
+    let prefix = format!(" {name}=\"");
+    let mut output = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(&prefix) {
+        let value_start = start + prefix.len();
+        let Some(value_len) = rest[value_start..].find('"') else {
+            break;
+        };
+        let value = &rest[value_start..value_start + value_len];
+        output.push_str(&rest[..value_start]);
+        output.push_str(&replace(value).unwrap_or_else(|| value.to_owned()));
+        rest = &rest[value_start + value_len..];
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Replaces the values of the `name` attributes of the `element` start tags of `html`
+/// for which `replace` returns a new value.
+pub(crate) fn replace_element_attribute(
+    html: &str,
+    element: &str,
+    name: &str,
+    mut replace: impl FnMut(&str) -> Option<String>,
+) -> String {
+    let start_tag = format!("<{element}");
+    let mut output = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(&start_tag) {
+        let after = &rest[start + start_tag.len()..];
+        let end = match after.find('>') {
+            Some(end) if after.starts_with(|c: char| c.is_whitespace() || c == '/') => {
+                start + start_tag.len() + end
+            }
+            // Another element, or no end of tag
+            _ => {
+                output.push_str(&rest[..start + start_tag.len()]);
+                rest = after;
+                continue;
+            }
+        };
+        output.push_str(&rest[..start]);
+        output.push_str(&replace_attribute(&rest[start..end], name, &mut replace));
+        rest = &rest[end..];
+    }
+    output.push_str(rest);
+    output
+}
+
+/// The media type of the image at `path`, if supported.
+pub(crate) fn media_type(path: &str) -> Option<&'static str> {
+    let extension = Path::new(path).extension()?.to_str()?.to_lowercase();
+    match extension.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "svg" => Some("image/svg+xml"),
+        "webp" => Some("image/webp"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_links() {
+        assert_eq!(
+            rewrite_links(
+                r##"<a href="../ch02/intro.md#setup">a</a> <a href="https://x.org/a.md">b</a> <a href="#top">c</a>"##,
+                Path::new("ch01")
+            ),
+            r##"<a href="#ch02-intro">a</a> <a href="https://x.org/a.md">b</a> <a href="#top">c</a>"##
+        );
+    }
+
+    #[test]
+    fn test_inline_images() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(dir.path().join("a.svg"), "<svg/>").unwrap();
+
+        assert_eq!(
+            inline_images(
+                r#"<img src="a.svg" alt="a"><img src="missing.png"><img src="http://x.org/b.png">"#,
+                dir.path()
+            ),
+            r#"<img src="data:image/svg+xml;base64,PHN2Zy8+" alt="a"><img src="missing.png"><img src="http://x.org/b.png">"#
+        );
+    }
+}
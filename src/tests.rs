@@ -8,15 +8,25 @@ use chrono::{TimeZone, Utc};
 use git2::Repository;
 use mockall::predicate;
 
-use super::{book, config::Config, git, ManifestEntry};
+use super::{
+    book, checksum,
+    config::{Config, FallbackMarkup},
+    git, Artifact, ImageFailure, ImageOptimization, ManifestEntry, Thumbnail,
+};
+use crate::cross_links::LinkTarget;
+use crate::export::Format;
+use crate::fallbacks::Fallbacks;
 
 #[test]
 fn test_run() {
+    // Outside of the source tree, and without a build cache from a previous run
+    let destination_dir = tempfile::TempDir::new().unwrap();
+    let working_dir = tempfile::TempDir::new().unwrap();
     let config = Config::from_str(&format!(
         r#"
     title = "My eBookshelf"
-    destination-dir = "tests/out"
-    working-dir = "tests/repos"
+    destination-dir = '{}'
+    working-dir = '{}'
     templates-dir = "tests/templates"
 
     [[book]]
@@ -24,9 +34,14 @@ fn test_run() {
     repo-url = "{REPO_URL}"
     url = "https://rams3s.github.io/mdbook-dummy/index.html"
     folder = "book"
+    authors = ["Ferris"]
+    publisher = "Rustaceans"
+    subject = ["Rust"]
     [book.env-var]
     MDBOOK_PREPROCESSOR__X = ""
-    "#
+    "#,
+        destination_dir.path().display(),
+        working_dir.path().display()
     ))
     .unwrap();
     const REPO_URL: &str = "https://github.com/rams3s/mdbook-dummy.git";
@@ -46,11 +61,17 @@ fn test_run() {
     let dest_ = dest.path().to_path_buf();
     let sec_ref = Arc::clone(&sec_cell);
     let sha_ref = Arc::clone(&sha_cell);
-    let book_result = (
-        Some(expect_title.to_owned()),
-        expect_filename.to_owned(),
-        expect_size.to_owned(),
-    );
+    let book_result = book::BookOutput {
+        title: Some(expect_title.to_owned()),
+        authors: vec![String::from("Ferris")],
+        path: expect_filename.to_owned(),
+        epub_size: expect_size.to_owned(),
+        digests: checksum::Digests {
+            sha256: String::from("0123abcd"),
+            blake3: None,
+        },
+        ..Default::default()
+    };
 
     // mocks
     let ctx_clone = git::MockRepo::clone_context();
@@ -77,33 +98,309 @@ fn test_run() {
     ctx_book
         .expect()
         .once()
-        .return_once(move |_path, vars, _dest| {
-            assert_eq!(vars.len(), 2);
+        .return_once(move |_path, options, _dest| {
+            let vars = &options.env_var;
+            assert_eq!(vars.len(), 3);
             assert_eq!(vars[0].0, "MDBOOK_PREPROCESSOR__X");
             assert_eq!(vars[0].1, Some(String::from("\"\"")));
             assert_eq!(vars[1].0, "MDBOOK_BOOK__TITLE");
-            assert_eq!(&vars[1].1, &book_result.0);
+            assert_eq!(&vars[1].1, &book_result.title);
+            assert_eq!(vars[2].0, "MDBOOK_BOOK__AUTHORS");
+            assert_eq!(vars[2].1, Some(String::from("[\"Ferris\"]")));
+            assert_eq!(
+                options.identifier,
+                Some(String::from(
+                    "urn:uuid:43230e99-c5ca-5626-9f1c-9faf7573a05c"
+                ))
+            );
+            assert_eq!(
+                options.output_filename,
+                Some(PathBuf::from("Hello Rust.epub"))
+            );
+            assert_eq!(
+                options.metadata,
+                vec![
+                    (String::from("publisher"), String::from("Rustaceans")),
+                    (String::from("subject"), String::from("Rust")),
+                ]
+            );
             Ok(book_result)
         });
 
     let got = super::run(&config).unwrap();
 
+    let commit_sha = sha_cell.lock().unwrap().to_string();
     let entry = ManifestEntry {
+        artifacts: vec![Artifact {
+            format: String::from("epub"),
+            path: expect_filename.to_owned(),
+            size: expect_size,
+            sha256: String::from("0123abcd"),
+            ..Default::default()
+        }],
+        authors: vec![String::from("Ferris")],
+        publisher: Some(String::from("Rustaceans")),
+        subject: vec![String::from("Rust")],
+        sha256: String::from("0123abcd"),
         title: expect_title,
         path: expect_filename,
         epub_size: expect_size,
         url: config.book_repo_configs[0].url.to_owned(),
+        uuid: String::from("43230e99-c5ca-5626-9f1c-9faf7573a05c"),
         repo_url: config.book_repo_configs[0].repo_url.to_owned(),
-        commit_sha: sha_cell.lock().unwrap().to_string(),
+        version: commit_sha[..7].to_owned(),
+        commit_sha,
         last_modified: Utc
             .timestamp_opt(*sec_cell.lock().unwrap(), 0)
             .unwrap()
             .to_rfc3339(),
+        ..Default::default()
     };
     assert_eq!(got.entries[0], entry);
     assert_eq!(got.title, config.title);
 }
 
+#[test]
+fn test_check_output_paths() {
+    let checkout = |id: &str, filenames: &[&str], formats: &[Format]| super::Checkout {
+        uuid: uuid::Uuid::nil(),
+        book_path: PathBuf::new(),
+        commit_sha: String::new(),
+        last_modified: String::new(),
+        version: String::new(),
+        variants: filenames
+            .iter()
+            .map(|filename| super::Variant {
+                id: format!("{id} [{filename}]"),
+                profile: None,
+                options: book::BookOptions {
+                    output_filename: Some(PathBuf::from(filename)),
+                    extra_formats: formats.to_vec(),
+                    thumbnail_widths: vec![200, 200],
+                    link_report: true,
+                    ..Default::default()
+                },
+            })
+            .collect(),
+        site_path: PathBuf::from(format!("{id}.html.zip")),
+        site_options: Default::default(),
+    };
+
+    let checkouts = vec![
+        checkout("a", &["A-kindle.epub", "A-kobo.epub"], &[Format::Kepub]),
+        checkout("b", &["B.epub"], &[]),
+    ];
+    assert!(super::check_output_paths(&checkouts).is_ok());
+
+    let checkouts = vec![
+        checkout("a", &["Book.epub"], &[]),
+        checkout("b", &["book.epub"], &[]),
+    ];
+    let err = super::check_output_paths(&checkouts).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Books a [Book.epub] and b [book.epub] would both be written to book.epub"
+    );
+
+    let checkouts = vec![checkout("a", &["A.epub", "A.epub"], &[])];
+    assert!(super::check_output_paths(&checkouts).is_err());
+
+    // The exported EPUB of a book is the EPUB of another one
+    let checkouts = vec![
+        checkout("a", &["A.epub"], &[Format::Kepub]),
+        checkout("b", &["A.kepub.epub"], &[]),
+    ];
+    let err = super::check_output_paths(&checkouts).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Books a [A.epub] and b [A.kepub.epub] would both be written to A.kepub.epub"
+    );
+
+    // The thumbnails of a book don't collide with the EPUB of another one
+    let checkouts = vec![
+        checkout("a", &["A-200.epub"], &[]),
+        checkout("b", &["A.epub"], &[]),
+    ];
+    assert!(super::check_output_paths(&checkouts).is_ok());
+
+    let checkouts = vec![
+        checkout("a", &["Guide.epub"], &[Format::Html]),
+        checkout("b", &["Guide.html"], &[]),
+    ];
+    assert!(super::check_output_paths(&checkouts).is_err());
+
+    // The HTML site is written once per book, whatever the number of profiles
+    let checkouts = vec![checkout(
+        "a",
+        &["A-kindle.epub", "A-kobo.epub"],
+        &[Format::HtmlZip],
+    )];
+    assert!(super::check_output_paths(&checkouts).is_ok());
+    let checkouts = vec![
+        checkout("a", &["A-kindle.epub", "A-kobo.epub"], &[Format::HtmlZip]),
+        checkout("b", &["a.html.zip"], &[]),
+    ];
+    let err = super::check_output_paths(&checkouts).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Books a [A-kindle.epub] and b [a.html.zip] would both be written to a.html.zip"
+    );
+}
+
+#[test]
+fn test_merge_variants() {
+    let variant = |profile: &str| ManifestEntry {
+        artifacts: vec![Artifact {
+            format: String::from("epub"),
+            profile: Some(profile.to_owned()),
+            path: PathBuf::from(format!("A-{profile}.epub")),
+            image_optimization: Some(ImageOptimization {
+                size_before: 100,
+                size_after: 80,
+            }),
+            ..Default::default()
+        }],
+        image_failures: vec![ImageFailure {
+            url: String::from("https://example.org/a.png"),
+            reason: String::from("Not found"),
+        }],
+        path: PathBuf::from(format!("A-{profile}.epub")),
+        profile: Some(profile.to_owned()),
+        thumbnails: vec![Thumbnail {
+            path: PathBuf::from(format!("A-{profile}-200.png")),
+            width: 200,
+            height: 300,
+        }],
+        ..Default::default()
+    };
+
+    let entry = super::merge_variants(vec![variant("kindle"), variant("kobo")]).unwrap();
+
+    assert_eq!(entry.path, Path::new("A-kindle.epub"));
+    assert_eq!(entry.artifacts.len(), 2);
+    assert!(entry.artifacts[1].image_optimization.is_some());
+    let thumbnails: Vec<&Path> = entry.thumbnails.iter().map(|t| t.path.as_path()).collect();
+    assert_eq!(
+        thumbnails,
+        ["A-kindle-200.png", "A-kobo-200.png"].map(Path::new)
+    );
+    assert_eq!(entry.image_failures.len(), 1);
+}
+
+#[test]
+fn test_add_cross_links() {
+    let checkout = |profiles: &[&str]| super::Checkout {
+        uuid: uuid::Uuid::nil(),
+        book_path: PathBuf::new(),
+        commit_sha: String::new(),
+        last_modified: String::new(),
+        version: String::new(),
+        variants: profiles
+            .iter()
+            .map(|profile| super::Variant {
+                id: String::new(),
+                profile: Some(profile.to_string()),
+                options: book::BookOptions {
+                    output_filename: Some(PathBuf::from(format!("{}.epub", profile))),
+                    extra_formats: if profile.ends_with("kobo") {
+                        vec![Format::Kepub]
+                    } else {
+                        Vec::new()
+                    },
+                    ..Default::default()
+                },
+            })
+            .collect(),
+        site_path: PathBuf::new(),
+        site_options: Default::default(),
+    };
+    let repo_config = |url: &str| super::BookRepoConfig {
+        url: url.to_owned(),
+        ..Default::default()
+    };
+    let repo_configs = vec![
+        repo_config("https://a.org/"),
+        repo_config("https://b.org/"),
+        repo_config(""),
+    ];
+    let mut checkouts = vec![
+        checkout(&["a-kindle", "a-kobo"]),
+        checkout(&["b-kobo"]),
+        checkout(&["c"]),
+    ];
+
+    super::add_cross_links(&repo_configs, &mut checkouts, LinkTarget::Epub);
+
+    let books = |checkout: usize, variant: usize| {
+        let cross_links = checkouts[checkout].variants[variant]
+            .options
+            .cross_links
+            .as_ref();
+        cross_links
+            .unwrap()
+            .books
+            .iter()
+            .map(|book| (book.url.as_str(), book.epub.to_str().unwrap(), book.kepub))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        books(1, 0),
+        vec![("https://a.org/", "a-kindle.epub", false)]
+    );
+    assert_eq!(books(0, 1), vec![("https://b.org/", "b-kobo.epub", true)]);
+    assert_eq!(books(2, 0).len(), 2);
+}
+
+#[test]
+fn test_highlight() {
+    let mut config = Config::default();
+    let kindle = crate::profile::find("kindle");
+    let generic = crate::profile::find("generic-epub3");
+    assert_eq!(super::highlight(&config, None).theme, "InspiredGitHub");
+    assert_eq!(
+        super::highlight(&config, generic).theme,
+        "Solarized (light)"
+    );
+
+    config.highlight_theme = Some(String::from("base16-ocean.light"));
+    config
+        .highlight_themes
+        .insert(String::from("kindle"), String::from("Solarized (light)"));
+    assert_eq!(super::highlight(&config, kindle).theme, "Solarized (light)");
+    assert_eq!(
+        super::highlight(&config, generic).theme,
+        "base16-ocean.light"
+    );
+}
+
+#[test]
+fn test_fallbacks() {
+    let mut config = Config::default();
+    let kindle = crate::profile::find("kindle");
+    let kobo = crate::profile::find("kobo");
+    config.fallback_markup.insert(
+        String::from("default"),
+        FallbackMarkup {
+            video: Some(String::from("<p>{url}</p>")),
+            playground: Some(String::new()),
+            ..Default::default()
+        },
+    );
+    config.fallback_markup.insert(
+        String::from("kindle"),
+        FallbackMarkup {
+            video: Some(String::from("<p>{title}</p>")),
+            ..Default::default()
+        },
+    );
+
+    let fallbacks = super::fallbacks(&config, kindle);
+    assert_eq!(fallbacks.video, "<p>{title}</p>");
+    assert_eq!(fallbacks.playground, "");
+    assert_eq!(fallbacks.embed, Fallbacks::default().embed);
+    assert_eq!(super::fallbacks(&config, kobo).video, "<p>{url}</p>");
+}
+
 /// Dummy repo init. Copied from git2::test.
 pub(crate) fn repo_init(dest: &Path) -> Result<Repository, git2::Error> {
     repo_init_opts(dest, git2::RepositoryInitOptions::new())
@@ -0,0 +1,89 @@
+//! Cover thumbnails written next to the generated EPUBs.
+
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use image::imageops::FilterType;
+use image::ImageFormat;
+
+use crate::Thumbnail;
+
+/// Writes a PNG thumbnail of `cover` for each of `widths` into `dest`, named after
+/// `epub_path` (e.g. `Hello Rust-200.png`). Covers are never upscaled.
+pub(crate) fn write_thumbnails(
+    cover: &[u8],
+    widths: &[u32],
+    dest: &Path,
+    epub_path: &Path,
+) -> Result<Vec<Thumbnail>> {
+    let image = image::load_from_memory(cover)?;
+    let mut thumbnails = Vec::with_capacity(widths.len());
+    for &width in widths {
+        let resized = if width < image.width() {
+            image.resize(width, u32::MAX, FilterType::Lanczos3)
+        } else {
+            image.clone()
+        };
+        let mut data = Cursor::new(Vec::new());
+        resized.write_to(&mut data, ImageFormat::Png)?;
+
+        let path = thumbnail_path(epub_path, width);
+        std::fs::write(dest.join(&path), data.into_inner())?;
+        thumbnails.push(Thumbnail {
+            path,
+            width: resized.width(),
+            height: resized.height(),
+        });
+    }
+    Ok(thumbnails)
+}
+
+/// The path of the thumbnail of the EPUB at `epub_path`, `width` pixels wide.
+pub(crate) fn thumbnail_path(epub_path: &Path, width: u32) -> PathBuf {
+    PathBuf::from(format!(
+        "{}-{}.png",
+        epub_path.with_extension("").display(),
+        width
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_thumbnails() {
+        let mut cover = Cursor::new(Vec::new());
+        image::RgbImage::new(400, 600)
+            .write_to(&mut cover, ImageFormat::Png)
+            .unwrap();
+        let dest = tempfile::TempDir::new().unwrap();
+
+        let thumbnails = write_thumbnails(
+            cover.get_ref(),
+            &[200, 600],
+            dest.path(),
+            Path::new("Hello Rust.epub"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            thumbnails,
+            vec![
+                Thumbnail {
+                    path: PathBuf::from("Hello Rust-200.png"),
+                    width: 200,
+                    height: 300,
+                },
+                Thumbnail {
+                    path: PathBuf::from("Hello Rust-600.png"),
+                    width: 400,
+                    height: 600,
+                },
+            ]
+        );
+        let thumbnail = image::open(dest.path().join("Hello Rust-200.png")).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (200, 300));
+    }
+}
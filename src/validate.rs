@@ -0,0 +1,295 @@
+//! Validation of the generated EPUBs, catching the errors which get books
+//! rejected by e-reader stores and converters.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::Result;
+use percent_encoding::percent_decode_str;
+use roxmltree::{Document, ParsingOptions};
+use zip::{CompressionMethod, ZipArchive};
+
+use crate::epub::{Epub, CONTAINER_PATH};
+use crate::Finding;
+
+const MIMETYPE: &str = "application/epub+zip";
+
+/// Validates the EPUB at `epub_file`, written to `path` in the destination directory.
+pub(crate) fn validate(epub_file: &Path, path: &Path) -> Result<Vec<Finding>> {
+    let mut findings = Findings {
+        path,
+        findings: Vec::new(),
+    };
+
+    let mut archive = ZipArchive::new(File::open(epub_file)?)?;
+    match archive.by_index(0) {
+        Ok(mut first) if first.name() == "mimetype" => {
+            if first.compression() != CompressionMethod::Stored {
+                findings.push("mimetype", "The mimetype entry must not be compressed");
+            }
+            let mut mimetype = String::new();
+            std::io::Read::read_to_string(&mut first, &mut mimetype)?;
+            if mimetype != MIMETYPE {
+                findings.push("mimetype", format!("The mimetype must be {MIMETYPE}"));
+            }
+        }
+        _ => findings.push("mimetype", "The mimetype entry must come first"),
+    }
+
+    let epub = Epub::open(epub_file)?;
+    if epub.get(CONTAINER_PATH).is_none() {
+        findings.push(CONTAINER_PATH, "Missing container");
+        return Ok(findings.findings);
+    }
+    let package_path = match epub.package_path() {
+        Ok(package_path) => package_path,
+        Err(e) => {
+            findings.push(CONTAINER_PATH, e.to_string());
+            return Ok(findings.findings);
+        }
+    };
+    let Some(package) = findings.parse(&epub, &package_path) else {
+        return Ok(findings.findings);
+    };
+
+    // Manifest items, by id
+    let mut items = HashMap::new();
+    for item in package
+        .descendants()
+        .filter(|node| node.has_tag_name("item"))
+    {
+        let (Some(id), Some(href)) = (item.attribute("id"), item.attribute("href")) else {
+            findings.push(&package_path, "Manifest item without id or href");
+            continue;
+        };
+        let name = resolve(&package_path, href);
+        if epub.get(&name).is_none() {
+            findings.push(
+                &package_path,
+                format!("Manifest item {id} references missing file {href}"),
+            );
+        }
+        items.insert(id, (name, item.attribute("media-type").unwrap_or_default()));
+    }
+    for itemref in package
+        .descendants()
+        .filter(|node| node.has_tag_name("itemref"))
+    {
+        let idref = itemref.attribute("idref").unwrap_or_default();
+        if !items.contains_key(idref) {
+            findings.push(
+                &package_path,
+                format!("Spine item {idref} is not in the manifest"),
+            );
+        }
+    }
+
+    // Content documents, by name
+    let mut documents: Vec<_> = items
+        .values()
+        .filter(|(name, media_type)| {
+            *media_type == "application/xhtml+xml" && epub.get(name).is_some()
+        })
+        .map(|(name, _)| name.as_str())
+        .collect();
+    documents.sort();
+    let documents: HashMap<&str, Document> = documents
+        .into_iter()
+        .filter_map(|name| Some((name, findings.parse(&epub, name)?)))
+        .collect();
+    let ids: HashMap<&str, HashSet<&str>> = documents
+        .iter()
+        .map(|(name, document)| {
+            let ids = document
+                .descendants()
+                .filter_map(|node| node.attribute("id"))
+                .collect();
+            (*name, ids)
+        })
+        .collect();
+
+    let mut names: Vec<_> = documents.keys().copied().collect();
+    names.sort();
+    for name in names {
+        for node in documents[name]
+            .descendants()
+            .filter(|node| node.is_element())
+        {
+            let tag = node.tag_name().name();
+            if tag == "a" {
+                let Some(href) = node.attribute("href") else {
+                    continue;
+                };
+                if is_external(href) {
+                    continue;
+                }
+                let (file, fragment) = href.split_once('#').unwrap_or((href, ""));
+                let target = if file.is_empty() {
+                    name.to_owned()
+                } else {
+                    resolve(name, file)
+                };
+                let resolved = if epub.get(&target).is_none() {
+                    false
+                } else {
+                    fragment.is_empty()
+                        || ids
+                            .get(target.as_str())
+                            .is_none_or(|ids| ids.contains(fragment))
+                };
+                if !resolved {
+                    findings.push(name, format!("Unresolved link {href}"));
+                }
+            } else if tag == "img" || tag == "image" {
+                let src = node.attribute("src").or_else(|| {
+                    node.attributes()
+                        .find(|attribute| attribute.name() == "href")
+                        .map(|attribute| attribute.value())
+                });
+                let Some(src) = src else {
+                    continue;
+                };
+                if !is_external(src) && epub.get(&resolve(name, src)).is_none() {
+                    findings.push(name, format!("Missing image {src}"));
+                }
+            }
+        }
+    }
+    Ok(findings.findings)
+}
+
+/// The findings about an EPUB.
+struct Findings<'a> {
+    path: &'a Path,
+    findings: Vec<Finding>,
+}
+
+impl Findings<'_> {
+    fn push(&mut self, entry: &str, message: impl Into<String>) {
+        self.findings.push(Finding {
+            path: self.path.to_path_buf(),
+            entry: entry.to_owned(),
+            message: message.into(),
+        });
+    }
+
+    /// Parses the XML entry `name`, recording a finding if it is missing or not well-formed.
+    fn parse<'a>(&mut self, epub: &'a Epub, name: &str) -> Option<Document<'a>> {
+        let xml = match epub.get_str(name) {
+            Ok(xml) => xml,
+            Err(e) => {
+                self.push(name, e.to_string());
+                return None;
+            }
+        };
+        let options = ParsingOptions {
+            allow_dtd: true,
+            ..Default::default()
+        };
+        match Document::parse_with_options(xml, options) {
+            Ok(document) => Some(document),
+            Err(e) => {
+                self.push(name, format!("Not well-formed: {e}"));
+                None
+            }
+        }
+    }
+}
+
+/// Returns whether `href` points outside of the EPUB: an URL, or another EPUB of the
+/// shelf the cross-book links were rewritten to.
+fn is_external(href: &str) -> bool {
+    let file = href.split(['#', '?']).next().unwrap_or_default();
+    href.contains(':') || file.to_lowercase().ends_with(".epub")
+}
+
+/// Resolves `href`, relative to the entry `base`, to an entry name.
+pub(crate) fn resolve(base: &str, href: &str) -> String {
+    let href = percent_decode_str(href).decode_utf8_lossy();
+    let mut parts: Vec<&str> = base.split('/').collect();
+    parts.pop();
+    for part in href.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(epub: &Epub) -> Vec<String> {
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = dir.path().join("book.epub");
+        epub.save(&file).unwrap();
+        validate(&file, Path::new("book.epub"))
+            .unwrap()
+            .into_iter()
+            .map(|finding| format!("{}: {}", finding.entry, finding.message))
+            .collect()
+    }
+
+    fn epub(chapter: &str) -> Epub {
+        let mut epub = Epub::default();
+        epub.set("mimetype", MIMETYPE.as_bytes().to_vec());
+        epub.set(
+            CONTAINER_PATH,
+            br#"<?xml version="1.0"?><container><rootfiles><rootfile full-path="OEBPS/content.opf"/></rootfiles></container>"#.to_vec(),
+        );
+        epub.set(
+            "OEBPS/content.opf",
+            concat!(
+                r#"<package><manifest>"#,
+                r#"<item id="ch1" href="chapter%201.xhtml" media-type="application/xhtml+xml"/>"#,
+                r#"<item id="css" href="style.css" media-type="text/css"/>"#,
+                r#"</manifest><spine><itemref idref="ch1"/><itemref idref="nav"/></spine></package>"#
+            )
+            .as_bytes()
+            .to_vec(),
+        );
+        epub.set("OEBPS/chapter 1.xhtml", chapter.as_bytes().to_vec());
+        epub
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = r##"<html><body><h1 id="a">A</h1><a href="#a">a</a><a href="https://x.org">x</a><a href="Other%20Book.epub">o</a></body></html>"##;
+        assert_eq!(
+            messages(&epub(valid)),
+            vec![
+                "OEBPS/content.opf: Manifest item css references missing file style.css",
+                "OEBPS/content.opf: Spine item nav is not in the manifest",
+            ]
+        );
+
+        let broken = r##"<html><body><a href="#b">b</a><a href="../c.xhtml">c</a><img src="img/d.png"/></body></html>"##;
+        let findings = messages(&epub(broken));
+        assert_eq!(
+            &findings[2..],
+            [
+                "OEBPS/chapter 1.xhtml: Unresolved link #b",
+                "OEBPS/chapter 1.xhtml: Unresolved link ../c.xhtml",
+                "OEBPS/chapter 1.xhtml: Missing image img/d.png",
+            ]
+        );
+
+        let findings = messages(&epub("<html><body><p>a</body></html>"));
+        assert!(findings[2].starts_with("OEBPS/chapter 1.xhtml: Not well-formed"));
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(
+            resolve("OEBPS/text/a.xhtml", "../img/b%20c.png"),
+            "OEBPS/img/b c.png"
+        );
+        assert_eq!(resolve("content.opf", "./a.xhtml"), "a.xhtml");
+    }
+}